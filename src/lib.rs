@@ -1,13 +1,15 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::LazyLock;
 
+use fancy_regex::Regex;
 use htmlentity::entity::{decode as decode_html_entity_bytes, ICodedDataTrait};
 use syntect::easy::HighlightLines;
 use syntect::parsing::SyntaxSet;
 use syntect::util::as_24_bit_terminal_escaped;
 use two_face::theme::{EmbeddedLazyThemeSet, EmbeddedThemeName};
-use unicode_width::UnicodeWidthStr;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 // Static theme set using two-face's extended themes
 static THEME_SET: LazyLock<EmbeddedLazyThemeSet> = LazyLock::new(two_face::theme::extra);
@@ -49,6 +51,107 @@ enum CalloutType {
     Caution,
 }
 
+/// Built-in code fence language aliases (alias -> syntect token).
+/// Users can add or override entries with `add_language_alias`.
+const DEFAULT_LANGUAGE_ALIASES: &[(&str, &str)] = &[
+    ("jsx", "javascript"),
+    ("tsx", "typescript"),
+    ("node", "javascript"),
+    ("py3", "python"),
+    ("python3", "python"),
+    ("shell", "bash"),
+    ("shell-session", "console"),
+    ("sh-session", "console"),
+    ("terminal", "console"),
+    ("k8s-yaml", "yaml"),
+    ("kubernetes", "yaml"),
+    ("jsonc", "json"),
+    ("json5", "json"),
+    ("docker", "dockerfile"),
+    ("golang", "go"),
+    ("c++", "cpp"),
+];
+
+/// Tag names that start an HTML block ending at a blank line (GFM condition 6)
+const HTML_BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "base",
+    "basefont",
+    "blockquote",
+    "body",
+    "caption",
+    "center",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dialog",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "frame",
+    "frameset",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hr",
+    "html",
+    "iframe",
+    "legend",
+    "li",
+    "link",
+    "main",
+    "menu",
+    "menuitem",
+    "nav",
+    "noframes",
+    "ol",
+    "optgroup",
+    "option",
+    "p",
+    "param",
+    "search",
+    "section",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "track",
+    "ul",
+];
+
+/// Style for code spans, which are padded with a space on each side
+const CODE_SPAN_STYLE: &str = "\u{001b}[38;5;167;48;5;235m";
+
+/// Stands in for the table of contents until every heading has been seen
+const TOC_PLACEHOLDER: &str = "\u{001b}]mdriver-toc\u{001b}\\";
+
+/// Mark where a selected section starts and ends in the output
+const SECTION_START: &str = "\u{001b}]mdriver-section-start\u{001b}\\";
+const SECTION_END: &str = "\u{001b}]mdriver-section-end\u{001b}\\";
+
+/// Style for rendered math, and for commands it has no Unicode for
+const MATH_STYLE: &str = "\u{001b}[36m";
+const MATH_UNKNOWN_STYLE: &str = "\u{001b}[33m";
+
 /// Image protocol for rendering images
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ImageProtocol {
@@ -56,6 +159,231 @@ pub enum ImageProtocol {
     Kitty,
 }
 
+/// Visual treatment for fenced, indented and `<pre>` code blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodeBlockStyle {
+    /// 4-space indent with the theme's foreground colors
+    Plain,
+    /// The theme's background color painted across the full width
+    Background,
+    /// A rounded box-drawing frame with the language in the top border
+    Frame,
+}
+
+/// How commands in `console` code blocks are made copyable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandCopy {
+    None,
+    /// Put the block's commands on the clipboard with OSC 52
+    Osc52,
+    /// Wrap each command in an OSC8 link to a `data:` URI holding its text
+    Link,
+}
+
+/// How tables are laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableLayout {
+    /// A grid, switching to records when the columns can't fit the width
+    Auto,
+    /// Always a grid of columns, shrunk as far as the minimum column width
+    Grid,
+    /// Each row as a block of `Header: value` lines
+    Records,
+}
+
+/// Border style for tables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableBorder {
+    /// Light box-drawing lines: `┌─┬─┐`
+    Light,
+    /// Heavy box-drawing lines: `┏━┳━┓`
+    Heavy,
+    /// Light lines with rounded corners: `╭─┬─╮`
+    Rounded,
+    /// Double lines: `╔═╦═╗`
+    Double,
+    /// ASCII only: `+-+-+`
+    Ascii,
+    /// Pipes and a markdown delimiter row: `| a | b |`
+    Markdown,
+    /// No borders, just a rule under the header
+    Borderless,
+}
+
+/// How YAML (`---`) or TOML (`+++`) front matter at the start of a document is shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrontMatterStyle {
+    /// A dimmed box of keys and values
+    Box,
+    /// Nothing
+    Hide,
+    /// Not recognized: a leading `---` is a thematic break as usual
+    Off,
+}
+
+/// How headings are drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeadingStyle {
+    /// Every level bold and blue
+    Uniform,
+    /// H1 underlined with `═`, H2 with `─`, and a color for each lower level
+    Levels,
+    /// Like `Levels`, but H1 is a full-width banner
+    Banner,
+}
+
+/// Where to print a table of contents
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TocPosition {
+    /// In place of a `[TOC]` marker, or else before the document. The whole
+    /// input is held back until `flush`.
+    Start,
+    /// After the document, so output keeps streaming
+    End,
+}
+
+/// Selects a section of the document by its heading
+enum SectionFilter {
+    /// Case-insensitive substrings of the heading and, in order, of some of
+    /// its ancestors: `Usage/Examples`
+    Path(Vec<String>),
+    /// A regex for the heading path, joined with `/`
+    Regex(Regex),
+}
+
+impl SectionFilter {
+    /// Whether the last heading in `path` (outermost first) starts a section
+    fn matches(&self, path: &[String]) -> bool {
+        match self {
+            SectionFilter::Path(segments) => {
+                let contains = |heading: &String, segment: &String| {
+                    heading.to_lowercase().contains(&segment.to_lowercase())
+                };
+                let (Some((last, outer)), Some((heading, ancestors))) =
+                    (segments.split_last(), path.split_last())
+                else {
+                    return false;
+                };
+                let mut ancestors = ancestors.iter();
+                contains(heading, last)
+                    && outer
+                        .iter()
+                        .all(|segment| ancestors.any(|ancestor| contains(ancestor, segment)))
+            }
+            SectionFilter::Regex(regex) => regex.is_match(&path.join("/")).unwrap_or(false),
+        }
+    }
+}
+
+/// Where the document is relative to the sections being extracted
+#[derive(Default)]
+struct SectionState {
+    /// The current heading and its ancestors: (level, text)
+    path: Vec<(usize, String)>,
+    /// Level of the heading that started the section being shown
+    current: Option<usize>,
+    /// Which filters have already selected a section
+    found: Vec<bool>,
+}
+
+/// A heading rendered so far, with its GitHub-style anchor
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadingAnchor {
+    /// Heading level, 1 to 6
+    pub level: usize,
+    /// The heading text without markup
+    pub text: String,
+    /// The anchor, unique within the document: `installation`, `usage-1`
+    pub slug: String,
+    /// Hierarchical number such as `2.1`, when headings are numbered
+    pub number: Option<String>,
+}
+
+impl HeadingAnchor {
+    /// The heading text, after its number if it has one
+    fn numbered_text(&self) -> String {
+        match &self.number {
+            Some(number) => format!("{} {}", number, self.text),
+            None => self.text.clone(),
+        }
+    }
+}
+
+/// Pieces for drawing one table border style
+struct TableChars {
+    /// Left, inner and right lines between cells of a row
+    vertical: [&'static str; 3],
+    horizontal: &'static str,
+    /// Left, junction and right pieces of each horizontal line; `None` lines aren't drawn
+    top: Option<[&'static str; 3]>,
+    separator: Option<[&'static str; 3]>,
+    bottom: Option<[&'static str; 3]>,
+    /// Spaces each side of cell content unless overridden
+    padding: usize,
+}
+
+impl TableBorder {
+    fn chars(self) -> TableChars {
+        let boxed = |h, v, top, separator, bottom| TableChars {
+            vertical: [v, v, v],
+            horizontal: h,
+            top: Some(top),
+            separator: Some(separator),
+            bottom: Some(bottom),
+            padding: 1,
+        };
+        match self {
+            TableBorder::Light => {
+                boxed("─", "│", ["┌", "┬", "┐"], ["├", "┼", "┤"], ["└", "┴", "┘"])
+            }
+            TableBorder::Heavy => {
+                boxed("━", "┃", ["┏", "┳", "┓"], ["┣", "╋", "┫"], ["┗", "┻", "┛"])
+            }
+            TableBorder::Rounded => {
+                boxed("─", "│", ["╭", "┬", "╮"], ["├", "┼", "┤"], ["╰", "┴", "╯"])
+            }
+            TableBorder::Double => {
+                boxed("═", "║", ["╔", "╦", "╗"], ["╠", "╬", "╣"], ["╚", "╩", "╝"])
+            }
+            TableBorder::Ascii => {
+                boxed("-", "|", ["+", "+", "+"], ["+", "+", "+"], ["+", "+", "+"])
+            }
+            TableBorder::Markdown => TableChars {
+                top: None,
+                bottom: None,
+                ..boxed("-", "|", ["|"; 3], ["|"; 3], ["|"; 3])
+            },
+            TableBorder::Borderless => TableChars {
+                vertical: ["", "  ", ""],
+                horizontal: "─",
+                top: None,
+                separator: Some(["", "  ", ""]),
+                bottom: None,
+                padding: 0,
+            },
+        }
+    }
+}
+
+/// Extra styling for one rendered table row
+#[derive(Clone, Copy, Default)]
+struct TableRowStyle {
+    /// Header row: bold cell text
+    bold: bool,
+    /// Shade the row when zebra striping is on
+    striped: bool,
+    /// Cut overflowing cells short instead of wrapping them
+    truncate: bool,
+}
+
+/// Layout of a table whose rows are emitted as they arrive
+struct TableStream {
+    /// Grid column widths, or `None` when rows are shown as records
+    col_widths: Option<Vec<usize>>,
+    /// Data rows emitted so far
+    rows_emitted: usize,
+}
+
 /// Image data parsed from markdown
 #[derive(Debug)]
 struct ImageData {
@@ -64,6 +392,38 @@ struct ImageData {
     end_pos: usize,
 }
 
+/// Kind of line within a unified diff code block
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DiffLineKind {
+    FileHeader,
+    Hunk,
+    Added,
+    Removed,
+    Context,
+    Meta,
+}
+
+/// Attributes parsed from a fenced code block's info string,
+/// e.g. ```` ```rust title="main.rs" {3,5-7} linenos ````
+#[derive(Debug, Default, Clone, PartialEq)]
+struct CodeBlockInfo {
+    language: String,
+    title: Option<String>,
+    /// Inclusive 1-based line ranges to highlight
+    highlight: Vec<(usize, usize)>,
+    line_numbers: bool,
+    /// CSV/TSV blocks: the first row is data rather than a header
+    no_header: bool,
+}
+
+impl CodeBlockInfo {
+    fn is_highlighted(&self, line_number: usize) -> bool {
+        self.highlight
+            .iter()
+            .any(|&(start, end)| (start..=end).contains(&line_number))
+    }
+}
+
 /// Streaming markdown parser that emits formatted blocks incrementally
 pub struct StreamingParser {
     buffer: String,
@@ -82,6 +442,65 @@ pub struct StreamingParser {
     pending_citations: RefCell<Vec<(usize, String, String)>>,
     /// Next citation number to assign
     next_citation_number: RefCell<usize>,
+    /// Set while formatting link text, where autolinks aren't recognized
+    in_link_text: Cell<bool>,
+    /// Show a line-number gutter on every code block
+    line_numbers: bool,
+    /// Code fence language aliases: lowercase alias -> syntect token
+    language_aliases: HashMap<String, String>,
+    /// How commands in shell session blocks are made copyable
+    command_copy: CommandCopy,
+    /// Background or frame around code blocks
+    code_block_style: CodeBlockStyle,
+    /// Grid or record layout for tables
+    table_layout: TableLayout,
+    /// Narrowest a table column may shrink to before falling back to records
+    table_min_column_width: usize,
+    /// Stream table rows once this many have been seen, instead of buffering the table
+    table_lookahead: Option<usize>,
+    /// Fixed layout of the table currently being streamed
+    table_stream: Option<TableStream>,
+    /// Render `csv` and `tsv` code blocks as tables
+    csv_tables: bool,
+    /// Characters used to draw table borders
+    table_border: TableBorder,
+    /// Spaces each side of table cells, if not the border style's default
+    table_padding: Option<usize>,
+    /// Shade every other table row
+    table_zebra: bool,
+    /// Number of `<details>` elements currently open
+    details_depth: usize,
+    /// Show only the summary line of `<details>` elements
+    collapse_details: bool,
+    /// Show link titles after the link text
+    link_titles: bool,
+    /// Headings rendered so far, for resolving `#fragment` links
+    heading_anchors: RefCell<Vec<HeadingAnchor>>,
+    /// `file://` URL of the document, which `#fragment` links point into
+    document_url: Option<String>,
+    /// Where to print a table of contents, if at all
+    toc: Option<TocPosition>,
+    /// Input held back until `flush` when the contents come first
+    toc_source: String,
+    /// Sections to show; the rest of the document is hidden
+    sections: Vec<SectionFilter>,
+    section_state: RefCell<SectionState>,
+    /// Whether output is inside a selected section
+    in_section: bool,
+    heading_style: HeadingStyle,
+    /// Show `#` markers before heading text
+    heading_markers: bool,
+    /// Number headings hierarchically: 1, 1.1, 1.1.2
+    number_headings: bool,
+    /// How many headings of each level have been seen under the current parents
+    heading_counters: RefCell<[usize; 6]>,
+    /// Whether any input has been processed, as front matter must come first
+    started: bool,
+    front_matter_style: FrontMatterStyle,
+    /// Show the front matter `title` as a top-level heading
+    front_matter_title: bool,
+    /// Render `$...$`, `$$...$$` and `math` code blocks as Unicode
+    math: bool,
 }
 
 /// Calculate the default output width: min(terminal_width, 80)
@@ -107,6 +526,26 @@ enum ParserState {
         nesting_level: usize,
     },
     InIndentedCodeBlock,
+    InPreBlock,
+    InHtmlBlock {
+        end: HtmlBlockEnd,
+    },
+    InLinkDefinition,
+    InFrontMatter {
+        toml: bool,
+    },
+    InMathBlock,
+}
+
+/// The end condition of a raw HTML block
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HtmlBlockEnd {
+    /// A line containing `</pre>`, `</script>`, `</style>` or `</textarea>`
+    RawText,
+    /// A line containing the given marker (`-->`, `?>`, `>` or `]]>`)
+    Marker(&'static str),
+    /// A blank line, which is not part of the block
+    BlankLine,
 }
 
 #[derive(Debug, Clone)]
@@ -135,11 +574,34 @@ enum BlockBuilder {
     IndentedCodeBlock {
         lines: Vec<String>,
     },
+    HtmlBlock {
+        lines: Vec<String>,
+    },
+    /// A link reference definition that may continue on the next line: its
+    /// destination if the label stood alone, or else its title
+    LinkDefinition {
+        label: String,
+        /// The lines so far, shown as a paragraph if no destination follows
+        lines: Vec<String>,
+        url: Option<String>,
+        /// A title still waiting for its closing delimiter, and whether it
+        /// began on a line of its own
+        title: Option<(String, bool)>,
+    },
+    FrontMatter {
+        lines: Vec<String>,
+        toml: bool,
+    },
+    /// `$$` display math, including the lines with the dollars
+    MathBlock {
+        lines: Vec<String>,
+    },
 }
 
 struct LinkData {
     text: String,
     url: String,
+    title: Option<String>,
     end_pos: usize,
 }
 
@@ -159,6 +621,83 @@ struct HtmlTagResult {
     end_pos: usize,
 }
 
+/// A piece of inline output: formatted text, or a delimiter run that may
+/// open or close emphasis
+enum InlineNode {
+    Text(String),
+    Delimiter(DelimiterRun),
+}
+
+/// A run of `*`, `_` or `~` characters on the CommonMark delimiter stack
+struct DelimiterRun {
+    ch: char,
+    /// Characters not yet used for emphasis
+    count: usize,
+    /// Length of the run as written
+    original_count: usize,
+    can_open: bool,
+    can_close: bool,
+    /// Emphasis opened after the remaining characters, outermost first
+    opens: Vec<Emphasis>,
+    /// Emphasis closed before the remaining characters, innermost first
+    closes: Vec<Emphasis>,
+}
+
+/// Inline emphasis styles
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emphasis {
+    Italic,
+    Bold,
+    Strikethrough,
+}
+
+impl Emphasis {
+    fn ansi_code(self) -> &'static str {
+        match self {
+            Emphasis::Italic => "\u{001b}[3m",
+            Emphasis::Bold => "\u{001b}[1m",
+            Emphasis::Strikethrough => "\u{001b}[9m",
+        }
+    }
+}
+
+/// A parsed piece of LaTeX math
+#[derive(Debug, Clone)]
+enum MathNode {
+    /// Text already converted to Unicode
+    Text(String),
+    /// A command with no Unicode approximation, kept as written
+    Unknown(String),
+    Group(Vec<MathNode>),
+    Frac(Vec<MathNode>, Vec<MathNode>),
+    /// A root, with its index if it has one
+    Sqrt(Option<String>, Vec<MathNode>),
+    /// `\sum`, `\int`, `\lim`, ...: scripts become limits in display math
+    BigOp(String),
+    Scripts {
+        base: Box<MathNode>,
+        sub: Option<Vec<MathNode>>,
+        sup: Option<Vec<MathNode>>,
+    },
+    /// `\\`, separating rows of display math
+    Newline,
+    /// A `matrix` or `cases` environment: rows of cells between delimiters
+    Matrix {
+        open: Option<char>,
+        close: Option<char>,
+        rows: Vec<Vec<Vec<MathNode>>>,
+        /// Matrix entries are centered in their columns, cases left aligned
+        centered: bool,
+    },
+}
+
+/// Display math laid out as lines of equal width, aligned on a baseline
+struct MathBox {
+    lines: Vec<String>,
+    width: usize,
+    baseline: usize,
+}
+
 impl StreamingParser {
     pub fn new() -> Self {
         Self::with_theme("base16-ocean.dark", ImageProtocol::None)
@@ -179,6 +718,40 @@ impl StreamingParser {
             link_definitions: HashMap::new(),
             pending_citations: RefCell::new(Vec::new()),
             next_citation_number: RefCell::new(1),
+            in_link_text: Cell::new(false),
+            line_numbers: false,
+            language_aliases: DEFAULT_LANGUAGE_ALIASES
+                .iter()
+                .map(|&(alias, language)| (alias.to_string(), language.to_string()))
+                .collect(),
+            command_copy: CommandCopy::None,
+            code_block_style: CodeBlockStyle::Plain,
+            table_layout: TableLayout::Auto,
+            table_min_column_width: 6,
+            table_lookahead: None,
+            table_stream: None,
+            csv_tables: false,
+            table_border: TableBorder::Light,
+            table_padding: None,
+            table_zebra: false,
+            details_depth: 0,
+            collapse_details: false,
+            link_titles: false,
+            heading_anchors: RefCell::new(Vec::new()),
+            document_url: None,
+            toc: None,
+            toc_source: String::new(),
+            sections: Vec::new(),
+            section_state: RefCell::new(SectionState::default()),
+            in_section: false,
+            heading_style: HeadingStyle::Uniform,
+            heading_markers: true,
+            number_headings: false,
+            heading_counters: RefCell::new([0; 6]),
+            started: false,
+            front_matter_style: FrontMatterStyle::Box,
+            front_matter_title: false,
+            math: true,
         }
     }
 
@@ -197,6 +770,40 @@ impl StreamingParser {
             link_definitions: HashMap::new(),
             pending_citations: RefCell::new(Vec::new()),
             next_citation_number: RefCell::new(1),
+            in_link_text: Cell::new(false),
+            line_numbers: false,
+            language_aliases: DEFAULT_LANGUAGE_ALIASES
+                .iter()
+                .map(|&(alias, language)| (alias.to_string(), language.to_string()))
+                .collect(),
+            command_copy: CommandCopy::None,
+            code_block_style: CodeBlockStyle::Plain,
+            table_layout: TableLayout::Auto,
+            table_min_column_width: 6,
+            table_lookahead: None,
+            table_stream: None,
+            csv_tables: false,
+            table_border: TableBorder::Light,
+            table_padding: None,
+            table_zebra: false,
+            details_depth: 0,
+            collapse_details: false,
+            link_titles: false,
+            heading_anchors: RefCell::new(Vec::new()),
+            document_url: None,
+            toc: None,
+            toc_source: String::new(),
+            sections: Vec::new(),
+            section_state: RefCell::new(SectionState::default()),
+            in_section: false,
+            heading_style: HeadingStyle::Uniform,
+            heading_markers: true,
+            number_headings: false,
+            heading_counters: RefCell::new([0; 6]),
+            started: false,
+            front_matter_style: FrontMatterStyle::Box,
+            front_matter_title: false,
+            math: true,
         }
     }
 
@@ -211,6 +818,165 @@ impl StreamingParser {
         themes
     }
 
+    /// Show line numbers on all code blocks, not just those with `linenos` in the info string
+    pub fn set_line_numbers(&mut self, enabled: bool) {
+        self.line_numbers = enabled;
+    }
+
+    /// Paint a background or draw a frame around code blocks
+    pub fn set_code_block_style(&mut self, style: CodeBlockStyle) {
+        self.code_block_style = style;
+    }
+
+    /// Choose between grid and record layouts for tables
+    pub fn set_table_layout(&mut self, layout: TableLayout) {
+        self.table_layout = layout;
+    }
+
+    /// Set how narrow table columns may get before a table is shown as records
+    pub fn set_table_min_column_width(&mut self, width: usize) {
+        self.table_min_column_width = width.max(1);
+    }
+
+    /// Emit table rows as they arrive instead of waiting for the whole table.
+    /// Column widths are fixed from the header and the first `lookahead` data
+    /// rows, and later cells too wide for their column are cut short with `…`.
+    /// `None` (the default) buffers each table until it ends.
+    pub fn set_table_streaming(&mut self, lookahead: Option<usize>) {
+        self.table_lookahead = lookahead;
+    }
+
+    /// Draw tables with a different border style
+    pub fn set_table_border(&mut self, border: TableBorder) {
+        self.table_border = border;
+    }
+
+    /// Put `padding` spaces each side of table cells instead of the border style's default
+    pub fn set_table_padding(&mut self, padding: usize) {
+        self.table_padding = Some(padding);
+    }
+
+    /// Shade every other table row with the theme's background color
+    pub fn set_table_zebra(&mut self, enabled: bool) {
+        self.table_zebra = enabled;
+    }
+
+    /// Render `csv` and `tsv` code blocks as tables instead of code
+    pub fn set_csv_tables(&mut self, enabled: bool) {
+        self.csv_tables = enabled;
+    }
+
+    /// Hide the body of `<details>` elements, showing only their summary
+    pub fn set_collapse_details(&mut self, collapse: bool) {
+        self.collapse_details = collapse;
+    }
+
+    /// Show link titles, dimmed, after the link text
+    pub fn set_link_titles(&mut self, enabled: bool) {
+        self.link_titles = enabled;
+    }
+
+    /// Point `#fragment` links into the document at `path`, so that a
+    /// terminal can open them. Without a path, links to a heading already
+    /// rendered name the heading instead.
+    pub fn set_document_path(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let encoded: Vec<String> = path
+            .to_string_lossy()
+            .split('/')
+            .map(percent_encode)
+            .collect();
+        self.document_url = Some(format!("file://{}", encoded.join("/")));
+    }
+
+    /// Print a table of contents of all headings. Markers (`[TOC]`,
+    /// `[[_TOC_]]` or `<!-- toc -->`) are replaced by it at the start, and
+    /// hidden when it comes at the end.
+    pub fn set_toc(&mut self, position: Option<TocPosition>) {
+        self.toc = position;
+    }
+
+    /// Show front matter as a dimmed box of keys and values, or hide it
+    pub fn set_front_matter_style(&mut self, style: FrontMatterStyle) {
+        self.front_matter_style = style;
+    }
+
+    /// Show the `title` from front matter as a top-level heading instead of
+    /// in the front matter box
+    pub fn set_front_matter_title(&mut self, enabled: bool) {
+        self.front_matter_title = enabled;
+    }
+
+    /// Render LaTeX math (`$...$`, `$$...$$` and `math` code blocks) as
+    /// Unicode instead of showing the source
+    pub fn set_math(&mut self, enabled: bool) {
+        self.math = enabled;
+    }
+
+    /// Draw headings with a different style for each level
+    pub fn set_heading_style(&mut self, style: HeadingStyle) {
+        self.heading_style = style;
+    }
+
+    /// Show or hide the `#` markers before heading text
+    pub fn set_heading_markers(&mut self, enabled: bool) {
+        self.heading_markers = enabled;
+    }
+
+    /// Number headings hierarchically (1, 1.1, 1.1.2), counting from the
+    /// highest heading level in the document
+    pub fn set_number_headings(&mut self, enabled: bool) {
+        self.number_headings = enabled;
+    }
+
+    /// Show only the section under a heading, up to the next heading at the
+    /// same or a higher level. `path` holds case-insensitive substrings of
+    /// the heading and of its ancestors, separated by `/`: `Usage/Examples`.
+    /// Each added section shows the first heading it matches.
+    pub fn add_section(&mut self, path: &str) {
+        let segments = path
+            .split('/')
+            .map(str::trim)
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect();
+        self.sections.push(SectionFilter::Path(segments));
+    }
+
+    /// Show only the section under the first heading whose path, with
+    /// headings joined by `/`, matches `pattern`
+    pub fn add_section_regex(&mut self, pattern: &str) -> Result<(), String> {
+        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+        self.sections.push(SectionFilter::Regex(regex));
+        Ok(())
+    }
+
+    /// Whether every section asked for has been shown, so the rest of the
+    /// input can't add to the output
+    pub fn sections_complete(&self) -> bool {
+        let state = self.section_state.borrow();
+        !self.sections.is_empty()
+            && state.current.is_none()
+            && state.found.len() == self.sections.len()
+            && state.found.iter().all(|&found| found)
+    }
+
+    /// The headings rendered so far, in document order, with their anchors
+    pub fn heading_anchors(&self) -> Vec<HeadingAnchor> {
+        self.heading_anchors.borrow().clone()
+    }
+
+    /// Make commands in `console`/`shell-session` blocks copyable
+    pub fn set_command_copy(&mut self, mode: CommandCopy) {
+        self.command_copy = mode;
+    }
+
+    /// Treat code fences tagged `alias` as `language`, overriding any built-in alias
+    pub fn add_language_alias(&mut self, alias: &str, language: &str) {
+        self.language_aliases
+            .insert(alias.to_lowercase(), language.to_string());
+    }
+
     /// Extract all image URLs from text content.
     /// Finds both markdown images ![alt](src) and HTML <img src="..."> tags.
     fn extract_image_urls(&self, text: &str) -> Vec<String> {
@@ -315,6 +1081,15 @@ impl StreamingParser {
     /// Feed a chunk of markdown to the parser
     /// Returns any completed blocks as formatted terminal output (with ANSI codes)
     pub fn feed(&mut self, chunk: &str) -> String {
+        // A table of contents at the start needs every heading first
+        if self.toc == Some(TocPosition::Start) {
+            self.toc_source.push_str(chunk);
+            return String::new();
+        }
+        self.feed_lines(chunk)
+    }
+
+    fn feed_lines(&mut self, chunk: &str) -> String {
         self.buffer.push_str(chunk);
 
         let mut output = String::new();
@@ -329,25 +1104,60 @@ impl StreamingParser {
             }
         }
 
-        output
+        self.filter_sections(output)
+    }
+
+    /// Keep only the output inside selected sections, when there are any
+    fn filter_sections(&mut self, output: String) -> String {
+        if self.sections.is_empty() {
+            return output;
+        }
+        let mut kept = String::new();
+        let mut rest = output.as_str();
+        loop {
+            let next = [SECTION_START, SECTION_END]
+                .iter()
+                .filter_map(|marker| rest.find(marker).map(|pos| (pos, *marker)))
+                .min();
+            let Some((pos, marker)) = next else {
+                if self.in_section {
+                    kept.push_str(rest);
+                }
+                return kept;
+            };
+            if self.in_section {
+                kept.push_str(&rest[..pos]);
+            }
+            self.in_section = marker == SECTION_START;
+            rest = &rest[pos + marker.len()..];
+        }
     }
 
     /// Flush any remaining buffered content
     pub fn flush(&mut self) -> String {
-        let mut output = String::new();
+        let source = std::mem::take(&mut self.toc_source);
+        let mut output = self.feed_lines(&source);
 
         // Process any remaining partial line
         if !self.buffer.is_empty() {
             let remaining = self.buffer.clone();
             self.buffer.clear();
             if let Some(emission) = self.process_line(&remaining) {
-                output.push_str(&emission);
+                output.push_str(&self.filter_sections(emission));
             }
         }
 
+        if matches!(self.state, ParserState::InFrontMatter { .. }) {
+            let reparsed = self.reparse_unclosed_front_matter();
+            output.push_str(&self.filter_sections(reparsed));
+        }
+        if self.state == ParserState::InMathBlock {
+            self.math_block_to_paragraph();
+        }
+
         // Emit any incomplete block
         if let Some(emission) = self.emit_current_block() {
-            output.push_str(&emission);
+            output.push_str(&self.filter_sections(emission));
         }
 
         // Emit bibliography if there are pending citations
@@ -355,6 +1165,43 @@ impl StreamingParser {
             output.push_str(&bibliography);
         }
 
+        match self.toc {
+            Some(TocPosition::Start) => {
+                let toc = self.format_toc();
+                if output.contains(TOC_PLACEHOLDER) {
+                    output = output.replace(TOC_PLACEHOLDER, &toc);
+                } else {
+                    output.insert_str(0, &toc);
+                }
+            }
+            Some(TocPosition::End) => output.push_str(&self.format_toc()),
+            None => {}
+        }
+
+        output
+    }
+
+    /// Format the table of contents from the headings rendered so far
+    fn format_toc(&self) -> String {
+        let anchors = self.heading_anchors.borrow();
+        let Some(top) = anchors.iter().map(|anchor| anchor.level).min() else {
+            return String::new();
+        };
+
+        let mut output = String::from("\u{001b}[1;34m─── Contents ───\u{001b}[0m\n\n");
+        for anchor in anchors.iter() {
+            let indent = "  ".repeat(anchor.level - top);
+            // Entries link to their heading when the document is a file
+            let entry = match &self.document_url {
+                Some(document) => self.format_hyperlink(
+                    &format!("{}#{}", document, anchor.slug),
+                    &anchor.numbered_text(),
+                ),
+                None => anchor.numbered_text(),
+            };
+            output.push_str(&format!("{}• {}\n", indent, entry));
+        }
+        output.push('\n');
         output
     }
 
@@ -399,6 +1246,22 @@ impl StreamingParser {
     }
 
     fn process_line(&mut self, line: &str) -> Option<String> {
+        // Front matter can only open on the very first line. Whether it
+        // really is front matter is decided by the line after the fence.
+        if !self.started {
+            self.started = true;
+            let toml = front_matter_fence(line)
+                .filter(|_| self.front_matter_style != FrontMatterStyle::Off);
+            if let Some(toml) = toml {
+                self.state = ParserState::InFrontMatter { toml };
+                self.current_block = BlockBuilder::FrontMatter {
+                    lines: Vec::new(),
+                    toml,
+                };
+                return None;
+            }
+        }
+
         match &self.state {
             ParserState::Ready => self.handle_ready_state(line),
             ParserState::InParagraph => self.handle_in_paragraph(line),
@@ -408,7 +1271,127 @@ impl StreamingParser {
             ParserState::InTable => self.handle_in_table(line),
             ParserState::InBlockquote { .. } => self.handle_in_blockquote(line),
             ParserState::InIndentedCodeBlock => self.handle_in_indented_code_block(line),
+            ParserState::InPreBlock => self.handle_in_pre_block(line),
+            ParserState::InHtmlBlock { .. } => self.handle_in_html_block(line),
+            ParserState::InLinkDefinition => self.handle_in_link_definition(line),
+            ParserState::InFrontMatter { toml } => {
+                let toml = *toml;
+                self.handle_in_front_matter(line, toml)
+            }
+            ParserState::InMathBlock => self.handle_in_math_block(line),
+        }
+    }
+
+    /// Collect front matter until its closing fence: `+++` for TOML, and
+    /// `---` or `...` for YAML. The first line after the opening fence must
+    /// be a field; that or any later line not being part of front matter
+    /// means this was markdown all along.
+    fn handle_in_front_matter(&mut self, line: &str, toml: bool) -> Option<String> {
+        let trimmed = line.trim_end();
+        let BlockBuilder::FrontMatter { lines, .. } = &mut self.current_block else {
+            return None;
+        };
+        let closes = if toml {
+            trimmed == "+++"
+        } else {
+            trimmed == "---" || trimmed == "..."
+        };
+        if closes && !lines.is_empty() {
+            return self.emit_current_block();
+        }
+        let continues = if lines.is_empty() {
+            is_front_matter_field(trimmed, toml)
+        } else {
+            is_front_matter_line(lines, trimmed, toml)
+        };
+        if !continues {
+            let mut output = self.reparse_unclosed_front_matter();
+            output.push_str(&self.process_line(line).unwrap_or_default());
+            return (!output.is_empty()).then_some(output);
+        }
+        lines.push(line.trim_end_matches('\n').to_string());
+        None
+    }
+
+    /// Front matter that never closed is ordinary markdown after all
+    fn reparse_unclosed_front_matter(&mut self) -> String {
+        let BlockBuilder::FrontMatter { lines, toml } =
+            std::mem::replace(&mut self.current_block, BlockBuilder::None)
+        else {
+            return String::new();
+        };
+        self.state = ParserState::Ready;
+        let fence = if toml { "+++" } else { "---" };
+        self.process_lines(std::iter::once(fence.to_string()).chain(lines).collect())
+    }
+
+    /// Format closed front matter: the title as a heading if asked for, then
+    /// a box of the other fields
+    fn format_front_matter(&self, lines: &[String], toml: bool) -> String {
+        let mut fields = if toml {
+            parse_toml_front_matter(lines)
+        } else {
+            parse_yaml_front_matter(lines)
+        };
+
+        let mut output = String::new();
+        if self.front_matter_title {
+            if let Some(pos) = fields.iter().position(|(key, _)| key == "title") {
+                let (_, title) = fields.remove(pos);
+                output.push_str(&self.format_heading(1, &escape_inline_markdown(&title)));
+            }
+        }
+        if self.front_matter_style == FrontMatterStyle::Hide || fields.is_empty() {
+            return output;
+        }
+
+        let key_width = fields
+            .iter()
+            .map(|(key, _)| key.width() + 1)
+            .max()
+            .unwrap_or(0);
+        let max_width = self.width.saturating_sub(4).max(1);
+        let rows: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| {
+                let row = format!(
+                    "{:<width$} {}",
+                    format!("{}:", key),
+                    value,
+                    width = key_width
+                );
+                if row.width() <= max_width {
+                    row
+                } else {
+                    let mut cut = self
+                        .split_ansi_by_width(&row, max_width.saturating_sub(1))
+                        .swap_remove(0);
+                    cut.push('…');
+                    cut
+                }
+            })
+            .collect();
+        let inner_width = rows.iter().map(|row| row.width()).max().unwrap_or(0);
+
+        let dim = "\u{001b}[2m";
+        let reset = "\u{001b}[0m";
+        output.push_str(&format!(
+            "{}╭{}╮{}\n",
+            dim,
+            "─".repeat(inner_width + 2),
+            reset
+        ));
+        for row in rows {
+            let padding = " ".repeat(inner_width - row.width());
+            output.push_str(&format!("{}│ {}{} │{}\n", dim, row, padding, reset));
         }
+        output.push_str(&format!(
+            "{}╰{}╯{}\n\n",
+            dim,
+            "─".repeat(inner_width + 2),
+            reset
+        ));
+        output
     }
 
     fn handle_ready_state(&mut self, line: &str) -> Option<String> {
@@ -419,6 +1402,17 @@ impl StreamingParser {
             return None;
         }
 
+        // A table of contents marker is filled in at the end when the
+        // contents come first, and is dropped when they come after the
+        // document. Without a table of contents it is ordinary text.
+        if is_toc_marker(trimmed) {
+            match self.toc {
+                Some(TocPosition::Start) => return Some(TOC_PLACEHOLDER.to_string()),
+                Some(TocPosition::End) => return None,
+                None => {}
+            }
+        }
+
         // Check for HTML comment line (<!-- ... -->)
         // These should be silently skipped
         if self.is_html_comment_line(trimmed) {
@@ -429,7 +1423,7 @@ impl StreamingParser {
         if let Some(level) = self.parse_atx_heading(trimmed) {
             let text = trimmed[level..].trim_start().to_string();
             // Headings complete on the same line - emit immediately
-            return Some(self.format_heading(level, &text));
+            return Some(self.format_nested(|p| p.format_heading(level, &text)));
         }
 
         // Check for code fence (```)
@@ -446,6 +1440,20 @@ impl StreamingParser {
             return None;
         }
 
+        // Check for $$ display math, which may open and close on one line
+        if self.is_math_block_open(trimmed) {
+            let content = trimmed.trim();
+            if content.len() > 4 && content.ends_with("$$") {
+                let source = &content[2..content.len() - 2];
+                return Some(self.format_nested(|p| p.format_display_math(source)));
+            }
+            self.state = ParserState::InMathBlock;
+            self.current_block = BlockBuilder::MathBlock {
+                lines: vec![trimmed.to_string()],
+            };
+            return None;
+        }
+
         // Check for blockquote
         if let Some(nesting_level) = self.parse_blockquote_marker(trimmed) {
             let content = self.strip_blockquote_markers(trimmed, nesting_level);
@@ -460,7 +1468,7 @@ impl StreamingParser {
         // Check for horizontal rule (thematic break)
         // Must be checked before list items per GFM spec
         if self.is_horizontal_rule(trimmed) {
-            return Some(self.format_horizontal_rule());
+            return Some(self.format_nested(|p| p.format_horizontal_rule()));
         }
 
         // Check for list item (- or digit.)
@@ -473,16 +1481,54 @@ impl StreamingParser {
         }
 
         // Check for link reference definition [label]: url "title"
-        // These are stored but never emit content
-        if let Some((label, url, title)) = self.parse_link_definition(trimmed) {
-            let normalized_label = self.normalize_link_label(&label);
-            // First definition wins (don't overwrite)
-            self.link_definitions
-                .entry(normalized_label)
-                .or_insert((url, title));
+        // These are stored but never emit content. The destination and the
+        // title may each start on the following line.
+        if let Some((label, rest)) = self.parse_link_definition(trimmed) {
+            let (url, title) = if rest.trim().is_empty() {
+                (None, None)
+            } else if let Some((url, title)) = parse_link_definition_tail(rest) {
+                if title.is_some() {
+                    self.add_link_definition(&label, url, title);
+                    return None;
+                }
+                (Some(url), None)
+            } else if let Some((url, title)) = parse_link_definition_open_title(rest) {
+                (Some(url), Some((title, false)))
+            } else {
+                // Trailing text after the destination: not a definition
+                return self.start_paragraph(trimmed);
+            };
+            self.state = ParserState::InLinkDefinition;
+            self.current_block = BlockBuilder::LinkDefinition {
+                label,
+                lines: vec![trimmed.to_string()],
+                url,
+                title,
+            };
             return None;
         }
 
+        // Check for an HTML <pre> block, rendered like a fenced code block
+        if let Some((info, rest)) = self.parse_pre_open(trimmed) {
+            self.current_block = BlockBuilder::CodeBlock {
+                lines: Vec::new(),
+                info,
+            };
+            self.state = ParserState::InPreBlock;
+            // A newline directly after <pre> is not part of the content
+            if rest.is_empty() {
+                return None;
+            }
+            return self.handle_in_pre_block(&rest);
+        }
+
+        // Check for a raw HTML block
+        if let Some(end) = self.html_block_start(trimmed, false) {
+            self.state = ParserState::InHtmlBlock { end };
+            self.current_block = BlockBuilder::HtmlBlock { lines: Vec::new() };
+            return self.handle_in_html_block(trimmed);
+        }
+
         // Check for indented code block (4+ spaces or tab)
         // Must come after list check (lists take precedence)
         if let Some(content) = self.is_indented_code_line(trimmed) {
@@ -494,13 +1540,182 @@ impl StreamingParser {
         }
 
         // Otherwise, start a paragraph
-        self.state = ParserState::InParagraph;
+        self.start_paragraph(trimmed)
+    }
+
+    /// Whether a line opens `$$` display math
+    fn is_math_block_open(&self, line: &str) -> bool {
+        let content = line.trim_start_matches(' ');
+        self.math && line.len() - content.len() <= 3 && content.starts_with("$$")
+    }
+
+    /// Collect display math until a line ending with `$$`
+    fn handle_in_math_block(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim_end_matches('\n');
+        // Math cannot contain a blank line, so the dollars were just text
+        if trimmed.trim().is_empty() {
+            self.math_block_to_paragraph();
+            return self.emit_current_block();
+        }
+        if let BlockBuilder::MathBlock { lines } = &mut self.current_block {
+            lines.push(trimmed.to_string());
+        }
+        if trimmed.trim_end().ends_with("$$") {
+            return self.emit_current_block();
+        }
+        None
+    }
+
+    /// Turn display math that never closed back into a paragraph
+    fn math_block_to_paragraph(&mut self) {
+        if let BlockBuilder::MathBlock { lines } =
+            std::mem::replace(&mut self.current_block, BlockBuilder::None)
+        {
+            self.current_block = BlockBuilder::Paragraph { lines };
+        }
+    }
+
+    fn start_paragraph(&mut self, line: &str) -> Option<String> {
+        self.state = ParserState::InParagraph;
         self.current_block = BlockBuilder::Paragraph {
-            lines: vec![trimmed.to_string()],
+            lines: vec![line.to_string()],
         };
         None
     }
 
+    /// Continue a link reference definition from the previous line
+    fn handle_in_link_definition(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim_end_matches('\n');
+        let BlockBuilder::LinkDefinition {
+            label,
+            mut lines,
+            url,
+            title,
+        } = std::mem::replace(&mut self.current_block, BlockBuilder::None)
+        else {
+            self.state = ParserState::Ready;
+            return self.handle_ready_state(line);
+        };
+        self.state = ParserState::Ready;
+
+        match (url, title) {
+            // A title continues until its closing delimiter; a blank line
+            // or text after the delimiter means it isn't a title after all
+            (Some(url), Some((mut title, own_line))) => {
+                if !trimmed.trim().is_empty() {
+                    title.push('\n');
+                    title.push_str(trimmed);
+                    lines.push(trimmed.to_string());
+                    let chars: Vec<char> = title.chars().collect();
+                    match parse_link_title(&chars, 0) {
+                        None => {
+                            self.state = ParserState::InLinkDefinition;
+                            self.current_block = BlockBuilder::LinkDefinition {
+                                label,
+                                lines,
+                                url: Some(url),
+                                title: Some((title, own_line)),
+                            };
+                            return None;
+                        }
+                        Some((text, end)) if chars[end..].iter().all(|c| c.is_whitespace()) => {
+                            self.add_link_definition(&label, url, Some(text));
+                            return None;
+                        }
+                        Some(_) => {}
+                    }
+                }
+                let mut output = if own_line {
+                    // The definition stands without a title, whose lines are
+                    // parsed afresh
+                    self.add_link_definition(&label, url, None);
+                    let title_lines = title.lines().count();
+                    let replayed = lines.split_off(lines.len() - title_lines);
+                    self.process_lines(replayed)
+                } else {
+                    self.state = ParserState::InParagraph;
+                    self.current_block = BlockBuilder::Paragraph { lines };
+                    String::new()
+                };
+                if trimmed.trim().is_empty() {
+                    output.push_str(&self.process_line(line).unwrap_or_default());
+                }
+                (!output.is_empty()).then_some(output)
+            }
+            // A title may follow on its own line; otherwise the line is
+            // parsed afresh
+            (Some(url), None) => match parse_link_title_line(trimmed) {
+                Some(title) => {
+                    self.add_link_definition(&label, url, Some(title));
+                    None
+                }
+                None if starts_open_link_title(trimmed) => {
+                    lines.push(trimmed.to_string());
+                    self.state = ParserState::InLinkDefinition;
+                    self.current_block = BlockBuilder::LinkDefinition {
+                        label,
+                        lines,
+                        url: Some(url),
+                        title: Some((trimmed.trim_start().to_string(), true)),
+                    };
+                    None
+                }
+                None => {
+                    self.add_link_definition(&label, url, None);
+                    self.handle_ready_state(line)
+                }
+            },
+            // The destination must follow the label line
+            (None, _) => {
+                let (url, title) = match parse_link_definition_tail(trimmed) {
+                    Some((url, Some(title))) => {
+                        self.add_link_definition(&label, url, Some(title));
+                        return None;
+                    }
+                    Some((url, None)) => (url, None),
+                    None => match parse_link_definition_open_title(trimmed) {
+                        Some((url, title)) => (url, Some((title, false))),
+                        None => {
+                            // Without a destination the label is an ordinary paragraph
+                            self.state = ParserState::InParagraph;
+                            self.current_block = BlockBuilder::Paragraph { lines };
+                            return self.handle_in_paragraph(line);
+                        }
+                    },
+                };
+                lines.push(trimmed.to_string());
+                self.state = ParserState::InLinkDefinition;
+                self.current_block = BlockBuilder::LinkDefinition {
+                    label,
+                    lines,
+                    url: Some(url),
+                    title,
+                };
+                None
+            }
+        }
+    }
+
+    /// Process lines that have already been read, as when a block turns out
+    /// not to be what it first looked like
+    fn process_lines(&mut self, lines: Vec<String>) -> String {
+        let mut output = String::new();
+        for line in lines {
+            if let Some(emission) = self.process_line(&format!("{}\n", line)) {
+                output.push_str(&emission);
+            }
+        }
+        output
+    }
+
+    /// Store a link reference definition; the first definition of a label wins
+    fn add_link_definition(&mut self, label: &str, url: String, title: Option<String>) {
+        let normalized_label = self.normalize_link_label(label);
+        self.link_definitions
+            .entry(normalized_label)
+            .or_insert((url, title));
+    }
+
     fn handle_in_paragraph(&mut self, line: &str) -> Option<String> {
         let trimmed = line.trim_end_matches('\n');
 
@@ -516,10 +1731,26 @@ impl StreamingParser {
                 let text = lines.join(" ");
                 self.state = ParserState::Ready;
                 self.current_block = BlockBuilder::None;
-                return Some(self.format_heading(level, &text));
+                return Some(self.format_nested(|p| p.format_heading(level, &text)));
             }
         }
 
+        // A code fence, display math or an HTML block (other than a lone
+        // tag) interrupts the paragraph
+        if self.parse_code_fence(trimmed).is_some()
+            || self.is_math_block_open(trimmed)
+            || self.html_block_start(trimmed, true).is_some()
+        {
+            let emission = self.emit_current_block();
+            let new_emission = self.handle_ready_state(line);
+
+            return match (emission, new_emission) {
+                (Some(e1), Some(e2)) => Some(format!("{}{}", e1, e2)),
+                (Some(e), None) | (None, Some(e)) => Some(e),
+                (None, None) => None,
+            };
+        }
+
         // Check if this might be a table delimiter row
         if let BlockBuilder::Paragraph { lines } = &self.current_block {
             if lines.len() == 1 && self.is_table_delimiter_row(trimmed) {
@@ -527,6 +1758,14 @@ impl StreamingParser {
                 let header = self.parse_table_row(&lines[0]);
                 let alignments = self.parse_alignments(trimmed);
 
+                // The header and delimiter rows must have the same number of cells
+                if header.len() != alignments.len() {
+                    if let BlockBuilder::Paragraph { lines } = &mut self.current_block {
+                        lines.push(trimmed.to_string());
+                    }
+                    return None;
+                }
+
                 // Promote to table
                 self.current_block = BlockBuilder::Table {
                     header,
@@ -534,7 +1773,7 @@ impl StreamingParser {
                     rows: Vec::new(),
                 };
                 self.state = ParserState::InTable;
-                return None; // No emission yet
+                return self.stream_table_rows(); // No emission yet unless streaming
             }
         }
 
@@ -555,25 +1794,22 @@ impl StreamingParser {
             ..
         } = &self.state
         {
-            // Closing fence can have 0-3 spaces of indentation (normal case)
-            // or 4+ spaces (when inside a list item)
-            let line_trimmed = trimmed.trim_start();
+            // Closing fence can have 0-3 spaces of indentation, on top of
+            // the 4-space indent of a list item for fences inside lists
+            let line_trimmed = trimmed.trim_start_matches(' ');
+            let container_indent = if *indent_offset >= 4 { 4 } else { 0 };
+            let closing_indent = trimmed.len() - line_trimmed.len();
 
-            // Check if this line is just the fence (possibly with trailing spaces)
-            if line_trimmed.starts_with(fence) && line_trimmed.trim() == fence.trim() {
+            if closing_indent <= container_indent + 3 && self.is_closing_fence(line_trimmed, fence)
+            {
                 // Closing fence - emit the block
                 return self.emit_current_block();
             }
 
-            // Add line to code block, stripping the indent offset
+            // Add line to code block, removing up to indent_offset leading spaces
             if let BlockBuilder::CodeBlock { lines, .. } = &mut self.current_block {
-                // Strip indent_offset spaces from the beginning if present
-                let line_to_add = if *indent_offset > 0 && trimmed.len() >= *indent_offset {
-                    &trimmed[*indent_offset..]
-                } else {
-                    trimmed
-                };
-                lines.push(line_to_add.to_string());
+                let strip = closing_indent.min(*indent_offset);
+                lines.push(trimmed[strip..].to_string());
             }
         }
 
@@ -740,9 +1976,8 @@ impl StreamingParser {
             return self.emit_current_block();
         }
 
-        // Check if line looks like a table row (contains |)
-        if !trimmed.contains('|') {
-            // Not a table row - emit table and start new block
+        if self.interrupts_table(trimmed) {
+            // Another block starts - emit table and start new block
             let emission = self.emit_current_block();
             let new_emission = self.handle_ready_state(line);
 
@@ -752,13 +1987,97 @@ impl StreamingParser {
                 (None, None) => None,
             }
         } else {
-            // Parse and accumulate data row
-            let cells = self.parse_table_row(trimmed);
-            if let BlockBuilder::Table { rows, .. } = &mut self.current_block {
+            // Parse and accumulate data row, even without pipes
+            let mut cells = self.parse_table_row(trimmed);
+            if let BlockBuilder::Table { header, rows, .. } = &mut self.current_block {
+                // Excess cells are ignored and missing ones left empty
+                cells.resize(header.len(), String::new());
                 rows.push(cells);
             }
-            None
+            self.stream_table_rows()
+        }
+    }
+
+    /// Whether a line starts a block that ends a table without a blank line
+    fn interrupts_table(&self, line: &str) -> bool {
+        let content = line.trim_start();
+        if line.len() - content.len() > 3 {
+            return false;
+        }
+
+        self.parse_atx_heading(content).is_some()
+            || self.parse_blockquote_marker(line).is_some()
+            || self.parse_code_fence(line).is_some()
+            || self.is_horizontal_rule(line)
+            || self.parse_list_item(line).is_some()
+            || self.html_block_start(line, true).is_some()
+    }
+
+    /// When streaming tables, emit the header once the look-ahead window has
+    /// filled, then each data row as soon as it is parsed
+    fn stream_table_rows(&mut self) -> Option<String> {
+        let lookahead = self.table_lookahead?;
+        let BlockBuilder::Table {
+            header,
+            alignments,
+            rows,
+        } = &mut self.current_block
+        else {
+            return None;
+        };
+        if self.table_stream.is_none() && rows.len() < lookahead {
+            return None;
+        }
+        let (header, alignments, rows) = (header.clone(), alignments.clone(), std::mem::take(rows));
+
+        let output = self.format_nested(|p| {
+            let mut output = String::new();
+            let mut stream = p.table_stream.take().unwrap_or_else(|| {
+                let col_widths = p.table_column_widths(&header, &rows);
+                if let Some(widths) = &col_widths {
+                    output.push_str(&p.format_table_header(&header, &alignments, widths));
+                }
+                TableStream {
+                    col_widths,
+                    rows_emitted: 0,
+                }
+            });
+            output.push_str(&p.format_streamed_rows(&mut stream, &header, &alignments, &rows));
+            p.table_stream = Some(stream);
+            output
+        });
+
+        Some(output).filter(|o| !o.is_empty())
+    }
+
+    /// Render rows of a streamed table using its fixed layout
+    fn format_streamed_rows(
+        &self,
+        stream: &mut TableStream,
+        header: &[String],
+        alignments: &[Alignment],
+        rows: &[Vec<String>],
+    ) -> String {
+        let mut output = String::new();
+        for row in rows {
+            match &stream.col_widths {
+                Some(widths) => output.push_str(&self.format_table_row(
+                    row,
+                    widths,
+                    alignments,
+                    TableRowStyle {
+                        truncate: true,
+                        striped: stream.rows_emitted % 2 == 1,
+                        ..Default::default()
+                    },
+                )),
+                None => {
+                    output.push_str(&self.format_table_record(header, row, stream.rows_emitted > 0))
+                }
+            }
+            stream.rows_emitted += 1;
         }
+        output
     }
 
     fn handle_in_blockquote(&mut self, line: &str) -> Option<String> {
@@ -835,6 +2154,166 @@ impl StreamingParser {
         }
     }
 
+    fn handle_in_pre_block(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim_end_matches('\n');
+
+        // Everything up to </pre> is content; the rest of that line is dropped
+        let lower = trimmed.to_ascii_lowercase();
+        let (content, closed) = match lower.find("</pre>") {
+            Some(pos) => {
+                let before = &trimmed[..pos];
+                let before = match before.to_ascii_lowercase().rfind("</code>") {
+                    Some(code_pos) if before[code_pos + 7..].trim().is_empty() => {
+                        &before[..code_pos]
+                    }
+                    _ => before,
+                };
+                (before, true)
+            }
+            None => (trimmed, false),
+        };
+
+        if let BlockBuilder::CodeBlock { lines, .. } = &mut self.current_block {
+            if !closed || !content.is_empty() {
+                lines.push(decode_html_entities(content));
+            }
+        }
+
+        if closed {
+            return self.emit_current_block();
+        }
+        None
+    }
+
+    fn handle_in_html_block(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim_end_matches('\n');
+        let ParserState::InHtmlBlock { end } = self.state else {
+            return None;
+        };
+
+        if end == HtmlBlockEnd::BlankLine && trimmed.trim().is_empty() {
+            return self.emit_current_block();
+        }
+
+        if let BlockBuilder::HtmlBlock { lines } = &mut self.current_block {
+            lines.push(trimmed.to_string());
+        }
+
+        let closed = match end {
+            HtmlBlockEnd::RawText => {
+                let lower = trimmed.to_ascii_lowercase();
+                ["</pre>", "</script>", "</style>", "</textarea>"]
+                    .iter()
+                    .any(|tag| lower.contains(tag))
+            }
+            HtmlBlockEnd::Marker(marker) => trimmed.contains(marker),
+            HtmlBlockEnd::BlankLine => false,
+        };
+        if closed {
+            return self.emit_current_block();
+        }
+        None
+    }
+
+    /// Check whether a line starts a raw HTML block, returning how the block
+    /// ends. Follows the seven GFM start conditions; a lone open or closing
+    /// tag (condition 7) cannot interrupt a paragraph.
+    fn html_block_start(&self, line: &str, interrupting: bool) -> Option<HtmlBlockEnd> {
+        let leading_spaces = line.len() - line.trim_start_matches(' ').len();
+        if leading_spaces > 3 {
+            return None;
+        }
+        let rest = &line[leading_spaces..];
+        if !rest.starts_with('<') {
+            return None;
+        }
+        let lower = rest.to_ascii_lowercase();
+        let ends_name = |after: &str| {
+            after.is_empty() || after.starts_with([' ', '\t', '>']) || after.starts_with("/>")
+        };
+
+        for tag in ["pre", "script", "style", "textarea"] {
+            if let Some(after) = lower[1..].strip_prefix(tag) {
+                if after.is_empty() || after.starts_with([' ', '\t', '>']) {
+                    return Some(HtmlBlockEnd::RawText);
+                }
+            }
+        }
+        if lower.starts_with("<!--") {
+            return Some(HtmlBlockEnd::Marker("-->"));
+        }
+        if lower.starts_with("<?") {
+            return Some(HtmlBlockEnd::Marker("?>"));
+        }
+        if lower.starts_with("<![cdata[") {
+            return Some(HtmlBlockEnd::Marker("]]>"));
+        }
+        if lower[1..].starts_with('!') && lower[2..].starts_with(|c: char| c.is_ascii_alphabetic())
+        {
+            return Some(HtmlBlockEnd::Marker(">"));
+        }
+
+        let name_start = if lower.starts_with("</") { 2 } else { 1 };
+        let name_len = lower[name_start..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(lower.len() - name_start);
+        let name = &lower[name_start..name_start + name_len];
+        if HTML_BLOCK_TAGS.contains(&name) && ends_name(&lower[name_start + name_len..]) {
+            return Some(HtmlBlockEnd::BlankLine);
+        }
+
+        if !interrupting && is_complete_html_tag_line(rest) {
+            return Some(HtmlBlockEnd::BlankLine);
+        }
+        None
+    }
+
+    /// Check for an opening `<pre>` tag (optionally followed by `<code>`) at
+    /// the start of a line. Returns the language, taken from a `lang` attribute
+    /// or a `language-*`/`lang-*` class, and the rest of the line.
+    fn parse_pre_open(&self, line: &str) -> Option<(String, String)> {
+        let leading_spaces = line.len() - line.trim_start().len();
+        if leading_spaces > 3 {
+            return None;
+        }
+        let trimmed = line.trim_start();
+        let lower = trimmed.to_ascii_lowercase();
+        if !(lower.starts_with("<pre>") || lower.starts_with("<pre ")) {
+            return None;
+        }
+
+        let tag_end = trimmed.find('>')?;
+        let mut language = self.html_code_language(&trimmed[4..tag_end]);
+        let mut rest = &trimmed[tag_end + 1..];
+
+        let rest_lower = rest.to_ascii_lowercase();
+        if rest_lower.starts_with("<code>") || rest_lower.starts_with("<code ") {
+            if let Some(code_end) = rest.find('>') {
+                if language.is_empty() {
+                    language = self.html_code_language(&rest[5..code_end]);
+                }
+                rest = &rest[code_end + 1..];
+            }
+        }
+
+        Some((language, rest.to_string()))
+    }
+
+    /// Find the language of an HTML code element from its attributes
+    fn html_code_language(&self, attrs: &str) -> String {
+        if let Some(class) = self.extract_attr(attrs, "class") {
+            for class_name in class.split_whitespace() {
+                if let Some(language) = class_name
+                    .strip_prefix("language-")
+                    .or_else(|| class_name.strip_prefix("lang-"))
+                {
+                    return language.to_string();
+                }
+            }
+        }
+        self.extract_attr(attrs, "lang").unwrap_or_default()
+    }
+
     fn parse_atx_heading(&self, line: &str) -> Option<usize> {
         let mut level = 0;
         for ch in line.chars() {
@@ -878,10 +2357,8 @@ impl StreamingParser {
     /// Check if a line is entirely an HTML comment (<!-- ... -->)
     fn is_html_comment_line(&self, line: &str) -> bool {
         let trimmed = line.trim();
-        if !trimmed.starts_with("<!--") {
-            return false;
-        }
-        if !trimmed.ends_with("-->") {
+        // Shorter lines such as `<!-->` overlap the markers
+        if trimmed.len() < 7 || !trimmed.starts_with("<!--") || !trimmed.ends_with("-->") {
             return false;
         }
         // Ensure the comment is properly formed (has content or is empty)
@@ -939,6 +2416,9 @@ impl StreamingParser {
         count >= 3 && rule_char.is_some()
     }
 
+    /// Parse an opening code fence: a run of 3+ backticks or tildes, then an
+    /// optional info string. Returns (info, fence, indent), where the fence is
+    /// the whole run so the block only closes on a fence at least as long.
     fn parse_code_fence(&self, line: &str) -> Option<(String, String, usize)> {
         // Code fences can have 0-3 spaces of indentation
         let leading_spaces = line.len() - line.trim_start().len();
@@ -948,18 +2428,33 @@ impl StreamingParser {
         }
 
         let trimmed = line.trim_start();
+        let fence_char = trimmed.chars().next().filter(|&c| c == '`' || c == '~')?;
+        let fence_len = trimmed.chars().take_while(|&c| c == fence_char).count();
+        if fence_len < 3 {
+            return None;
+        }
 
-        if let Some(rest) = trimmed.strip_prefix("```") {
-            let fence = "```".to_string();
-            let info = rest.trim().to_string();
-            Some((info, fence, leading_spaces))
-        } else if let Some(rest) = trimmed.strip_prefix("~~~") {
-            let fence = "~~~".to_string();
-            let info = rest.trim().to_string();
-            Some((info, fence, leading_spaces))
-        } else {
-            None
+        // Backticks in the info string would make this inline code, not a fence
+        let info = trimmed[fence_len..].trim();
+        if fence_char == '`' && info.contains('`') {
+            return None;
         }
+
+        Some((
+            info.to_string(),
+            trimmed[..fence_len].to_string(),
+            leading_spaces,
+        ))
+    }
+
+    /// Whether a line closes a code block opened with `fence`: the same
+    /// character repeated at least as many times, followed only by spaces
+    fn is_closing_fence(&self, line: &str, fence: &str) -> bool {
+        let Some(fence_char) = fence.chars().next() else {
+            return false;
+        };
+        let run = line.chars().take_while(|&c| c == fence_char).count();
+        run >= fence.len() && line[run..].trim_end_matches([' ', '\t']).is_empty()
     }
 
     /// Normalize a link label for matching per GFM spec:
@@ -974,10 +2469,10 @@ impl StreamingParser {
             .to_lowercase()
     }
 
-    /// Try to parse a link reference definition from a line.
-    /// Returns Some((label, url, optional_title)) if successful.
+    /// Try to parse the start of a link reference definition from a line.
+    /// Returns Some((label, rest_of_line)) if successful.
     /// Link definition format: [label]: url "optional title"
-    fn parse_link_definition(&self, line: &str) -> Option<(String, String, Option<String>)> {
+    fn parse_link_definition<'a>(&self, line: &'a str) -> Option<(String, &'a str)> {
         let trimmed = line.trim_end_matches('\n');
 
         // Check indentation (0-3 spaces allowed)
@@ -1021,49 +2516,9 @@ impl StreamingParser {
 
         // Must be followed by :
         let after_label = &trimmed[label_end + 2..];
-        if !after_label.starts_with(':') {
-            return None;
-        }
-
-        // Parse URL (after : and optional whitespace)
-        let url_part = after_label[1..].trim_start();
-
-        // Empty URL part means this isn't a valid definition
-        if url_part.is_empty() {
-            return None;
-        }
-
-        // URL can be:
-        // 1. Angle-bracketed URL: <url>
-        // 2. Bare URL (no spaces, ends at whitespace or end of line)
-        let (url, remaining) = if let Some(stripped) = url_part.strip_prefix('<') {
-            // Angle-bracketed URL
-            if let Some(end) = stripped.find('>') {
-                (&stripped[..end], &stripped[end + 1..])
-            } else {
-                return None; // Unclosed angle bracket
-            }
-        } else {
-            // Bare URL - take until whitespace
-            let end = url_part.find(char::is_whitespace).unwrap_or(url_part.len());
-            (&url_part[..end], &url_part[end..])
-        };
-
-        // Parse optional title
-        let remaining = remaining.trim_start();
-        let title = if remaining.is_empty() {
-            None
-        } else if let Some(stripped) = remaining.strip_prefix('"') {
-            stripped.find('"').map(|end| stripped[..end].to_string())
-        } else if let Some(stripped) = remaining.strip_prefix('\'') {
-            stripped.find('\'').map(|end| stripped[..end].to_string())
-        } else if let Some(stripped) = remaining.strip_prefix('(') {
-            stripped.find(')').map(|end| stripped[..end].to_string())
-        } else {
-            None
-        };
+        let rest = after_label.strip_prefix(':')?;
 
-        Some((label.to_string(), url.to_string(), title))
+        Some((label.to_string(), rest))
     }
 
     fn parse_list_item(&self, line: &str) -> Option<(usize, ListItemType)> {
@@ -1174,26 +2629,116 @@ impl StreamingParser {
 
         match block {
             BlockBuilder::None => None,
-            BlockBuilder::Paragraph { lines } => Some(self.format_paragraph(&lines)),
-            BlockBuilder::CodeBlock { lines, info } => Some(self.format_code_block(&lines, &info)),
-            BlockBuilder::List { items } => Some(self.format_list(&items)),
+            // HTML blocks open and close <details> sections, so they nest their own output
+            BlockBuilder::HtmlBlock { lines } => Some(self.format_html_block(&lines)),
+            BlockBuilder::LinkDefinition {
+                label,
+                mut lines,
+                url: Some(url),
+                title,
+            } => match title {
+                // An unclosed title on the destination line means there was
+                // no definition at all
+                Some((_, false)) => Some(self.format_nested(|p| p.format_paragraph(&lines))),
+                Some((title, true)) => {
+                    self.add_link_definition(&label, url, None);
+                    let title_lines = lines.split_off(lines.len() - title.lines().count());
+                    Some(self.format_nested(|p| p.format_paragraph(&title_lines)))
+                }
+                None => {
+                    self.add_link_definition(&label, url, None);
+                    None
+                }
+            },
+            block => Some(self.format_nested(|p| p.format_block(block))),
+        }
+    }
+
+    /// Format a completed block
+    fn format_block(&mut self, block: BlockBuilder) -> String {
+        match block {
+            BlockBuilder::None | BlockBuilder::HtmlBlock { .. } => String::new(),
+            BlockBuilder::Paragraph { lines } | BlockBuilder::LinkDefinition { lines, .. } => {
+                self.format_paragraph(&lines)
+            }
+            BlockBuilder::FrontMatter { lines, toml } => self.format_front_matter(&lines, toml),
+            BlockBuilder::MathBlock { lines } => {
+                let source = lines.join("\n");
+                let source = source.trim();
+                let source = source.strip_prefix("$$").unwrap_or(source);
+                self.format_display_math(source.strip_suffix("$$").unwrap_or(source))
+            }
+            BlockBuilder::CodeBlock { lines, info } => self
+                .format_delimited_table(&lines, &info)
+                .or_else(|| self.format_math_code_block(&lines, &info))
+                .unwrap_or_else(|| self.format_code_block(&lines, &info)),
+            BlockBuilder::List { items } => self.format_list(&items),
             BlockBuilder::Table {
                 header,
                 alignments,
                 rows,
-            } => Some(self.format_table(&header, &alignments, &rows)),
-            BlockBuilder::Blockquote { lines, .. } => Some(self.format_blockquote(&lines)),
+            } => match self.table_stream.take() {
+                // The header and earlier rows of a streamed table are already out
+                Some(mut stream) => {
+                    let mut output =
+                        self.format_streamed_rows(&mut stream, &header, &alignments, &rows);
+                    if let Some(widths) = &stream.col_widths {
+                        let bottom = self.table_border.chars().bottom;
+                        output.push_str(&self.table_border_line(widths, bottom, &alignments));
+                    }
+                    output.push('\n');
+                    output
+                }
+                None => self.format_table(&header, &alignments, &rows),
+            },
+            BlockBuilder::Blockquote { lines, .. } => self.format_blockquote(&lines),
             BlockBuilder::IndentedCodeBlock { lines } => {
                 let mut lines = lines.clone();
                 // Strip trailing blank lines
                 while lines.last().is_some_and(|l| l.is_empty()) {
                     lines.pop();
                 }
-                Some(self.format_code_block(&lines, ""))
+                self.format_code_block(&lines, "")
             }
         }
     }
 
+    /// Format output at the width left inside any open `<details>` sections,
+    /// then indent it to match
+    fn format_nested(&mut self, format: impl FnOnce(&mut Self) -> String) -> String {
+        let width = self.details_width();
+        let output = format(&mut NarrowedWidth::new(self, width));
+        self.nest_in_details(output)
+    }
+
+    /// Width available inside the open `<details>` sections
+    fn details_width(&self) -> usize {
+        self.width.saturating_sub(2 * self.details_depth).max(20)
+    }
+
+    /// Indent output inside open `<details>` sections, or hide it when
+    /// details are collapsed
+    fn nest_in_details(&self, output: String) -> String {
+        if self.details_depth == 0 {
+            return output;
+        }
+        if self.collapse_details {
+            return String::new();
+        }
+        let indent = "  ".repeat(self.details_depth);
+        output
+            .split_inclusive('\n')
+            .map(|line| {
+                // Lines with nothing visible, such as a trailing reset, stay as they are
+                if self.strip_ansi(line).trim().is_empty() {
+                    line.to_string()
+                } else {
+                    format!("{}{}", indent, line)
+                }
+            })
+            .collect()
+    }
+
     /// Extract all text content from a block for image URL scanning
     fn extract_block_text(&self, block: &BlockBuilder) -> String {
         match block {
@@ -1219,35 +2764,245 @@ impl StreamingParser {
                 .collect::<Vec<_>>()
                 .join("\n"),
             BlockBuilder::IndentedCodeBlock { .. } => String::new(), // Code blocks don't have images
+            BlockBuilder::HtmlBlock { lines } => lines.join("\n"),
+            BlockBuilder::LinkDefinition { lines, .. } => lines.join("\n"),
+            BlockBuilder::FrontMatter { .. } | BlockBuilder::MathBlock { .. } => String::new(),
         }
     }
 
     fn format_heading(&self, level: usize, text: &str) -> String {
+        self.add_heading_anchor(level, text);
+        let section_marker = self.enter_section(level);
+        let number = self
+            .heading_anchors
+            .borrow()
+            .last()
+            .and_then(|anchor| anchor.number.clone());
         let formatted_text = self.format_inline(text);
-        // Heading: blue and bold, with line break after for spacing
+        // Heading: bold and colored by level, with line break after for spacing
         // Replace any ANSI reset codes within the formatted text to restore heading style
         // This prevents inline formatting (like _italic_) from breaking the heading color
-        let heading_style = "\u{001b}[1;34m";
+        let heading_style = self.heading_color(level);
         let formatted_text =
             formatted_text.replace("\u{001b}[0m", &format!("\u{001b}[0m{}", heading_style));
-        format!(
-            "{}{} {}\u{001b}[0m\n\n",
-            heading_style,
-            "#".repeat(level),
-            formatted_text
-        )
+
+        let mut parts = Vec::new();
+        if self.heading_markers {
+            parts.push("#".repeat(level));
+        }
+        parts.extend(number);
+        parts.push(formatted_text);
+        let label = parts.join(" ");
+        let width = self.display_width(&label);
+
+        match (self.heading_style, level) {
+            (HeadingStyle::Banner, 1) => {
+                let padding = " ".repeat(self.width.saturating_sub(width + 1));
+                format!(
+                    "{}{} {}{}\u{001b}[0m\n\n",
+                    section_marker, heading_style, label, padding
+                )
+            }
+            (HeadingStyle::Levels | HeadingStyle::Banner, 1 | 2) => {
+                let rule = if level == 1 { "═" } else { "─" };
+                format!(
+                    "{}{}{}\u{001b}[0m\n{}{}\u{001b}[0m\n\n",
+                    section_marker,
+                    heading_style,
+                    label,
+                    heading_style,
+                    rule.repeat(width.min(self.width))
+                )
+            }
+            _ => format!(
+                "{}{}{}\u{001b}[0m\n\n",
+                section_marker, heading_style, label
+            ),
+        }
     }
 
-    fn format_horizontal_rule(&self) -> String {
-        // Use a line of dashes with dim/gray color
-        let rule = "─".repeat(self.width);
-        format!("\u{001b}[2m{}\u{001b}[0m\n\n", rule)
+    /// The SGR codes for a heading of the given level
+    fn heading_color(&self, level: usize) -> &'static str {
+        match (self.heading_style, level) {
+            (HeadingStyle::Uniform, _) => "\u{001b}[1;34m",
+            (HeadingStyle::Banner, 1) => "\u{001b}[1;97;44m",
+            (_, 1) => "\u{001b}[1;34m",
+            (_, 2) => "\u{001b}[1;36m",
+            (_, 3) => "\u{001b}[1;32m",
+            (_, 4) => "\u{001b}[1;33m",
+            (_, 5) => "\u{001b}[1;35m",
+            _ => "\u{001b}[1;90m",
+        }
     }
 
-    fn format_paragraph(&self, lines: &[String]) -> String {
-        let mut result = String::new();
+    /// Count a heading and return its hierarchical number, from the highest
+    /// level seen so far down to this one
+    fn next_heading_number(&self, level: usize, top: usize) -> String {
+        let mut counters = self.heading_counters.borrow_mut();
+        counters[level - 1] += 1;
+        for counter in counters[level..].iter_mut() {
+            *counter = 0;
+        }
+        counters[top - 1..level]
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
 
-        for (i, line) in lines.iter().enumerate() {
+    /// Track the heading path for section extraction. Returns a marker for
+    /// where a selected section starts or ends, or nothing.
+    fn enter_section(&self, level: usize) -> &'static str {
+        if self.sections.is_empty() {
+            return "";
+        }
+        let text = self
+            .heading_anchors
+            .borrow()
+            .last()
+            .map(|anchor| anchor.text.clone())
+            .unwrap_or_default();
+
+        let mut state = self.section_state.borrow_mut();
+        state.found.resize(self.sections.len(), false);
+        state.path.retain(|&(outer, _)| outer < level);
+        state.path.push((level, text));
+
+        let was_shown = state.current.is_some();
+        if state.current.is_some_and(|section| level <= section) {
+            state.current = None;
+        }
+        if state.current.is_none() {
+            let path: Vec<String> = state.path.iter().map(|(_, text)| text.clone()).collect();
+            let matched = (0..self.sections.len())
+                .find(|&i| !state.found[i] && self.sections[i].matches(&path));
+            if let Some(i) = matched {
+                state.found[i] = true;
+                state.current = Some(level);
+            }
+        }
+
+        match (was_shown, state.current.is_some()) {
+            (false, true) => SECTION_START,
+            (true, false) => SECTION_END,
+            _ => "",
+        }
+    }
+
+    /// Record a heading's anchor, numbering repeated slugs as GitHub does
+    fn add_heading_anchor(&self, level: usize, text: &str) {
+        let text = self.heading_plain_text(text);
+        let mut anchors = self.heading_anchors.borrow_mut();
+        let base = slugify(&text);
+        let mut slug = base.clone();
+        let mut n = 0;
+        while anchors.iter().any(|anchor| anchor.slug == slug) {
+            n += 1;
+            slug = format!("{}-{}", base, n);
+        }
+        let number = self.number_headings.then(|| {
+            let top = anchors
+                .iter()
+                .map(|anchor| anchor.level)
+                .fold(level, usize::min);
+            self.next_heading_number(level, top)
+        });
+        anchors.push(HeadingAnchor {
+            level,
+            text,
+            slug,
+            number,
+        });
+    }
+
+    /// The text of a heading as it reads once rendered, without markup
+    fn heading_plain_text(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut result = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '\\' && chars.get(i + 1).is_some_and(|&c| is_ascii_punctuation(c)) {
+                result.push(chars[i + 1]);
+                i += 2;
+                continue;
+            }
+            if c == '`' {
+                let run_len = chars[i..].iter().take_while(|&&c| c == '`').count();
+                if let Some(end) = find_code_span_end(&chars, i + run_len, run_len) {
+                    let content: String = chars[i + run_len..end].iter().collect();
+                    result.push_str(content.trim());
+                    i = end + run_len;
+                } else {
+                    result.extend(&chars[i..i + run_len]);
+                    i += run_len;
+                }
+                continue;
+            }
+            if c == '!' {
+                if let Some(img) = self.parse_image(&chars, i) {
+                    result.push_str(&self.heading_plain_text(&img.alt));
+                    i = img.end_pos;
+                    continue;
+                }
+            }
+            if c == '[' {
+                if let Some(link) = self.parse_link(&chars, i) {
+                    result.push_str(&self.heading_plain_text(&link.text));
+                    i = link.end_pos;
+                    continue;
+                }
+            }
+            if c == '<' {
+                if let Some((_, text, end)) = parse_angle_autolink(&chars, i) {
+                    result.push_str(&text);
+                    i = end;
+                    continue;
+                }
+                // Drop HTML tags
+                if chars
+                    .get(i + 1)
+                    .is_some_and(|&c| c.is_ascii_alphabetic() || c == '/')
+                {
+                    if let Some(len) = chars[i..].iter().position(|&c| c == '>') {
+                        i += len + 1;
+                        continue;
+                    }
+                }
+            }
+            if c == '&' {
+                if let Some((decoded, consumed)) = decode_html_entity(&chars, i) {
+                    result.push_str(&decoded);
+                    i += consumed;
+                    continue;
+                }
+            }
+            // Emphasis markers; an underscore inside a word is literal
+            let in_word = i > 0
+                && chars[i - 1].is_alphanumeric()
+                && chars.get(i + 1).is_some_and(|c| c.is_alphanumeric());
+            if c == '*' || c == '~' || (c == '_' && !in_word) {
+                i += 1;
+                continue;
+            }
+            result.push(c);
+            i += 1;
+        }
+
+        result.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    fn format_horizontal_rule(&self) -> String {
+        // Use a line of dashes with dim/gray color
+        let rule = "─".repeat(self.width);
+        format!("\u{001b}[2m{}\u{001b}[0m\n\n", rule)
+    }
+
+    fn format_paragraph(&self, lines: &[String]) -> String {
+        let mut result = String::new();
+
+        for (i, line) in lines.iter().enumerate() {
             // Check for hard line break: 2+ trailing spaces or trailing backslash
             let has_hard_break = line.ends_with("  ")
                 || line.ends_with("   ")
@@ -1283,355 +3038,1282 @@ impl StreamingParser {
         format!("{}\n\n", wrapped_segments.join("\n"))
     }
 
-    /// Convert a theme name string to an EmbeddedThemeName enum variant
-    fn theme_name_to_enum(name: &str) -> Option<EmbeddedThemeName> {
-        // Find matching theme by comparing string names
-        EmbeddedLazyThemeSet::theme_names()
-            .iter()
-            .find(|theme| theme.as_name().eq_ignore_ascii_case(name))
-            .copied()
-    }
-
-    fn format_code_block(&self, lines: &[String], info: &str) -> String {
+    /// Render a raw HTML block. Wrapper elements are stripped, `<table>`s are
+    /// drawn as tables, `<picture>` shows its image, and comments, scripts and
+    /// styles are dropped; the remaining text is formatted as paragraphs.
+    fn format_html_block(&mut self, lines: &[String]) -> String {
+        let html = lines.join("\n");
+        let lower = html.to_ascii_lowercase();
         let mut output = String::new();
+        let mut text = String::new();
+        let mut pos = 0;
 
-        // Map common aliases to their syntect language names
-        let language = match info.to_lowercase().as_str() {
-            "jsx" => "javascript",
-            "tsx" => "typescript",
-            _ => info,
-        };
-
-        // Try to find syntax definition for the language
-        let syntax = self
-            .syntax_set
-            .find_syntax_by_token(language)
-            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
-
-        // Get theme from two-face's embedded themes, with fallback
-        let theme = Self::theme_name_to_enum(&self.theme_name)
-            .map(|name| self.theme_set.get(name))
-            .unwrap_or_else(|| self.theme_set.get(EmbeddedThemeName::Base16OceanDark));
+        while pos < html.len() {
+            let Some(offset) = html[pos..].find('<') else {
+                text.push_str(&html[pos..]);
+                break;
+            };
+            text.push_str(&html[pos..pos + offset]);
+            pos += offset;
+            let rest = &lower[pos..];
+
+            // CDATA is shown as literal text
+            if let Some(cdata) = rest.strip_prefix("<![cdata[") {
+                let end = cdata.find("]]>").map_or(html.len(), |e| pos + 9 + e);
+                text.push_str(&escape_inline_markdown(&html[pos + 9..end]));
+                pos = (end + 3).min(html.len());
+                continue;
+            }
 
-        let mut highlighter = HighlightLines::new(syntax, theme);
+            // Comments, processing instructions and declarations are dropped
+            let skipped = [("<!--", "-->"), ("<?", "?>"), ("<!", ">")]
+                .into_iter()
+                .find(|(open, _)| rest.starts_with(open));
+            if let Some((open, close)) = skipped {
+                pos = rest[open.len()..]
+                    .find(close)
+                    .map_or(html.len(), |e| pos + open.len() + e + close.len());
+                continue;
+            }
 
-        // Process lines and collect highlighted output
-        let mut highlighted_lines = Vec::new();
-        for line in lines {
-            // Add newline for proper syntax highlighting state management
-            let line_with_newline = format!("{}\n", line);
-            let ranges = highlighter
-                .highlight_line(&line_with_newline, &self.syntax_set)
-                .unwrap_or_default();
-            let highlighted = as_24_bit_terminal_escaped(&ranges[..], false);
-            // Remove the trailing newline from highlighted output
-            let highlighted = highlighted.trim_end_matches('\n').to_string();
-            highlighted_lines.push(highlighted);
-        }
+            let closing = rest.starts_with("</");
+            let name: String = rest[if closing { 2 } else { 1 }..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect();
+            let Some(tag_len) = rest.find('>').filter(|_| !name.is_empty()) else {
+                // Not a tag; keep the `<` as text
+                text.push('<');
+                pos += 1;
+                continue;
+            };
+            let tag = &html[pos..pos + tag_len + 1];
+            let element_end = || {
+                lower[pos..]
+                    .find(&format!("</{}", name))
+                    .and_then(|e| lower[pos + e..].find('>').map(|g| pos + e + g + 1))
+                    .unwrap_or(html.len())
+            };
 
-        // Each line: 4 space indent + highlighted content (no background)
-        for highlighted in highlighted_lines.iter() {
-            output.push_str("    ");
-            output.push_str(highlighted);
-            output.push('\n');
+            match name.as_str() {
+                "script" | "style" if !closing => {
+                    pos = element_end();
+                    continue;
+                }
+                "table" if !closing => {
+                    let end = element_end();
+                    self.flush_html_text(&mut text, &mut output);
+                    let table = &html[pos..end];
+                    output.push_str(&self.format_nested(|p| p.format_html_table(table)));
+                    pos = end;
+                    continue;
+                }
+                "picture" if !closing => {
+                    let end = element_end();
+                    text.push_str(&self.picture_image(&html[pos..end]));
+                    pos = end;
+                    continue;
+                }
+                "hr" => {
+                    self.flush_html_text(&mut text, &mut output);
+                    output.push_str(&self.format_nested(|p| p.format_horizontal_rule()));
+                }
+                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" if closing => {
+                    let heading = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    text.clear();
+                    if !heading.is_empty() {
+                        let level = name[1..].parse().unwrap_or(1);
+                        output.push_str(&self.format_nested(|p| p.format_heading(level, &heading)));
+                    }
+                }
+                "details" if !closing => {
+                    self.flush_html_text(&mut text, &mut output);
+                    // Without a <summary>, browsers label the section "Details"
+                    if !lower[pos + tag_len + 1..]
+                        .trim_start()
+                        .starts_with("<summary")
+                    {
+                        output.push_str(&self.format_details_summary("Details"));
+                    }
+                    self.details_depth += 1;
+                }
+                "details" => {
+                    self.flush_html_text(&mut text, &mut output);
+                    self.details_depth = self.details_depth.saturating_sub(1);
+                }
+                "summary" if closing => {
+                    let summary = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    text.clear();
+                    // The summary belongs to the enclosing level
+                    self.details_depth = self.details_depth.saturating_sub(1);
+                    output.push_str(&self.format_details_summary(&summary));
+                    self.details_depth += 1;
+                }
+                "li" if !closing => {
+                    self.flush_html_text(&mut text, &mut output);
+                    text.push_str("• ");
+                }
+                _ if HTML_BLOCK_TAGS.contains(&name.as_str()) => {
+                    self.flush_html_text(&mut text, &mut output);
+                }
+                // Inline elements are left for inline formatting
+                _ => text.push_str(tag),
+            }
+            pos += tag_len + 1;
         }
 
-        // Reset ANSI codes to prevent color bleeding
-        output.push_str("\u{001b}[0m");
-
-        // Add blank line after code block for spacing
-        output.push('\n');
+        self.flush_html_text(&mut text, &mut output);
         output
     }
 
-    /// Parse a task list item marker at the start of content.
-    /// Returns Some((is_checked, remaining_content)) if a task list marker is found.
-    /// Task list markers are: [ ] (unchecked) or [x]/[X] (checked), followed by whitespace.
-    fn parse_task_list_marker<'a>(&self, content: &'a str) -> Option<(bool, &'a str)> {
-        let trimmed = content.trim_start();
+    /// Format text collected from an HTML block as a paragraph, collapsing
+    /// whitespace the way a browser would
+    fn flush_html_text(&mut self, text: &mut String, output: &mut String) {
+        let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        text.clear();
+        if !collapsed.is_empty() {
+            output.push_str(&self.format_nested(|p| p.format_paragraph(&[collapsed])));
+        }
+    }
 
-        // Check for [ ] (unchecked)
-        if let Some(rest) = trimmed.strip_prefix("[ ]") {
-            // Must be followed by at least one whitespace
-            if rest.starts_with(' ') || rest.starts_with('\t') {
-                return Some((false, rest.trim_start()));
+    /// Format the summary line of a `<details>` element with a disclosure
+    /// marker showing whether its body follows
+    fn format_details_summary(&mut self, summary: &str) -> String {
+        let marker = if self.collapse_details { "▶" } else { "▼" };
+        let summary = self
+            .format_inline(summary)
+            .replace("\u{001b}[0m", "\u{001b}[0m\u{001b}[1m");
+        let line = format!("{} \u{001b}[1m{}\u{001b}[0m", marker, summary);
+        self.format_nested(|p| format!("{}\n\n", p.wrap_text(&line, "", "  ")))
+    }
+
+    /// Render an HTML `<table>` element. A first row of `<th>` cells becomes
+    /// the header, `align` (or a `text-align` style) sets a column's alignment,
+    /// and a `colspan` cell is followed by empty cells for the columns it spans.
+    fn format_html_table(&self, html: &str) -> String {
+        let lower = html.to_ascii_lowercase();
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        let mut alignments: Vec<Option<Alignment>> = Vec::new();
+        let mut has_header = false;
+
+        let mut pos = 0;
+        while let Some((row_start, _)) = find_html_tag(&lower, pos, &["tr"]) {
+            let row_end = find_html_tag(
+                &lower,
+                row_start + 1,
+                &[
+                    "/tr", "tr", "/thead", "/tbody", "/tfoot", "tbody", "tfoot", "/table",
+                ],
+            )
+            .map_or(lower.len(), |(end, _)| end);
+            let row = &lower[..row_end];
+            pos = row_end;
+
+            let mut cells = Vec::new();
+            let mut all_header_cells = true;
+            let mut cell_pos = row_start;
+            while let Some((cell_start, name)) = find_html_tag(row, cell_pos, &["td", "th"]) {
+                let Some(open_len) = row[cell_start..].find('>') else {
+                    break;
+                };
+                let attrs = &html[cell_start + 1 + name.len()..cell_start + open_len];
+                let content_start = cell_start + open_len + 1;
+                // Closing tags are optional, so a cell also ends at the next one
+                let content_end = find_html_tag(row, content_start, &["/td", "/th", "td", "th"])
+                    .map_or(row_end, |(end, _)| end);
+                cell_pos = content_end;
+
+                let column = cells.len();
+                if alignments.len() <= column {
+                    alignments.resize(column + 1, None);
+                }
+                if alignments[column].is_none() {
+                    alignments[column] = self.html_cell_alignment(attrs);
+                }
+                all_header_cells &= name == "th";
+                cells.push(self.html_cell_text(&html[content_start..content_end]));
+
+                let span = self
+                    .extract_attr(attrs, "colspan")
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .unwrap_or(1)
+                    .clamp(1, 100);
+                cells.resize(cells.len() + span - 1, String::new());
             }
-        }
 
-        // Check for [x] or [X] (checked)
-        if let Some(rest) = trimmed
-            .strip_prefix("[x]")
-            .or_else(|| trimmed.strip_prefix("[X]"))
-        {
-            // Must be followed by at least one whitespace
-            if rest.starts_with(' ') || rest.starts_with('\t') {
-                return Some((true, rest.trim_start()));
+            if cells.is_empty() {
+                continue;
+            }
+            if rows.is_empty() {
+                has_header = all_header_cells;
             }
+            rows.push(cells);
         }
 
-        None
+        if rows.is_empty() {
+            return String::new();
+        }
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        for row in &mut rows {
+            row.resize(columns, String::new());
+        }
+        let header = if has_header {
+            rows.remove(0)
+        } else {
+            Vec::new()
+        };
+        let alignments: Vec<Alignment> = (0..columns)
+            .map(|i| {
+                alignments
+                    .get(i)
+                    .copied()
+                    .flatten()
+                    .unwrap_or(Alignment::Left)
+            })
+            .collect();
+        self.format_table(&header, &alignments, &rows)
     }
 
-    fn format_list(&self, items: &[(usize, ListItemType, String)]) -> String {
-        let mut output = String::new();
-
-        for (indent_level, item_type, item) in items {
-            let trimmed = item.trim_start();
+    /// Alignment set on an HTML table cell by `align` or a `text-align` style
+    fn html_cell_alignment(&self, attrs: &str) -> Option<Alignment> {
+        let style = self.extract_attr(attrs, "style").unwrap_or_default();
+        let value = match style.to_ascii_lowercase().split_once("text-align") {
+            Some((_, rest)) => rest.trim_start_matches([' ', ':']).to_string(),
+            None => self.extract_attr(attrs, "align")?.to_ascii_lowercase(),
+        };
+        if value.starts_with("center") {
+            Some(Alignment::Center)
+        } else if value.starts_with("right") {
+            Some(Alignment::Right)
+        } else if value.starts_with("left") {
+            Some(Alignment::Left)
+        } else {
+            None
+        }
+    }
 
-            // Extract the content after the marker
-            let content = if let Some(rest) = trimmed.strip_prefix("- ") {
-                rest
-            } else if let Some(rest) = trimmed.strip_prefix("+ ") {
-                rest
-            } else if let Some(rest) = trimmed.strip_prefix("* ") {
-                rest
-            } else if let Some(dot_pos) = trimmed.find(". ") {
-                &trimmed[dot_pos + 2..]
+    /// Convert the content of an HTML table cell to a line of markdown, so it
+    /// is measured and formatted like a markdown table cell. Images show their
+    /// alt text, `<br>` becomes a space and unknown tags are dropped.
+    fn html_cell_text(&self, html: &str) -> String {
+        let lower = html.to_ascii_lowercase();
+        let mut text = String::new();
+        let mut link_urls: Vec<Option<String>> = Vec::new();
+        let mut in_code = false;
+        let mut pos = 0;
+
+        while pos < html.len() {
+            let next_tag = lower[pos..].find('<').map_or(html.len(), |e| pos + e);
+            let segment = decode_html_entities(&html[pos..next_tag]);
+            if in_code {
+                text.push_str(&segment);
             } else {
-                trimmed
-            };
-
-            // Use indentation level to determine nesting (each 4 spaces = 1 level)
-            let nesting_level = indent_level / 4;
-            let indent = "  ".repeat(nesting_level);
-
-            // Check for task list item marker and handle specially
-            if let Some((is_checked, remaining)) = self.parse_task_list_marker(content) {
-                // Task list item: use checkbox as the marker (no bullet)
-                let checkbox = if is_checked { "☑" } else { "☐" };
-                let formatted_content = self.format_inline(remaining);
-                let first_indent = format!("{}  {} ", indent, checkbox);
-                let cont_indent = format!("{}    ", indent); // align with content after checkbox
-                let wrapped = self.wrap_text(&formatted_content, &first_indent, &cont_indent);
-                output.push_str(&wrapped);
-                output.push('\n');
-                continue;
+                text.push_str(&escape_inline_markdown(&segment));
+            }
+            if next_tag == html.len() {
+                break;
             }
 
-            let formatted_content = self.format_inline(content);
+            let Some(tag_len) = lower[next_tag..].find('>') else {
+                text.push_str(&escape_inline_markdown(&html[next_tag..]));
+                break;
+            };
+            let tag = &html[next_tag + 1..next_tag + tag_len];
+            pos = next_tag + tag_len + 1;
+            let closing = tag.starts_with('/');
+            let name: String = tag
+                .trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
 
-            // Format based on item type, with wrapping
-            match item_type {
-                ListItemType::Unordered => {
-                    let first_indent = format!("{}  • ", indent);
-                    let cont_indent = format!("{}    ", indent); // align with content after bullet
-                    let wrapped = self.wrap_text(&formatted_content, &first_indent, &cont_indent);
-                    output.push_str(&wrapped);
-                    output.push('\n');
+            match (name.as_str(), closing) {
+                ("img", _) => {
+                    let alt = self.extract_attr(tag, "alt").unwrap_or_default();
+                    text.push_str(&escape_inline_markdown(&alt));
                 }
-                ListItemType::Ordered(num) => {
-                    // Use the original number from the markdown
-                    let first_indent = format!("{}  {}. ", indent, num);
-                    // Continuation indent aligns with content (after "N. ")
-                    let cont_indent =
-                        format!("{}  {}  ", indent, " ".repeat(num.to_string().len()));
-                    let wrapped = self.wrap_text(&formatted_content, &first_indent, &cont_indent);
-                    output.push_str(&wrapped);
-                    output.push('\n');
+                ("br", _) => text.push(' '),
+                ("b" | "strong", _) => text.push_str("**"),
+                ("i" | "em", _) => text.push('*'),
+                ("s" | "del" | "strike", _) => text.push_str("~~"),
+                ("code", _) => {
+                    in_code = !closing;
+                    text.push('`');
+                }
+                ("a", false) => {
+                    let href = self.extract_href(tag);
+                    if href.is_some() {
+                        text.push('[');
+                    }
+                    link_urls.push(href);
+                }
+                ("a", true) => {
+                    if let Some(Some(url)) = link_urls.pop() {
+                        text.push_str(&format!("]({})", url));
+                    }
                 }
+                _ => {}
             }
         }
-        // Add blank line after list for spacing
-        output.push('\n');
-        output
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
     }
 
-    // Table parsing and formatting functions
-
-    fn is_table_delimiter_row(&self, line: &str) -> bool {
-        // Must contain pipes
-        if !line.contains('|') {
-            return false;
+    /// The `<img>` to show for a `<picture>` element: its fallback image, or
+    /// the first `<source>` when there is none
+    fn picture_image(&self, html: &str) -> String {
+        let lower = html.to_ascii_lowercase();
+        if let Some(start) = lower.find("<img") {
+            let end = lower[start..]
+                .find('>')
+                .map_or(html.len(), |e| start + e + 1);
+            return html[start..end].to_string();
+        }
+        for (start, _) in lower.match_indices("<source") {
+            let end = lower[start..].find('>').map_or(html.len(), |e| start + e);
+            let srcset = self.extract_attr(&html[start + 1..end], "srcset");
+            if let Some(src) = srcset.as_deref().and_then(|s| s.split_whitespace().next()) {
+                return format!("<img src=\"{}\">", src);
+            }
         }
+        String::new()
+    }
 
-        // Split by pipes, check each cell
-        let cells: Vec<&str> = line
-            .split('|')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .collect();
+    /// Convert a theme name string to an EmbeddedThemeName enum variant
+    fn theme_name_to_enum(name: &str) -> Option<EmbeddedThemeName> {
+        // Find matching theme by comparing string names
+        EmbeddedLazyThemeSet::theme_names()
+            .iter()
+            .find(|theme| theme.as_name().eq_ignore_ascii_case(name))
+            .copied()
+    }
 
-        if cells.is_empty() {
-            return false;
+    /// Parse a code fence info string into a language plus optional attributes.
+    /// Supports `title="..."`/`filename=...`, highlighted line ranges in braces
+    /// (`{3,5-7}`, also attached as `rust{3}`), `hl_lines="3 5-7"`, and the
+    /// `linenos`/`showLineNumbers` flags.
+    fn parse_code_info(&self, info: &str) -> CodeBlockInfo {
+        let mut result = CodeBlockInfo::default();
+
+        // Split into whitespace-separated tokens, keeping quoted values together
+        let mut tokens: Vec<String> = Vec::new();
+        let mut current = String::new();
+        let mut quote: Option<char> = None;
+        for ch in info.chars() {
+            match quote {
+                Some(q) if ch == q => quote = None,
+                Some(_) => current.push(ch),
+                None if ch == '"' || ch == '\'' => quote = Some(ch),
+                None if ch.is_whitespace() => {
+                    if !current.is_empty() {
+                        tokens.push(std::mem::take(&mut current));
+                    }
+                }
+                None => current.push(ch),
+            }
+        }
+        if !current.is_empty() {
+            tokens.push(current);
         }
 
-        // Each cell must match pattern: optional :, at least 3 dashes, optional :
-        for cell in cells {
-            let chars: Vec<char> = cell.chars().collect();
-            if chars.is_empty() {
-                return false;
+        for (i, token) in tokens.iter().enumerate() {
+            // Brace ranges, either standalone or attached to the language
+            let (word, ranges) = match token.find('{') {
+                Some(pos) if token.ends_with('}') => {
+                    (&token[..pos], Some(&token[pos + 1..token.len() - 1]))
+                }
+                _ => (token.as_str(), None),
+            };
+            if let Some(ranges) = ranges {
+                result.highlight.extend(Self::parse_line_ranges(ranges));
             }
 
-            let starts_colon = chars[0] == ':';
-            let ends_colon = chars[chars.len() - 1] == ':';
-
-            let dash_section = if starts_colon && ends_colon {
-                &chars[1..chars.len() - 1]
-            } else if starts_colon {
-                &chars[1..]
-            } else if ends_colon {
-                &chars[..chars.len() - 1]
-            } else {
-                &chars[..]
-            };
+            if let Some((key, value)) = word.split_once('=') {
+                match key.to_lowercase().as_str() {
+                    "title" | "filename" => result.title = Some(value.to_string()),
+                    "hl_lines" => result.highlight.extend(Self::parse_line_ranges(value)),
+                    "header" => {
+                        result.no_header = matches!(value.to_lowercase().as_str(), "false" | "no")
+                    }
+                    _ => {}
+                }
+                continue;
+            }
 
-            // Must have at least 3 dashes
-            if dash_section.len() < 3 || !dash_section.iter().all(|&c| c == '-') {
-                return false;
+            match word {
+                "" => {}
+                "linenos" | "showLineNumbers" | "line-numbers" => result.line_numbers = true,
+                "noheader" => result.no_header = true,
+                // Only the first token names the language
+                _ if i == 0 => result.language = word.to_string(),
+                _ => {}
             }
         }
 
-        true
+        result
     }
 
-    fn parse_table_row(&self, line: &str) -> Vec<String> {
-        let mut cells = Vec::new();
-        let mut current_cell = String::new();
-        let mut escaped = false;
+    /// Parse line ranges like `3,5-7` or `3 5-7` into inclusive (start, end) pairs
+    fn parse_line_ranges(spec: &str) -> Vec<(usize, usize)> {
+        spec.split(|c: char| c == ',' || c.is_whitespace())
+            .filter_map(|part| {
+                let part = part.trim();
+                if let Some((start, end)) = part.split_once('-') {
+                    let start = start.trim().parse().ok()?;
+                    let end = end.trim().parse().ok()?;
+                    Some((start, end))
+                } else {
+                    let line = part.parse().ok()?;
+                    Some((line, line))
+                }
+            })
+            .collect()
+    }
 
-        for ch in line.chars() {
-            if escaped {
-                current_cell.push(ch);
-                escaped = false;
-                continue;
+    /// Background escape for the theme's background color
+    fn theme_background_escape(theme: &syntect::highlighting::Theme) -> String {
+        match theme.settings.background {
+            Some(bg) => format!("\u{001b}[48;2;{};{};{}m", bg.r, bg.g, bg.b),
+            None => "\u{001b}[48;5;235m".to_string(),
+        }
+    }
+
+    /// Top border of a framed code block, with the label set into it:
+    /// `╭─ rust ─────╮`
+    fn frame_top_border(label: &str, width: usize) -> String {
+        let inner = width.saturating_sub(2);
+        if label.is_empty() {
+            return format!("\u{001b}[2;39m╭{}╮\u{001b}[22m\n", "─".repeat(inner));
+        }
+        let label_width = label.width() + 2;
+        let rest = inner.saturating_sub(1 + label_width);
+        format!(
+            "\u{001b}[2;39m╭─\u{001b}[22m \u{001b}[1m{}\u{001b}[22m \u{001b}[2m{}╮\u{001b}[22m\n",
+            label,
+            "─".repeat(rest)
+        )
+    }
+
+    /// Background escape for highlighted code lines, taken from the theme's
+    /// line highlight color (blended over its background when translucent)
+    fn line_highlight_escape(theme: &syntect::highlighting::Theme) -> String {
+        let settings = &theme.settings;
+        match (settings.line_highlight, settings.background) {
+            (Some(hl), Some(bg)) if hl.a < 255 => {
+                let alpha = hl.a as u32;
+                let blend = |fg: u8, bg: u8| -> u32 {
+                    (fg as u32 * alpha + bg as u32 * (255 - alpha)) / 255
+                };
+                format!(
+                    "\u{001b}[48;2;{};{};{}m",
+                    blend(hl.r, bg.r),
+                    blend(hl.g, bg.g),
+                    blend(hl.b, bg.b)
+                )
             }
+            (Some(hl), _) => format!("\u{001b}[48;2;{};{};{}m", hl.r, hl.g, hl.b),
+            (None, _) => "\u{001b}[48;5;237m".to_string(),
+        }
+    }
 
-            if ch == '\\' {
-                escaped = true;
+    /// Split ANSI-formatted text into pieces no wider than `max_width` columns.
+    /// Each continuation piece is prefixed with the last SGR sequence seen so
+    /// syntax colors carry across the break.
+    fn split_ansi_by_width(&self, text: &str, max_width: usize) -> Vec<String> {
+        let mut pieces = Vec::new();
+        let mut current = String::new();
+        let mut current_width = 0;
+        let mut last_sgr = String::new();
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '[' {
+                // SGR sequence: copy verbatim and remember it
+                let seq_start = i;
+                i += 2;
+                while i < chars.len() && chars[i] != 'm' {
+                    i += 1;
+                }
+                i = (i + 1).min(chars.len());
+                let seq: String = chars[seq_start..i].iter().collect();
+                current.push_str(&seq);
+                last_sgr = seq;
+                continue;
+            }
+            if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == ']' {
+                // OSC sequence (e.g. OSC8 hyperlink): zero width, copy up to ST or BEL
+                let seq_start = i;
+                i += 2;
+                while i < chars.len() {
+                    if chars[i] == '\x07' {
+                        i += 1;
+                        break;
+                    }
+                    if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\' {
+                        i += 2;
+                        break;
+                    }
+                    i += 1;
+                }
+                current.extend(&chars[seq_start..i]);
                 continue;
             }
 
-            if ch == '|' {
-                cells.push(current_cell.trim().to_string());
-                current_cell.clear();
-            } else {
-                current_cell.push(ch);
+            let ch_width = chars[i].width().unwrap_or(0);
+            if current_width > 0 && current_width + ch_width > max_width {
+                pieces.push(std::mem::take(&mut current));
+                current.push_str(&last_sgr);
+                current_width = 0;
             }
+            current.push(chars[i]);
+            current_width += ch_width;
+            i += 1;
         }
 
-        // Add last cell if not empty
-        if !current_cell.trim().is_empty() {
-            cells.push(current_cell.trim().to_string());
+        pieces.push(current);
+        pieces
+    }
+
+    /// Map a code fence language through the alias table, case-insensitively
+    fn resolve_language_alias<'a>(&'a self, language: &'a str) -> &'a str {
+        self.language_aliases
+            .get(&language.to_lowercase())
+            .map(|s| s.as_str())
+            .unwrap_or(language)
+    }
+
+    /// Find the syntax for a code block. Tries, in order: the language as a
+    /// syntect token, the language as a file name or path (`path/to/file.rs`,
+    /// `Dockerfile`), the block's title as a file name, and finally the first
+    /// line of code (shebangs like `#!/usr/bin/env python`).
+    fn find_code_syntax(
+        &self,
+        language: &str,
+        title: Option<&str>,
+        first_line: Option<&str>,
+    ) -> &syntect::parsing::SyntaxReference {
+        let by_file_name = |path: &str| {
+            let file_name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+            std::path::Path::new(file_name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| self.syntax_set.find_syntax_by_extension(ext))
+                .or_else(|| self.syntax_set.find_syntax_by_extension(file_name))
+                .or_else(|| self.syntax_set.find_syntax_by_token(file_name))
+        };
+
+        let from_language = if language.is_empty() {
+            None
+        } else {
+            self.syntax_set
+                .find_syntax_by_token(language)
+                .or_else(|| by_file_name(language))
+        };
+
+        from_language
+            .or_else(|| title.and_then(by_file_name))
+            .or_else(|| {
+                if language.is_empty() {
+                    first_line.and_then(|line| self.syntax_set.find_syntax_by_first_line(line))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text())
+    }
+
+    fn format_code_block(&self, lines: &[String], info: &str) -> String {
+        let mut output = String::new();
+        let info = self.parse_code_info(info);
+
+        // Map aliases to their syntect language names
+        let language = self.resolve_language_alias(&info.language);
+
+        // Get theme from two-face's embedded themes, with fallback
+        let theme = Self::theme_name_to_enum(&self.theme_name)
+            .map(|name| self.theme_set.get(name))
+            .unwrap_or_else(|| self.theme_set.get(EmbeddedThemeName::Base16OceanDark));
+
+        // Each row is the highlighted line plus an optional full-row background
+        let is_session = language.eq_ignore_ascii_case("console");
+        let rows: Vec<(String, Option<String>)> =
+            if matches!(language.to_lowercase().as_str(), "diff" | "patch") {
+                self.highlight_diff_lines(lines, theme)
+            } else if is_session {
+                self.highlight_session_lines(lines, theme)
+            } else {
+                let syntax = self.find_code_syntax(
+                    language,
+                    info.title.as_deref(),
+                    lines.first().map(|l| l.as_str()),
+                );
+                let mut highlighter = HighlightLines::new(syntax, theme);
+
+                lines
+                    .iter()
+                    .map(|line| {
+                        // Add newline for proper syntax highlighting state management
+                        let line_with_newline = format!("{}\n", line);
+                        let ranges = highlighter
+                            .highlight_line(&line_with_newline, &self.syntax_set)
+                            .unwrap_or_default();
+                        let highlighted = as_24_bit_terminal_escaped(&ranges[..], false);
+                        // Remove the trailing newline from highlighted output
+                        (highlighted.trim_end_matches('\n').to_string(), None)
+                    })
+                    .collect()
+            };
+
+        let frame = self.code_block_style == CodeBlockStyle::Frame;
+        let block_bg = (self.code_block_style == CodeBlockStyle::Background)
+            .then(|| Self::theme_background_escape(theme));
+
+        // Filename header above the code (framed blocks show it in the border)
+        if let (Some(title), false) = (&info.title, frame) {
+            output.push_str(&format!("    \u{001b}[1m{}\u{001b}[0m\n", title));
+            output.push_str(&format!(
+                "    \u{001b}[2m{}\u{001b}[0m\n",
+                "─".repeat(title.width())
+            ));
         }
 
-        // Filter out empty leading/trailing cells (from leading/trailing pipes)
-        if !cells.is_empty() && cells[0].is_empty() {
-            cells.remove(0);
+        // Gutter: 4 space indent, or right-aligned line numbers when enabled.
+        // Continuation rows of wrapped lines get a ↪ marker in the gutter.
+        let show_line_numbers = self.line_numbers || info.line_numbers;
+        let number_width = lines.len().to_string().len();
+        let cont_prefix = if show_line_numbers {
+            format!("\u{001b}[2;39m{:>w$} │\u{001b}[22m ", "↪", w = number_width)
+        } else {
+            "\u{001b}[2;39m  ↪\u{001b}[22m ".to_string()
+        };
+        let gutter_width = self.display_width(&cont_prefix);
+        // Frames take "│ " on the left and " │" on the right
+        let frame_width = if frame { 4 } else { 0 };
+        let content_width = self
+            .width
+            .saturating_sub(gutter_width + frame_width)
+            .max(10);
+        let block_width = gutter_width + content_width + frame_width;
+        let highlight_bg = Self::line_highlight_escape(theme);
+
+        if frame {
+            let label = info.title.as_deref().unwrap_or(&info.language);
+            output.push_str(&Self::frame_top_border(label, block_width));
+        } else if let Some(bg) = &block_bg {
+            // Blank padding row above the code
+            output.push_str(&format!("{}{}\u{001b}[49m\n", bg, " ".repeat(block_width)));
+        }
+
+        for (idx, (highlighted, row_bg)) in rows.iter().enumerate() {
+            let line_number = idx + 1;
+            let first_prefix = if show_line_numbers {
+                format!(
+                    "\u{001b}[2;39m{:>w$} │\u{001b}[22m ",
+                    line_number,
+                    w = number_width
+                )
+            } else {
+                "    ".to_string()
+            };
+            let background = if info.is_highlighted(line_number) {
+                Some(&highlight_bg)
+            } else {
+                row_bg.as_ref().or(block_bg.as_ref())
+            };
+
+            for (piece_idx, piece) in self
+                .split_ansi_by_width(highlighted, content_width)
+                .iter()
+                .enumerate()
+            {
+                if piece_idx > 0 {
+                    output.push('\n');
+                }
+                if frame {
+                    output.push_str("\u{001b}[2;39m│\u{001b}[22m ");
+                }
+                if let Some(bg) = &block_bg {
+                    output.push_str(bg);
+                }
+                if piece_idx > 0 {
+                    output.push_str(&cont_prefix);
+                } else {
+                    output.push_str(&first_prefix);
+                }
+                let padding = content_width.saturating_sub(self.display_width(piece));
+                if let Some(bg) = background {
+                    // Tint the whole row, padding out to the full width
+                    output.push_str(bg);
+                    output.push_str(piece);
+                    output.push_str(bg);
+                    output.push_str(&" ".repeat(padding));
+                    output.push_str("\u{001b}[49m");
+                } else {
+                    output.push_str(piece);
+                    if frame {
+                        output.push_str(&" ".repeat(padding));
+                    }
+                }
+                if frame {
+                    output.push_str(" \u{001b}[2;39m│\u{001b}[22m");
+                }
+            }
+            output.push('\n');
         }
-        if !cells.is_empty() && cells[cells.len() - 1].is_empty() {
-            cells.pop();
+
+        if frame {
+            output.push_str(&format!(
+                "\u{001b}[2;39m╰{}╯\u{001b}[22m\n",
+                "─".repeat(block_width.saturating_sub(2))
+            ));
+        } else if let Some(bg) = &block_bg {
+            // Blank padding row below the code
+            output.push_str(&format!("{}{}\u{001b}[49m\n", bg, " ".repeat(block_width)));
+        }
+
+        // Put the session's commands on the clipboard
+        if is_session && self.command_copy == CommandCopy::Osc52 {
+            let commands = Self::session_commands(lines);
+            if !commands.is_empty() {
+                use base64::{engine::general_purpose::STANDARD, Engine as _};
+                let encoded = STANDARD.encode(commands.join("\n"));
+                output.push_str(&format!("\u{001b}]52;c;{}\u{0007}", encoded));
+            }
         }
 
-        cells
+        // Reset ANSI codes to prevent color bleeding
+        output.push_str("\u{001b}[0m");
+
+        // Add blank line after code block for spacing
+        output.push('\n');
+        output
     }
 
-    fn parse_alignments(&self, delimiter_row: &str) -> Vec<Alignment> {
-        let cells = self.parse_table_row(delimiter_row);
+    /// Split a shell session line into (prompt, command) if it starts with a
+    /// prompt: `$ `, `# `, `% `, `> ` or `❯ ` at the start of the line (after
+    /// indentation), optionally preceded by a `user@host:~/dir` or
+    /// `[user@host dir]` prompt prefix.
+    fn split_session_prompt(line: &str) -> Option<(&str, &str)> {
+        const MARKERS: [&str; 5] = ["$", "#", "%", ">", "❯"];
+        let indent = line.len() - line.trim_start().len();
+        let rest = &line[indent..];
+
+        let prefix_len = if rest.starts_with('[') {
+            rest.find(']').map_or(0, |end| end + 1)
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || MARKERS.iter().any(|m| m.starts_with(c)))
+                .unwrap_or(rest.len());
+            if Self::is_user_host_prefix(&rest[..end]) {
+                end
+            } else {
+                0
+            }
+        };
 
-        cells
-            .iter()
-            .map(|cell| {
-                let trimmed = cell.trim();
-                let starts_colon = trimmed.starts_with(':');
-                let ends_colon = trimmed.ends_with(':');
+        let after_prefix = &rest[prefix_len..];
+        let marker = MARKERS.iter().find(|m| after_prefix.starts_with(*m))?;
+        let after_marker = &after_prefix[marker.len()..];
+        let prompt_end = indent + prefix_len + marker.len();
+        if after_marker.is_empty() {
+            Some((line, ""))
+        } else if after_marker.starts_with(' ') {
+            Some((&line[..prompt_end + 1], &line[prompt_end + 1..]))
+        } else {
+            None
+        }
+    }
 
-                match (starts_colon, ends_colon) {
-                    (true, true) => Alignment::Center,
-                    (false, true) => Alignment::Right,
-                    _ => Alignment::Left,
+    /// Whether `prefix` looks like a `user@host` or `user@host:path` prompt
+    fn is_user_host_prefix(prefix: &str) -> bool {
+        let host_part = prefix.split(':').next().unwrap_or("");
+        match host_part.split_once('@') {
+            Some((user, host)) => {
+                let valid = |s: &str| {
+                    !s.is_empty()
+                        && s.chars()
+                            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                };
+                valid(user) && valid(host)
+            }
+            None => false,
+        }
+    }
+
+    /// Extract the commands from a shell session, joining lines continued
+    /// with a trailing backslash
+    fn session_commands(lines: &[String]) -> Vec<String> {
+        let mut commands: Vec<String> = Vec::new();
+        let mut continuing = false;
+
+        for line in lines {
+            if continuing {
+                if let Some(command) = commands.last_mut() {
+                    command.push('\n');
+                    command.push_str(line);
                 }
-            })
-            .collect()
+            } else if let Some((_, command)) = Self::split_session_prompt(line) {
+                commands.push(command.to_string());
+            } else {
+                continue;
+            }
+            continuing = line.ends_with('\\');
+        }
+
+        commands.retain(|command| !command.trim().is_empty());
+        commands
     }
 
-    /// Strip ANSI escape sequences from text for width calculation.
-    /// Handles both SGR sequences (\x1b[...m) and OSC8 hyperlinks (\x1b]8;;...\x1b\\).
-    pub fn strip_ansi(&self, text: &str) -> String {
-        // Strip ANSI escape sequences for width calculation
-        // Handles both SGR sequences (\x1b[...m) and OSC8 hyperlinks (\x1b]8;;...\x1b\\)
-        let mut result = String::new();
-        let chars: Vec<char> = text.chars().collect();
-        let mut i = 0;
+    /// Highlight a `console`/`shell-session` block: prompts are dimmed,
+    /// commands are highlighted as bash and output lines are left plain.
+    fn highlight_session_lines(
+        &self,
+        lines: &[String],
+        theme: &syntect::highlighting::Theme,
+    ) -> Vec<(String, Option<String>)> {
+        let syntax = self
+            .syntax_set
+            .find_syntax_by_token("bash")
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let mut highlighter = HighlightLines::new(syntax, theme);
+        let mut highlight_command = |command: &str| -> String {
+            let line_with_newline = format!("{}\n", command);
+            let ranges = highlighter
+                .highlight_line(&line_with_newline, &self.syntax_set)
+                .unwrap_or_default();
+            let highlighted = as_24_bit_terminal_escaped(&ranges[..], false);
+            highlighted.trim_end_matches('\n').to_string()
+        };
 
-        while i < chars.len() {
-            if chars[i] == '\x1b' {
-                if i + 1 < chars.len() {
-                    match chars[i + 1] {
-                        '[' => {
-                            // SGR sequence: \x1b[...m - skip until 'm'
-                            i += 2;
-                            while i < chars.len() && chars[i] != 'm' {
-                                i += 1;
-                            }
-                            if i < chars.len() {
-                                i += 1; // skip the 'm'
-                            }
-                        }
-                        ']' => {
-                            // OSC sequence: \x1b]...ST where ST is \x1b\\ or BEL
-                            // Used for OSC8 hyperlinks: \x1b]8;;URL\x1b\\
-                            i += 2;
-                            while i < chars.len() {
-                                if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\'
-                                {
-                                    i += 2; // skip \x1b\\
-                                    break;
-                                } else if chars[i] == '\x07' {
-                                    // BEL is also a valid string terminator
-                                    i += 1;
-                                    break;
-                                }
-                                i += 1;
-                            }
+        let commands = Self::session_commands(lines);
+        let mut next_command = 0;
+        let mut continuing = false;
+        let mut rows = Vec::with_capacity(lines.len());
+
+        for line in lines {
+            let (prompt, command) = if continuing {
+                ("", line.as_str())
+            } else if let Some((prompt, command)) = Self::split_session_prompt(line) {
+                (prompt, command)
+            } else {
+                // Output line: default foreground, no highlighting
+                rows.push((format!("\u{001b}[39m{}", line), None));
+                continue;
+            };
+
+            let mut row = String::new();
+            if !prompt.is_empty() {
+                row.push_str(&format!("\u{001b}[2m{}\u{001b}[22m", prompt));
+            }
+            let highlighted = highlight_command(command);
+            let starts_command = !continuing && !command.trim().is_empty();
+            match (self.command_copy, starts_command) {
+                (CommandCopy::Link, true) if next_command < commands.len() => {
+                    // Link the first line of each command to its full text
+                    let target = format!(
+                        "data:text/plain;charset=utf-8,{}",
+                        percent_encode(&commands[next_command])
+                    );
+                    row.push_str(&format!(
+                        "\u{001b}]8;;{}\u{001b}\\{}\u{001b}]8;;\u{001b}\\",
+                        target, highlighted
+                    ));
+                }
+                _ => row.push_str(&highlighted),
+            }
+            if starts_command {
+                next_command += 1;
+            }
+            continuing = line.ends_with('\\');
+            rows.push((row, None));
+        }
+
+        rows
+    }
+
+    /// Highlight the lines of a unified diff. Added and removed lines get a
+    /// green/red row background, hunk and file headers are styled separately,
+    /// and when a file header names a file with a known extension the code
+    /// itself is syntax highlighted in that language. Runs of removed lines
+    /// followed by the same number of added lines get word-level emphasis.
+    fn highlight_diff_lines(
+        &self,
+        lines: &[String],
+        theme: &syntect::highlighting::Theme,
+    ) -> Vec<(String, Option<String>)> {
+        let removed_bg = "\u{001b}[48;2;72;24;28m";
+        let removed_emphasis = "\u{001b}[48;2;130;40;46m";
+        let added_bg = "\u{001b}[48;2;24;60;32m";
+        let added_emphasis = "\u{001b}[48;2;40;110;55m";
+
+        let kinds = Self::classify_diff_lines(lines);
+        let emphasis = Self::diff_word_emphasis(lines, &kinds);
+
+        let plain = self.syntax_set.find_syntax_plain_text();
+        let mut highlighter = HighlightLines::new(plain, theme);
+        let mut rows = Vec::with_capacity(lines.len());
+
+        for (idx, line) in lines.iter().enumerate() {
+            let row = match kinds[idx] {
+                DiffLineKind::FileHeader => {
+                    // Switch languages when a header reveals the file name
+                    if let Some(syntax) = self.diff_header_syntax(line) {
+                        highlighter = HighlightLines::new(syntax, theme);
+                    }
+                    (format!("\u{001b}[1m{}\u{001b}[22m", line), None)
+                }
+                DiffLineKind::Hunk => (format!("\u{001b}[36m{}\u{001b}[39m", line), None),
+                DiffLineKind::Meta => (format!("\u{001b}[2m{}\u{001b}[22m", line), None),
+                kind => {
+                    let (marker, content) = line.split_at(line.len().min(1));
+                    let line_with_newline = format!("{}\n", content);
+                    let ranges = highlighter
+                        .highlight_line(&line_with_newline, &self.syntax_set)
+                        .unwrap_or_default();
+                    let (bg, emphasis_bg, marker_color) = match kind {
+                        DiffLineKind::Added => (Some(added_bg), added_emphasis, "\u{001b}[32m"),
+                        DiffLineKind::Removed => {
+                            (Some(removed_bg), removed_emphasis, "\u{001b}[31m")
                         }
-                        '_' => {
-                            // APC sequence: \x1b_...ST where ST is \x1b\\
-                            // Used for Kitty graphics protocol: \x1b_Gf=100,...;base64data\x1b\\
-                            i += 2;
-                            while i < chars.len() {
-                                if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\'
-                                {
-                                    i += 2; // skip \x1b\\
-                                    break;
-                                }
-                                i += 1;
+                        _ => (None, "", "\u{001b}[2m"),
+                    };
+
+                    let mut rendered = format!("{}{}\u{001b}[0m", marker_color, marker);
+                    if let Some(bg) = bg {
+                        rendered.push_str(bg);
+                    }
+                    let mut offset = 0;
+                    let mut in_emphasis = false;
+                    for (style, text) in ranges {
+                        let text = text.trim_end_matches('\n');
+                        rendered.push_str(&format!(
+                            "\u{001b}[38;2;{};{};{}m",
+                            style.foreground.r, style.foreground.g, style.foreground.b
+                        ));
+                        for ch in text.chars() {
+                            let emphasized = emphasis[idx]
+                                .iter()
+                                .any(|&(start, end)| (start..end).contains(&offset));
+                            if emphasized != in_emphasis {
+                                rendered.push_str(if emphasized {
+                                    emphasis_bg
+                                } else {
+                                    bg.unwrap_or("")
+                                });
+                                in_emphasis = emphasized;
                             }
-                        }
-                        _ => {
-                            // Unknown escape sequence, skip the ESC and next char
-                            i += 2;
+                            rendered.push(ch);
+                            offset += ch.len_utf8();
                         }
                     }
-                } else {
-                    i += 1;
+                    (rendered, bg.map(str::to_string))
                 }
-            } else {
-                result.push(chars[i]);
-                i += 1;
-            }
+            };
+            rows.push(row);
         }
 
-        result
+        rows
     }
 
-    /// Calculate the display width of text after stripping ANSI codes.
-    /// Uses Unicode width to properly handle wide characters (like emoji and CJK).
-    pub fn display_width(&self, text: &str) -> usize {
-        self.strip_ansi(text).width()
+    /// Find the syntax for the file named in a diff file header such as
+    /// `+++ b/src/main.rs` or `diff --git a/x.py b/x.py`
+    fn diff_header_syntax(&self, line: &str) -> Option<&syntect::parsing::SyntaxReference> {
+        let path = if let Some(rest) = line.strip_prefix("+++ ") {
+            rest
+        } else if let Some(rest) = line.strip_prefix("diff --git ") {
+            rest.split_whitespace().last()?
+        } else {
+            return None;
+        };
+        let path = path.split('\t').next()?.trim();
+        let path = path.strip_prefix("b/").unwrap_or(path);
+        if path == "/dev/null" {
+            return None;
+        }
+
+        let file_name = std::path::Path::new(path).file_name()?.to_str()?;
+        let extension = std::path::Path::new(file_name)
+            .extension()
+            .and_then(|e| e.to_str());
+        extension
+            .and_then(|ext| self.syntax_set.find_syntax_by_extension(ext))
+            .or_else(|| self.syntax_set.find_syntax_by_extension(file_name))
     }
 
-    /// Wrap text to self.width, preserving ANSI codes and not breaking words.
-    /// `first_indent` is prepended to the first line, `cont_indent` to continuation lines.
-    /// Long words that exceed width are kept whole on their own line.
-    pub fn wrap_text(&self, text: &str, first_indent: &str, cont_indent: &str) -> String {
-        let first_indent_width = first_indent.width();
-        let cont_indent_width = cont_indent.width();
+    /// Classify each line of a unified diff. Hunk line counts from `@@` headers
+    /// are tracked so that `--- `/`+++ ` inside a hunk are treated as content.
+    fn classify_diff_lines(lines: &[String]) -> Vec<DiffLineKind> {
+        let mut kinds = Vec::with_capacity(lines.len());
+        // Remaining (old, new) line counts of the current hunk
+        let mut remaining: Option<(usize, usize)> = None;
 
-        // Split text into "tokens" preserving ANSI codes with adjacent words
-        // We need to split on whitespace while preserving the ANSI codes
+        for line in lines {
+            let in_hunk = remaining.is_some_and(|(old, new)| old > 0 || new > 0);
+            let kind = if line.starts_with("@@") {
+                remaining = Self::parse_hunk_counts(line);
+                DiffLineKind::Hunk
+            } else if !in_hunk
+                && (line.starts_with("diff ")
+                    || line.starts_with("index ")
+                    || line.starts_with("--- ")
+                    || line.starts_with("+++ "))
+            {
+                DiffLineKind::FileHeader
+            } else if line.starts_with('+') {
+                DiffLineKind::Added
+            } else if line.starts_with('-') {
+                DiffLineKind::Removed
+            } else if line.starts_with(' ') || (in_hunk && line.is_empty()) {
+                DiffLineKind::Context
+            } else {
+                DiffLineKind::Meta
+            };
+
+            if let Some((old, new)) = remaining.as_mut() {
+                match kind {
+                    DiffLineKind::Added => *new = new.saturating_sub(1),
+                    DiffLineKind::Removed => *old = old.saturating_sub(1),
+                    DiffLineKind::Context => {
+                        *old = old.saturating_sub(1);
+                        *new = new.saturating_sub(1);
+                    }
+                    _ => {}
+                }
+            }
+            kinds.push(kind);
+        }
+
+        kinds
+    }
+
+    /// Parse the old/new line counts from a hunk header like `@@ -1,4 +1,5 @@`
+    fn parse_hunk_counts(line: &str) -> Option<(usize, usize)> {
+        let mut parts = line.split_whitespace().skip(1);
+        let count = |range: &str| -> Option<usize> {
+            match range[1..].split_once(',') {
+                Some((_, count)) => count.parse().ok(),
+                None => Some(1),
+            }
+        };
+        let old = parts.next().filter(|p| p.starts_with('-'))?;
+        let new = parts.next().filter(|p| p.starts_with('+'))?;
+        Some((count(old)?, count(new)?))
+    }
+
+    /// Compute byte ranges (within the line content, after the +/- marker)
+    /// to emphasize for word-level diffs. A run of N removed lines directly
+    /// followed by N added lines is paired up line by line.
+    fn diff_word_emphasis(lines: &[String], kinds: &[DiffLineKind]) -> Vec<ByteRanges> {
+        let mut emphasis = vec![Vec::new(); lines.len()];
+        let mut i = 0;
+
+        while i < lines.len() {
+            if kinds[i] != DiffLineKind::Removed {
+                i += 1;
+                continue;
+            }
+            let removed_start = i;
+            while i < lines.len() && kinds[i] == DiffLineKind::Removed {
+                i += 1;
+            }
+            let added_start = i;
+            while i < lines.len() && kinds[i] == DiffLineKind::Added {
+                i += 1;
+            }
+            let count = added_start - removed_start;
+            if i - added_start != count {
+                continue;
+            }
+            for n in 0..count {
+                let old = &lines[removed_start + n][1..];
+                let new = &lines[added_start + n][1..];
+                let (old_ranges, new_ranges) = word_diff_ranges(old, new);
+                emphasis[removed_start + n] = old_ranges;
+                emphasis[added_start + n] = new_ranges;
+            }
+        }
+
+        emphasis
+    }
+
+    /// Parse a task list item marker at the start of content.
+    /// Returns Some((is_checked, remaining_content)) if a task list marker is found.
+    /// Task list markers are: [ ] (unchecked) or [x]/[X] (checked), followed by whitespace.
+    fn parse_task_list_marker<'a>(&self, content: &'a str) -> Option<(bool, &'a str)> {
+        let trimmed = content.trim_start();
+
+        // Check for [ ] (unchecked)
+        if let Some(rest) = trimmed.strip_prefix("[ ]") {
+            // Must be followed by at least one whitespace
+            if rest.starts_with(' ') || rest.starts_with('\t') {
+                return Some((false, rest.trim_start()));
+            }
+        }
+
+        // Check for [x] or [X] (checked)
+        if let Some(rest) = trimmed
+            .strip_prefix("[x]")
+            .or_else(|| trimmed.strip_prefix("[X]"))
+        {
+            // Must be followed by at least one whitespace
+            if rest.starts_with(' ') || rest.starts_with('\t') {
+                return Some((true, rest.trim_start()));
+            }
+        }
+
+        None
+    }
+
+    fn format_list(&self, items: &[(usize, ListItemType, String)]) -> String {
+        let mut output = String::new();
+
+        for (indent_level, item_type, item) in items {
+            let trimmed = item.trim_start();
+
+            // Extract the content after the marker
+            let content = if let Some(rest) = trimmed.strip_prefix("- ") {
+                rest
+            } else if let Some(rest) = trimmed.strip_prefix("+ ") {
+                rest
+            } else if let Some(rest) = trimmed.strip_prefix("* ") {
+                rest
+            } else if let Some(dot_pos) = trimmed.find(". ") {
+                &trimmed[dot_pos + 2..]
+            } else {
+                trimmed
+            };
+
+            // Use indentation level to determine nesting (each 4 spaces = 1 level)
+            let nesting_level = indent_level / 4;
+            let indent = "  ".repeat(nesting_level);
+
+            // Check for task list item marker and handle specially
+            if let Some((is_checked, remaining)) = self.parse_task_list_marker(content) {
+                // Task list item: use checkbox as the marker (no bullet)
+                let checkbox = if is_checked { "☑" } else { "☐" };
+                let formatted_content = self.format_inline(remaining);
+                let first_indent = format!("{}  {} ", indent, checkbox);
+                let cont_indent = format!("{}    ", indent); // align with content after checkbox
+                let wrapped = self.wrap_text(&formatted_content, &first_indent, &cont_indent);
+                output.push_str(&wrapped);
+                output.push('\n');
+                continue;
+            }
+
+            let formatted_content = self.format_inline(content);
+
+            // Format based on item type, with wrapping
+            match item_type {
+                ListItemType::Unordered => {
+                    let first_indent = format!("{}  • ", indent);
+                    let cont_indent = format!("{}    ", indent); // align with content after bullet
+                    let wrapped = self.wrap_text(&formatted_content, &first_indent, &cont_indent);
+                    output.push_str(&wrapped);
+                    output.push('\n');
+                }
+                ListItemType::Ordered(num) => {
+                    // Use the original number from the markdown
+                    let first_indent = format!("{}  {}. ", indent, num);
+                    // Continuation indent aligns with content (after "N. ")
+                    let cont_indent =
+                        format!("{}  {}  ", indent, " ".repeat(num.to_string().len()));
+                    let wrapped = self.wrap_text(&formatted_content, &first_indent, &cont_indent);
+                    output.push_str(&wrapped);
+                    output.push('\n');
+                }
+            }
+        }
+        // Add blank line after list for spacing
+        output.push('\n');
+        output
+    }
+
+    // Table parsing and formatting functions
+
+    fn is_table_delimiter_row(&self, line: &str) -> bool {
+        // Must contain pipes
+        if !line.contains('|') {
+            return false;
+        }
+
+        let cells = self.parse_table_row(line);
+        if cells.is_empty() {
+            return false;
+        }
+
+        // Each cell must match pattern: optional :, at least one dash, optional :
+        cells.iter().all(|cell| {
+            let dashes = cell.strip_prefix(':').unwrap_or(cell);
+            let dashes = dashes.strip_suffix(':').unwrap_or(dashes);
+            !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+        })
+    }
+
+    /// Split a table row into trimmed cells. Leading and trailing pipes are
+    /// optional. `\|` is a literal pipe, even inside a code span; any other
+    /// backslash is left in place for inline formatting to handle.
+    fn parse_table_row(&self, line: &str) -> Vec<String> {
+        let mut cells = Vec::new();
+        let mut current_cell = String::new();
+        let mut chars = line.trim().chars().peekable();
+
+        // A leading pipe opens the first cell rather than closing an empty one
+        if chars.peek() == Some(&'|') {
+            chars.next();
+        }
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' if chars.peek() == Some(&'|') => {
+                    current_cell.push('|');
+                    chars.next();
+                }
+                '\\' => {
+                    // Keep escaped characters (including `\\`) together
+                    current_cell.push(ch);
+                    if let Some(next) = chars.next() {
+                        current_cell.push(next);
+                    }
+                }
+                '|' => cells.push(std::mem::take(&mut current_cell).trim().to_string()),
+                _ => current_cell.push(ch),
+            }
+        }
+
+        // The last cell has no closing pipe unless the row ends with one
+        if !current_cell.trim().is_empty() {
+            cells.push(current_cell.trim().to_string());
+        }
+
+        cells
+    }
+
+    fn parse_alignments(&self, delimiter_row: &str) -> Vec<Alignment> {
+        let cells = self.parse_table_row(delimiter_row);
+
+        cells
+            .iter()
+            .map(|cell| {
+                let trimmed = cell.trim();
+                let starts_colon = trimmed.starts_with(':');
+                let ends_colon = trimmed.ends_with(':');
+
+                match (starts_colon, ends_colon) {
+                    (true, true) => Alignment::Center,
+                    (false, true) => Alignment::Right,
+                    _ => Alignment::Left,
+                }
+            })
+            .collect()
+    }
+
+    /// Strip ANSI escape sequences from text for width calculation.
+    /// Handles both SGR sequences (\x1b[...m) and OSC8 hyperlinks (\x1b]8;;...\x1b\\).
+    pub fn strip_ansi(&self, text: &str) -> String {
+        // Strip ANSI escape sequences for width calculation
         // Handles both SGR sequences (\x1b[...m) and OSC8 hyperlinks (\x1b]8;;...\x1b\\)
-        let mut tokens: Vec<String> = Vec::new();
-        let mut current_token = String::new();
+        let mut result = String::new();
         let chars: Vec<char> = text.chars().collect();
         let mut i = 0;
 
@@ -1640,112 +4322,214 @@ impl StreamingParser {
                 if i + 1 < chars.len() {
                     match chars[i + 1] {
                         '[' => {
-                            // SGR sequence: \x1b[...m - keep until 'm'
-                            current_token.push(chars[i]);
-                            current_token.push(chars[i + 1]);
+                            // SGR sequence: \x1b[...m - skip until 'm'
                             i += 2;
                             while i < chars.len() && chars[i] != 'm' {
-                                current_token.push(chars[i]);
                                 i += 1;
                             }
                             if i < chars.len() {
-                                current_token.push(chars[i]); // the 'm'
-                                i += 1;
+                                i += 1; // skip the 'm'
                             }
                         }
                         ']' => {
                             // OSC sequence: \x1b]...ST where ST is \x1b\\ or BEL
                             // Used for OSC8 hyperlinks: \x1b]8;;URL\x1b\\
-                            current_token.push(chars[i]);
-                            current_token.push(chars[i + 1]);
                             i += 2;
                             while i < chars.len() {
                                 if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\'
                                 {
-                                    current_token.push(chars[i]);
-                                    current_token.push(chars[i + 1]);
-                                    i += 2;
+                                    i += 2; // skip \x1b\\
                                     break;
                                 } else if chars[i] == '\x07' {
                                     // BEL is also a valid string terminator
-                                    current_token.push(chars[i]);
                                     i += 1;
                                     break;
                                 }
-                                current_token.push(chars[i]);
                                 i += 1;
                             }
                         }
                         '_' => {
                             // APC sequence: \x1b_...ST where ST is \x1b\\
                             // Used for Kitty graphics protocol: \x1b_Gf=100,...;base64data\x1b\\
-                            current_token.push(chars[i]);
-                            current_token.push(chars[i + 1]);
                             i += 2;
                             while i < chars.len() {
                                 if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\'
                                 {
-                                    current_token.push(chars[i]);
-                                    current_token.push(chars[i + 1]);
-                                    i += 2;
+                                    i += 2; // skip \x1b\\
                                     break;
                                 }
-                                current_token.push(chars[i]);
                                 i += 1;
                             }
                         }
                         _ => {
-                            // Unknown escape sequence, include ESC and next char
-                            current_token.push(chars[i]);
-                            current_token.push(chars[i + 1]);
+                            // Unknown escape sequence, skip the ESC and next char
                             i += 2;
                         }
                     }
                 } else {
-                    current_token.push(chars[i]);
-                    i += 1;
-                }
-            } else if chars[i] == '!' && i + 1 < chars.len() && chars[i + 1] == '[' {
-                // Image markdown: ![alt](url) - keep as single token
-                // First, flush any existing token
-                if !current_token.is_empty() {
-                    tokens.push(current_token);
-                    current_token = String::new();
-                }
-                // Start building the image token
-                current_token.push(chars[i]); // '!'
-                current_token.push(chars[i + 1]); // '['
-                i += 2;
-                // Find closing ]
-                let mut bracket_depth = 1;
-                while i < chars.len() && bracket_depth > 0 {
-                    if chars[i] == '[' {
-                        bracket_depth += 1;
-                    } else if chars[i] == ']' {
-                        bracket_depth -= 1;
-                    }
-                    current_token.push(chars[i]);
                     i += 1;
                 }
-                // Check for ( following ]
-                if i < chars.len() && chars[i] == '(' {
-                    current_token.push(chars[i]);
-                    i += 1;
-                    // Find closing )
-                    let mut paren_depth = 1;
-                    while i < chars.len() && paren_depth > 0 {
-                        if chars[i] == '(' {
-                            paren_depth += 1;
-                        } else if chars[i] == ')' {
-                            paren_depth -= 1;
-                        }
-                        current_token.push(chars[i]);
-                        i += 1;
-                    }
+            } else {
+                result.push(chars[i]);
+                i += 1;
+            }
+        }
+
+        result
+    }
+
+    /// Calculate the display width of text after stripping ANSI codes.
+    /// Uses Unicode width to properly handle wide characters (like emoji and CJK).
+    pub fn display_width(&self, text: &str) -> usize {
+        self.strip_ansi(text).width()
+    }
+
+    /// Wrap text to self.width, preserving ANSI codes and not breaking words.
+    /// `first_indent` is prepended to the first line, `cont_indent` to continuation lines.
+    /// Long words that exceed width are kept whole on their own line.
+    pub fn wrap_text(&self, text: &str, first_indent: &str, cont_indent: &str) -> String {
+        self.wrap_text_to_width(text, first_indent, cont_indent, self.width)
+    }
+
+    /// Wrap text like `wrap_text`, but to an explicit width (used for table cells)
+    fn wrap_text_to_width(
+        &self,
+        text: &str,
+        first_indent: &str,
+        cont_indent: &str,
+        max_width: usize,
+    ) -> String {
+        let first_indent_width = first_indent.width();
+        let cont_indent_width = cont_indent.width();
+
+        // Split text into "tokens" preserving ANSI codes with adjacent words
+        // We need to split on whitespace while preserving the ANSI codes
+        // Handles both SGR sequences (\x1b[...m) and OSC8 hyperlinks (\x1b]8;;...\x1b\\)
+        let mut tokens: Vec<String> = Vec::new();
+        let mut current_token = String::new();
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+        let mut in_code_span = false;
+
+        while i < chars.len() {
+            if chars[i] == '\x1b' {
+                if i + 1 < chars.len() {
+                    match chars[i + 1] {
+                        '[' => {
+                            // SGR sequence: \x1b[...m - keep until 'm'
+                            let start = current_token.len();
+                            current_token.push(chars[i]);
+                            current_token.push(chars[i + 1]);
+                            i += 2;
+                            while i < chars.len() && chars[i] != 'm' {
+                                current_token.push(chars[i]);
+                                i += 1;
+                            }
+                            if i < chars.len() {
+                                current_token.push(chars[i]); // the 'm'
+                                i += 1;
+                            }
+                            in_code_span = &current_token[start..] == CODE_SPAN_STYLE;
+                        }
+                        ']' => {
+                            // OSC sequence: \x1b]...ST where ST is \x1b\\ or BEL
+                            // Used for OSC8 hyperlinks: \x1b]8;;URL\x1b\\
+                            current_token.push(chars[i]);
+                            current_token.push(chars[i + 1]);
+                            i += 2;
+                            while i < chars.len() {
+                                if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\'
+                                {
+                                    current_token.push(chars[i]);
+                                    current_token.push(chars[i + 1]);
+                                    i += 2;
+                                    break;
+                                } else if chars[i] == '\x07' {
+                                    // BEL is also a valid string terminator
+                                    current_token.push(chars[i]);
+                                    i += 1;
+                                    break;
+                                }
+                                current_token.push(chars[i]);
+                                i += 1;
+                            }
+                        }
+                        '_' => {
+                            // APC sequence: \x1b_...ST where ST is \x1b\\
+                            // Used for Kitty graphics protocol: \x1b_Gf=100,...;base64data\x1b\\
+                            current_token.push(chars[i]);
+                            current_token.push(chars[i + 1]);
+                            i += 2;
+                            while i < chars.len() {
+                                if chars[i] == '\x1b' && i + 1 < chars.len() && chars[i + 1] == '\\'
+                                {
+                                    current_token.push(chars[i]);
+                                    current_token.push(chars[i + 1]);
+                                    i += 2;
+                                    break;
+                                }
+                                current_token.push(chars[i]);
+                                i += 1;
+                            }
+                        }
+                        _ => {
+                            // Unknown escape sequence, include ESC and next char
+                            current_token.push(chars[i]);
+                            current_token.push(chars[i + 1]);
+                            i += 2;
+                        }
+                    }
+                } else {
+                    current_token.push(chars[i]);
+                    i += 1;
+                }
+            } else if chars[i] == '!' && i + 1 < chars.len() && chars[i + 1] == '[' {
+                // Image markdown: ![alt](url) - keep as single token
+                // First, flush any existing token
+                if !current_token.is_empty() {
+                    tokens.push(current_token);
+                    current_token = String::new();
+                }
+                // Start building the image token
+                current_token.push(chars[i]); // '!'
+                current_token.push(chars[i + 1]); // '['
+                i += 2;
+                // Find closing ]
+                let mut bracket_depth = 1;
+                while i < chars.len() && bracket_depth > 0 {
+                    if chars[i] == '[' {
+                        bracket_depth += 1;
+                    } else if chars[i] == ']' {
+                        bracket_depth -= 1;
+                    }
+                    current_token.push(chars[i]);
+                    i += 1;
+                }
+                // Check for ( following ]
+                if i < chars.len() && chars[i] == '(' {
+                    current_token.push(chars[i]);
+                    i += 1;
+                    // Find closing )
+                    let mut paren_depth = 1;
+                    while i < chars.len() && paren_depth > 0 {
+                        if chars[i] == '(' {
+                            paren_depth += 1;
+                        } else if chars[i] == ')' {
+                            paren_depth -= 1;
+                        }
+                        current_token.push(chars[i]);
+                        i += 1;
+                    }
                 }
                 // The image token is complete, push it
                 tokens.push(current_token);
                 current_token = String::new();
+            } else if in_code_span
+                && (current_token.ends_with(CODE_SPAN_STYLE) || chars.get(i + 1) == Some(&'\x1b'))
+            {
+                // A code span's padding stays with the code rather than becoming a break
+                current_token.push(chars[i]);
+                i += 1;
             } else if chars[i].is_whitespace() {
                 if !current_token.is_empty() {
                     tokens.push(current_token);
@@ -1782,7 +4566,7 @@ impl StreamingParser {
                 })
             {
                 // Not at start of line, check if token fits
-                if current_width + 1 + token_width > self.width {
+                if current_width + 1 + token_width > max_width {
                     // Token doesn't fit, start new line
                     lines.push(current_line);
                     current_line = format!("{}{}", cont_indent, token);
@@ -1841,14 +4625,89 @@ impl StreamingParser {
         }
     }
 
+    /// Render a `csv` or `tsv` code block as a table. Returns `None` when
+    /// the option is off, the block is another language, or the data is
+    /// malformed, so the block is shown as code instead.
+    fn format_delimited_table(&self, lines: &[String], info: &str) -> Option<String> {
+        if !self.csv_tables {
+            return None;
+        }
+        let info = self.parse_code_info(info);
+        let delimiter = match info.language.to_lowercase().as_str() {
+            "csv" => ',',
+            "tsv" => '\t',
+            _ => return None,
+        };
+
+        let mut records = parse_delimited(&lines.join("\n"), delimiter)?;
+        let header = if info.no_header {
+            Vec::new()
+        } else {
+            records.remove(0)
+        };
+
+        // Escape markdown so values are shown exactly as written
+        let escape = |record: Vec<String>| -> Vec<String> {
+            record
+                .iter()
+                .map(|field| escape_inline_markdown(field))
+                .collect()
+        };
+        let header = escape(header);
+        let rows: Vec<Vec<String>> = records.into_iter().map(escape).collect();
+
+        // Right-align columns that hold only numbers
+        let num_cols = header.len().max(rows.first().map_or(0, |r| r.len()));
+        let alignments: Vec<Alignment> = (0..num_cols)
+            .map(|i| {
+                let mut values = rows
+                    .iter()
+                    .map(|row| row[i].trim())
+                    .filter(|v| !v.is_empty());
+                let first = values.next();
+                if first.is_some_and(is_numeric_value) && values.all(is_numeric_value) {
+                    Alignment::Right
+                } else {
+                    Alignment::Left
+                }
+            })
+            .collect();
+
+        Some(self.format_table(&header, &alignments, &rows))
+    }
+
     fn format_table(
         &self,
         header: &[String],
         alignments: &[Alignment],
         rows: &[Vec<String>],
     ) -> String {
-        let mut output = String::new();
+        let Some(col_widths) = self.table_column_widths(header, rows) else {
+            return self.format_table_records(header, rows);
+        };
+
+        let mut output = self.format_table_header(header, alignments, &col_widths);
+
+        // Render data rows
+        for (row_index, row) in rows.iter().enumerate() {
+            let style = TableRowStyle {
+                striped: row_index % 2 == 1,
+                ..Default::default()
+            };
+            output.push_str(&self.format_table_row(row, &col_widths, alignments, style));
+        }
+
+        // Render bottom border: └───┴───┘
+        let bottom = self.table_border.chars().bottom;
+        output.push_str(&self.table_border_line(&col_widths, bottom, alignments));
+        output.push('\n');
+
+        output
+    }
 
+    /// Work out the width of each table column, or `None` if the table
+    /// should be shown as records instead
+    fn table_column_widths(&self, header: &[String], rows: &[Vec<String>]) -> Option<Vec<usize>> {
         // Calculate column widths
         let num_cols = header
             .len()
@@ -1858,14 +4717,14 @@ impl StreamingParser {
 
         // Measure header (with inline formatting stripped, using Unicode width)
         for (i, cell) in header.iter().enumerate() {
-            col_widths[i] = self.strip_ansi(cell).width();
+            col_widths[i] = self.raw_cell_width(cell);
         }
 
         // Measure all data rows
         for row in rows {
             for (i, cell) in row.iter().enumerate() {
                 if i < num_cols {
-                    let width = self.strip_ansi(cell).width();
+                    let width = self.raw_cell_width(cell);
                     col_widths[i] = col_widths[i].max(width);
                 }
             }
@@ -1876,117 +4735,458 @@ impl StreamingParser {
             *width = (*width).max(3);
         }
 
-        // Render top border: ┌───┬───┐
-        output.push('┌');
-        for (i, &width) in col_widths.iter().enumerate() {
-            output.push_str(&"─".repeat(width + 2));
-            if i < col_widths.len() - 1 {
-                output.push('┬');
-            }
-        }
-        output.push_str("┐\n");
-
-        // Render header row: │ Header │ Header │
-        output.push('│');
-        for (i, cell) in header.iter().enumerate() {
-            let formatted = self.format_inline(cell);
-            let aligned = self.align_cell(
-                &formatted,
-                col_widths[i],
-                alignments.get(i).copied().unwrap_or(Alignment::Left),
-            );
-            output.push_str(&format!(" {} │", aligned));
+        if self.table_layout == TableLayout::Records {
+            return None;
         }
-        output.push('\n');
 
-        // Render separator: ├───┼───┤
-        output.push('├');
-        for (i, &width) in col_widths.iter().enumerate() {
-            output.push_str(&"─".repeat(width + 2));
-            if i < col_widths.len() - 1 {
-                output.push('┼');
+        // Shrink columns to fit the output width, wrapping cells that no longer fit
+        let chars = self.table_border.chars();
+        let [left, inner, right] = chars.vertical.map(|v| v.width());
+        let border_width = num_cols * 2 * self.table_cell_padding()
+            + left
+            + right
+            + num_cols.saturating_sub(1) * inner;
+        if col_widths.iter().sum::<usize>() + border_width > self.width {
+            let available = self.width.saturating_sub(border_width);
+            col_widths = self.fit_column_widths(header, rows, num_cols, available);
+
+            // Too many columns to give each the minimum width
+            if self.table_layout == TableLayout::Auto
+                && col_widths.iter().sum::<usize>() > available
+            {
+                return None;
             }
         }
-        output.push_str("┤\n");
 
-        // Render data rows
-        for row in rows {
-            output.push('│');
-            for (i, &width) in col_widths.iter().enumerate().take(num_cols) {
-                let cell = row.get(i).map(|s| s.as_str()).unwrap_or("");
-                let formatted = self.format_inline(cell);
-                let aligned = self.align_cell(
-                    &formatted,
-                    width,
-                    alignments.get(i).copied().unwrap_or(Alignment::Left),
-                );
-                output.push_str(&format!(" {} │", aligned));
-            }
-            output.push('\n');
-        }
+        Some(col_widths)
+    }
 
-        // Render bottom border: └───┴───┘
-        output.push('└');
-        for (i, &width) in col_widths.iter().enumerate() {
-            output.push_str(&"─".repeat(width + 2));
-            if i < col_widths.len() - 1 {
-                output.push('┴');
+    /// Width of a raw table cell, counting each backslash escape as the one
+    /// character it displays
+    fn raw_cell_width(&self, cell: &str) -> usize {
+        let text = self.strip_ansi(cell);
+        let mut width = text.width();
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch == '\\' && chars.next_if(|&c| is_ascii_punctuation(c)).is_some() {
+                width -= 1;
             }
         }
-        output.push_str("┘\n\n");
+        width
+    }
 
-        output
+    /// Spaces each side of table cell content
+    fn table_cell_padding(&self) -> usize {
+        self.table_padding
+            .unwrap_or_else(|| self.table_border.chars().padding)
     }
 
-    /// Try to parse a callout type from the first line of a blockquote.
-    /// Callouts start with `[!TYPE]` where TYPE is NOTE, TIP, IMPORTANT, WARNING, or CAUTION.
-    fn parse_callout_type(&self, first_line: &str) -> Option<CalloutType> {
-        let trimmed = first_line.trim();
+    /// Render the top border, header row and separator of a grid table
+    fn format_table_header(
+        &self,
+        header: &[String],
+        alignments: &[Alignment],
+        col_widths: &[usize],
+    ) -> String {
+        let chars = self.table_border.chars();
 
-        // Check for [!TYPE] pattern at the start
-        if !trimmed.starts_with("[!") {
-            return None;
+        // Render top border: ┌───┬───┐
+        let mut output = self.table_border_line(col_widths, chars.top, alignments);
+        if header.is_empty() {
+            return output;
         }
 
-        // Find the closing bracket
-        let end = trimmed.find(']')?;
-        let type_str = &trimmed[2..end];
+        // Render header row: │ Header │ Header │
+        let style = TableRowStyle {
+            bold: true,
+            ..Default::default()
+        };
+        output.push_str(&self.format_table_row(header, col_widths, alignments, style));
 
-        match type_str.to_uppercase().as_str() {
-            "NOTE" => Some(CalloutType::Note),
-            "TIP" => Some(CalloutType::Tip),
-            "IMPORTANT" => Some(CalloutType::Important),
-            "WARNING" => Some(CalloutType::Warning),
-            "CAUTION" => Some(CalloutType::Caution),
-            _ => None,
-        }
+        // Render separator: ├───┼───┤
+        output.push_str(&self.table_border_line(col_widths, chars.separator, alignments));
+
+        output
     }
 
-    /// Get the display properties for a callout type: (icon, name, ANSI color code)
-    fn callout_style(
+    /// Render a horizontal table line from its left, junction and right
+    /// pieces, e.g. `┌───┬───┐`. Markdown tables mark alignment with colons.
+    fn table_border_line(
         &self,
-        callout_type: CalloutType,
-    ) -> (&'static str, &'static str, &'static str) {
-        match callout_type {
-            // Blue for informational notes
-            CalloutType::Note => ("ℹ", "Note", "\u{001b}[34m"),
-            // Green for helpful tips
-            CalloutType::Tip => ("💡", "Tip", "\u{001b}[32m"),
-            // Purple/magenta for important information
-            CalloutType::Important => ("❗", "Important", "\u{001b}[35m"),
-            // Yellow for warnings
-            CalloutType::Warning => ("⚠", "Warning", "\u{001b}[33m"),
-            // Red for critical cautions
-            CalloutType::Caution => ("🛑", "Caution", "\u{001b}[31m"),
-        }
+        col_widths: &[usize],
+        pieces: Option<[&str; 3]>,
+        alignments: &[Alignment],
+    ) -> String {
+        let Some([left, junction, right]) = pieces else {
+            return String::new();
+        };
+        let horizontal = self.table_border.chars().horizontal;
+        let padding = self.table_cell_padding();
+
+        let segments: Vec<String> = col_widths
+            .iter()
+            .enumerate()
+            .map(|(i, &w)| {
+                let segment = horizontal.repeat(w + 2 * padding);
+                if self.table_border != TableBorder::Markdown {
+                    return segment;
+                }
+                let (start, end) = match alignments.get(i) {
+                    Some(Alignment::Center) => (":", ":"),
+                    Some(Alignment::Right) => ("-", ":"),
+                    _ => ("-", "-"),
+                };
+                let dashes = "-".repeat((w + 2 * padding).saturating_sub(2));
+                format!("{}{}{}", start, dashes, end)
+            })
+            .collect();
+        format!("{}{}{}\n", left, segments.join(junction), right)
     }
 
-    fn format_blockquote(&self, lines: &[(usize, String)]) -> String {
-        // Check if this is a callout (first line starts with [!TYPE])
-        if let Some((_, first_content)) = lines.first() {
-            if let Some(callout_type) = self.parse_callout_type(first_content) {
-                return self.format_callout(callout_type, lines);
-            }
+    /// Choose column widths that fit in `available` columns of text.
+    /// Narrow columns keep their natural width; wider ones shrink in
+    /// proportion to how much they exceed the minimum. If even the minimum
+    /// widths don't fit, those are returned and the table will overflow.
+    fn fit_column_widths(
+        &self,
+        header: &[String],
+        rows: &[Vec<String>],
+        num_cols: usize,
+        available: usize,
+    ) -> Vec<usize> {
+        // Measure rendered cells, since markup characters take no space once formatted
+        let mut natural = vec![3; num_cols];
+        for row in std::iter::once(header).chain(rows.iter().map(|r| r.as_slice())) {
+            for (i, cell) in row.iter().enumerate().take(num_cols) {
+                let width = self.display_width(&self.format_inline(cell));
+                natural[i] = natural[i].max(width);
+            }
+        }
+        if natural.iter().sum::<usize>() <= available {
+            return natural;
+        }
+
+        let floors: Vec<usize> = natural
+            .iter()
+            .map(|&w| w.min(self.table_min_column_width))
+            .collect();
+        let floor_total: usize = floors.iter().sum();
+        if floor_total >= available {
+            return floors;
+        }
+
+        let extra = available - floor_total;
+        let shrinkable: usize = natural.iter().zip(&floors).map(|(n, f)| n - f).sum();
+        let mut widths: Vec<usize> = natural
+            .iter()
+            .zip(&floors)
+            .map(|(&n, &f)| f + (n - f) * extra / shrinkable)
+            .collect();
+
+        // Hand the columns lost to rounding back to the widest columns
+        let mut leftover = available - widths.iter().sum::<usize>();
+        let mut order: Vec<usize> = (0..num_cols).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(natural[i]));
+        for i in order {
+            if leftover == 0 {
+                break;
+            }
+            if widths[i] < natural[i] {
+                widths[i] += 1;
+                leftover -= 1;
+            }
+        }
+
+        widths
+    }
+
+    /// Render a table as records: each row becomes a block of `Header: value`
+    /// lines, with values wrapped under each other and rules between rows
+    fn format_table_records(&self, header: &[String], rows: &[Vec<String>]) -> String {
+        let mut output = String::new();
+        for (row_index, row) in rows.iter().enumerate() {
+            output.push_str(&self.format_table_record(header, row, row_index > 0));
+        }
+        output.push('\n');
+
+        output
+    }
+
+    /// Render one table row as `Header: value` lines, preceded by a rule
+    /// when it follows another record
+    fn format_table_record(&self, header: &[String], row: &[String], rule_before: bool) -> String {
+        let labels: Vec<String> = (0..header.len().max(row.len()))
+            .map(|i| {
+                let label = match header.get(i) {
+                    Some(cell) => self.format_inline(cell),
+                    None => format!("Column {}", i + 1),
+                };
+                format!("{}:", label)
+            })
+            .collect();
+        let label_width = labels
+            .iter()
+            .map(|l| self.display_width(l))
+            .max()
+            .unwrap_or(0);
+        // Long headers get a line of their own rather than pushing values off screen
+        let value_indent = " ".repeat((label_width + 1).min(self.width / 2));
+
+        let mut output = String::new();
+        if rule_before {
+            let rule = if self.table_border == TableBorder::Ascii {
+                "-"
+            } else {
+                "─"
+            };
+            output.push_str(&format!(
+                "\u{001b}[2m{}\u{001b}[0m\n",
+                rule.repeat(self.width)
+            ));
+        }
+        for (i, label) in labels.iter().enumerate() {
+            let value = row
+                .get(i)
+                .map(|s| self.format_inline(s))
+                .unwrap_or_default();
+            let wrapped = self.wrap_text_to_width(&value, &value_indent, &value_indent, self.width);
+            let wrapped = wrapped.trim_end_matches('\n');
+            let label_len = self.display_width(label);
+
+            output.push_str(&format!("\u{001b}[1m{}\u{001b}[0m", label));
+            if !value.is_empty() {
+                if label_len < value_indent.len() {
+                    output.push_str(&" ".repeat(value_indent.len() - label_len));
+                    output.push_str(&wrapped[value_indent.len()..]);
+                } else {
+                    output.push('\n');
+                    output.push_str(wrapped);
+                }
+            }
+            output.push('\n');
+        }
+
+        output
+    }
+
+    /// Render one table row. Cells wider than their column wrap onto
+    /// extra physical lines, so the row is as tall as its tallest cell,
+    /// or are cut short with `…` when the style says to truncate.
+    fn format_table_row(
+        &self,
+        cells: &[String],
+        col_widths: &[usize],
+        alignments: &[Alignment],
+        style: TableRowStyle,
+    ) -> String {
+        let wrapped: Vec<Vec<String>> = col_widths
+            .iter()
+            .enumerate()
+            .map(|(i, &width)| {
+                let cell = cells.get(i).map(|s| s.as_str()).unwrap_or("");
+                let mut formatted = self.format_inline(cell);
+                if style.bold && !formatted.is_empty() {
+                    formatted = format!(
+                        "\u{001b}[1m{}\u{001b}[0m",
+                        formatted.replace("\u{001b}[0m", "\u{001b}[0m\u{001b}[1m")
+                    );
+                }
+                if style.truncate {
+                    vec![self.truncate_table_cell(&formatted, width)]
+                } else {
+                    self.wrap_table_cell(&formatted, width)
+                }
+            })
+            .collect();
+        let height = wrapped.iter().map(Vec::len).max().unwrap_or(1);
+
+        let chars = self.table_border.chars();
+        let [left, inner, right] = chars.vertical;
+        let padding = " ".repeat(self.table_cell_padding());
+        let background = (style.striped && self.table_zebra).then(|| self.zebra_background());
+
+        let mut output = String::new();
+        for line in 0..height {
+            output.push_str(left);
+            for (i, &width) in col_widths.iter().enumerate() {
+                let content = wrapped[i].get(line).map(|s| s.as_str()).unwrap_or("");
+                let aligned = self.align_cell(
+                    content,
+                    width,
+                    alignments.get(i).copied().unwrap_or(Alignment::Left),
+                );
+                let cell = format!("{}{}{}", padding, aligned, padding);
+                match &background {
+                    Some(bg) => output.push_str(&format!(
+                        "{}{}\u{001b}[0m",
+                        bg,
+                        cell.replace("\u{001b}[0m", &format!("\u{001b}[0m{}", bg))
+                    )),
+                    None => output.push_str(&cell),
+                }
+                output.push_str(if i + 1 < col_widths.len() {
+                    inner
+                } else {
+                    right
+                });
+            }
+            // Without a right border, padding at the end of the line is just trailing space
+            if right.is_empty() && background.is_none() {
+                output.truncate(output.trim_end_matches(' ').len());
+            }
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Background for shaded table rows: the theme's background color
+    fn zebra_background(&self) -> String {
+        let theme = Self::theme_name_to_enum(&self.theme_name)
+            .map(|name| self.theme_set.get(name))
+            .unwrap_or_else(|| self.theme_set.get(EmbeddedThemeName::Base16OceanDark));
+        Self::theme_background_escape(theme)
+    }
+
+    /// Cut formatted cell content down to a column width, marking the cut with `…`
+    fn truncate_table_cell(&self, content: &str, width: usize) -> String {
+        if self.display_width(content) <= width {
+            return content.to_string();
+        }
+
+        let mut cell = self
+            .split_ansi_by_width(content, width.saturating_sub(1))
+            .swap_remove(0);
+        // Close a link cut off before its end
+        if cell
+            .rfind("\x1b]8;")
+            .is_some_and(|pos| !cell[pos..].starts_with("\x1b]8;;\x1b\\"))
+        {
+            cell.push_str("\x1b]8;;\x1b\\");
+        }
+        if cell.contains('\x1b') {
+            cell.push_str("\x1b[0m");
+        }
+        cell.push(if self.table_border == TableBorder::Ascii {
+            '~'
+        } else {
+            '…'
+        });
+        cell
+    }
+
+    /// Wrap formatted cell content to a column width. Words longer than the
+    /// column are split, and any style or OSC8 link still open at the end of a
+    /// line is closed there and reopened on the next, so it never leaks into
+    /// the borders or neighbouring cells.
+    fn wrap_table_cell(&self, content: &str, width: usize) -> Vec<String> {
+        if self.display_width(content) <= width {
+            return vec![content.to_string()];
+        }
+
+        let mut lines = Vec::new();
+        for line in self.wrap_text_to_width(content, "", "", width).split('\n') {
+            if self.display_width(line) > width {
+                lines.extend(self.split_ansi_by_width(line, width));
+            } else {
+                lines.push(line.to_string());
+            }
+        }
+
+        let mut active_sgr = String::new();
+        let mut active_link: Option<String> = None;
+        for line in &mut lines {
+            let mut balanced = active_sgr.clone();
+            balanced.push_str(active_link.as_deref().unwrap_or(""));
+
+            let mut rest = line.as_str();
+            while let Some(pos) = rest.find('\x1b') {
+                let seq_rest = &rest[pos..];
+                let len = if seq_rest.starts_with("\x1b[") {
+                    seq_rest.find('m').map_or(seq_rest.len(), |end| end + 1)
+                } else if let Some(osc) = seq_rest.strip_prefix("\x1b]") {
+                    let st = osc.find("\x1b\\").map(|end| end + 4);
+                    let bel = osc.find('\x07').map(|end| end + 3);
+                    st.into_iter().chain(bel).min().unwrap_or(seq_rest.len())
+                } else {
+                    1
+                };
+                let seq = &seq_rest[..len];
+                if seq == "\x1b[0m" || seq == "\x1b[m" {
+                    active_sgr.clear();
+                } else if seq.starts_with("\x1b[") {
+                    active_sgr.push_str(seq);
+                } else if let Some(params) = seq.strip_prefix("\x1b]8;") {
+                    let url = params
+                        .trim_end_matches(['\x07', '\\'])
+                        .trim_end_matches('\x1b')
+                        .split_once(';')
+                        .map_or("", |(_, url)| url);
+                    active_link = (!url.is_empty()).then(|| seq.to_string());
+                }
+                rest = &seq_rest[len..];
+            }
+
+            balanced.push_str(line);
+            if active_link.is_some() {
+                balanced.push_str("\x1b]8;;\x1b\\");
+            }
+            if !active_sgr.is_empty() {
+                balanced.push_str("\x1b[0m");
+            }
+            *line = balanced;
+        }
+        lines
+    }
+
+    /// Try to parse a callout type from the first line of a blockquote.
+    /// Callouts start with `[!TYPE]` where TYPE is NOTE, TIP, IMPORTANT, WARNING, or CAUTION.
+    fn parse_callout_type(&self, first_line: &str) -> Option<CalloutType> {
+        let trimmed = first_line.trim();
+
+        // Check for [!TYPE] pattern at the start
+        if !trimmed.starts_with("[!") {
+            return None;
+        }
+
+        // Find the closing bracket
+        let end = trimmed.find(']')?;
+        let type_str = &trimmed[2..end];
+
+        match type_str.to_uppercase().as_str() {
+            "NOTE" => Some(CalloutType::Note),
+            "TIP" => Some(CalloutType::Tip),
+            "IMPORTANT" => Some(CalloutType::Important),
+            "WARNING" => Some(CalloutType::Warning),
+            "CAUTION" => Some(CalloutType::Caution),
+            _ => None,
+        }
+    }
+
+    /// Get the display properties for a callout type: (icon, name, ANSI color code)
+    fn callout_style(
+        &self,
+        callout_type: CalloutType,
+    ) -> (&'static str, &'static str, &'static str) {
+        match callout_type {
+            // Blue for informational notes
+            CalloutType::Note => ("ℹ", "Note", "\u{001b}[34m"),
+            // Green for helpful tips
+            CalloutType::Tip => ("💡", "Tip", "\u{001b}[32m"),
+            // Purple/magenta for important information
+            CalloutType::Important => ("❗", "Important", "\u{001b}[35m"),
+            // Yellow for warnings
+            CalloutType::Warning => ("⚠", "Warning", "\u{001b}[33m"),
+            // Red for critical cautions
+            CalloutType::Caution => ("🛑", "Caution", "\u{001b}[31m"),
+        }
+    }
+
+    fn format_blockquote(&self, lines: &[(usize, String)]) -> String {
+        // Check if this is a callout (first line starts with [!TYPE])
+        if let Some((_, first_content)) = lines.first() {
+            if let Some(callout_type) = self.parse_callout_type(first_content) {
+                return self.format_callout(callout_type, lines);
+            }
         }
 
         // Regular blockquote formatting
@@ -2067,6 +5267,7 @@ impl StreamingParser {
     /// Format inline markdown elements (bold, italic, code, links, etc.) to ANSI codes.
     pub fn format_inline(&self, text: &str) -> String {
         let mut result = String::new();
+        let mut nodes: Vec<InlineNode> = Vec::new();
         let chars: Vec<char> = text.chars().collect();
         let mut i = 0;
 
@@ -2088,6 +5289,40 @@ impl StreamingParser {
                 continue;
             }
 
+            // Check for `code` spans, which take precedence over everything but
+            // backslash escapes outside them
+            if chars[i] == '`' {
+                let run_len = chars[i..].iter().take_while(|&&c| c == '`').count();
+                match find_code_span_end(&chars, i + run_len, run_len) {
+                    Some(end) => {
+                        let content: String = chars[i + run_len..end].iter().collect();
+                        result.push_str(&self.format_code_span(&content));
+                        i = end + run_len;
+                    }
+                    None => {
+                        // An unmatched backtick string is literal text
+                        result.extend(&chars[i..i + run_len]);
+                        i += run_len;
+                    }
+                }
+                continue;
+            }
+
+            // Check for $math$ and $$math$$
+            if chars[i] == '$' && self.math {
+                if let Some((source, end)) = find_inline_math(&chars, i) {
+                    result.push_str(&self.format_inline_math(&source));
+                    i = end;
+                    continue;
+                }
+                // An unmatched `$$` must not open `$...$` at its second dollar
+                if chars.get(i + 1) == Some(&'$') {
+                    result.push_str("$$");
+                    i += 2;
+                    continue;
+                }
+            }
+
             // Check for ![alt](src) images
             if chars[i] == '!' {
                 if let Some(img) = self.parse_image(&chars, i) {
@@ -2102,16 +5337,9 @@ impl StreamingParser {
                 // First try inline link [text](url)
                 if let Some(link) = self.parse_link(&chars, i) {
                     // Process link text through format_inline to handle images, formatting, etc.
-                    let formatted_text = self.format_inline(&link.text);
-                    // OSC8 format with blue and underline styling
-                    result.push_str("\u{001b}]8;;");
-                    result.push_str(&link.url);
-                    result.push_str("\u{001b}\\");
-                    // Blue and underlined
-                    result.push_str("\u{001b}[34;4m");
-                    result.push_str(&formatted_text);
-                    result.push_str("\u{001b}[0m");
-                    result.push_str("\u{001b}]8;;\u{001b}\\");
+                    let formatted_text = self.format_link_text(&link.text);
+                    result.push_str(&self.format_hyperlink(&link.url, &formatted_text));
+                    result.push_str(&self.format_link_title(link.title.as_deref()));
                     i = link.end_pos;
                     continue;
                 }
@@ -2124,78 +5352,43 @@ impl StreamingParser {
                 }
             }
 
-            // Check for ~~strikethrough~~
-            if i + 1 < chars.len() && chars[i] == '~' && chars[i + 1] == '~' {
-                if let Some(end) = self.find_closing("~~", &chars, i + 2) {
-                    let inner: String = chars[i + 2..end].iter().collect();
-                    let formatted_inner = self.format_inline(&inner);
-                    result.push_str("\u{001b}[9m");
-                    result.push_str(&formatted_inner);
-                    result.push_str("\u{001b}[0m");
-                    i = end + 2;
-                    continue;
-                }
-            }
-
-            // Check for **bold**
-            if i + 1 < chars.len() && chars[i] == '*' && chars[i + 1] == '*' {
-                if let Some(end) = self.find_closing("**", &chars, i + 2) {
-                    let inner: String = chars[i + 2..end].iter().collect();
-                    let formatted_inner = self.format_inline(&inner);
-                    result.push_str("\u{001b}[1m");
-                    result.push_str(&formatted_inner);
-                    result.push_str("\u{001b}[0m");
-                    i = end + 2;
-                    continue;
-                }
-            }
-
-            // Check for *italic*
-            if chars[i] == '*' {
-                if let Some(end) = self.find_closing("*", &chars, i + 1) {
-                    let inner: String = chars[i + 1..end].iter().collect();
-                    let formatted_inner = self.format_inline(&inner);
-                    result.push_str("\u{001b}[3m");
-                    result.push_str(&formatted_inner);
-                    result.push_str("\u{001b}[0m");
-                    i = end + 1;
-                    continue;
-                }
-            }
-
-            // Check for `code`
-            if chars[i] == '`' {
-                if let Some(end) = self.find_closing("`", &chars, i + 1) {
-                    result.push_str("\u{001b}[38;5;167;48;5;235m ");
-                    result.extend(&chars[i + 1..end]);
-                    result.push_str(" \u{001b}[0m");
-                    i = end + 1;
-                    continue;
-                }
-            }
-
-            // Check for __bold__ (underscore variant)
-            if i + 1 < chars.len() && chars[i] == '_' && chars[i + 1] == '_' {
-                if let Some(end) = self.find_closing("__", &chars, i + 2) {
-                    let inner: String = chars[i + 2..end].iter().collect();
-                    let formatted_inner = self.format_inline(&inner);
-                    result.push_str("\u{001b}[1m");
-                    result.push_str(&formatted_inner);
-                    result.push_str("\u{001b}[0m");
-                    i = end + 2;
-                    continue;
-                }
+            // Runs of *, _ and ~ go on the delimiter stack; whether they
+            // become emphasis is decided once the whole text has been seen
+            if matches!(chars[i], '*' | '_' | '~') {
+                let ch = chars[i];
+                let run_len = chars[i..].iter().take_while(|&&c| c == ch).count();
+                let before = i.checked_sub(1).map(|j| chars[j]);
+                let after = chars.get(i + run_len).copied();
+                let (left_flanking, right_flanking) = delimiter_flanking(before, after);
+                let is_punct = |c: Option<char>| c.is_some_and(is_punctuation_char);
+                let (can_open, can_close) = match ch {
+                    '_' => (
+                        left_flanking && (!right_flanking || is_punct(before)),
+                        right_flanking && (!left_flanking || is_punct(after)),
+                    ),
+                    // Strikethrough takes one or two tildes
+                    '~' if run_len > 2 => (false, false),
+                    _ => (left_flanking, right_flanking),
+                };
+                nodes.push(InlineNode::Text(std::mem::take(&mut result)));
+                nodes.push(InlineNode::Delimiter(DelimiterRun {
+                    ch,
+                    count: run_len,
+                    original_count: run_len,
+                    can_open,
+                    can_close,
+                    opens: Vec::new(),
+                    closes: Vec::new(),
+                }));
+                i += run_len;
+                continue;
             }
 
-            // Check for _italic_ (underscore variant)
-            if chars[i] == '_' {
-                if let Some(end) = self.find_closing("_", &chars, i + 1) {
-                    let inner: String = chars[i + 1..end].iter().collect();
-                    let formatted_inner = self.format_inline(&inner);
-                    result.push_str("\u{001b}[3m");
-                    result.push_str(&formatted_inner);
-                    result.push_str("\u{001b}[0m");
-                    i = end + 1;
+            // Check for <scheme:...> and <user@host> autolinks
+            if chars[i] == '<' {
+                if let Some((url, text, end)) = parse_angle_autolink(&chars, i) {
+                    result.push_str(&self.format_autolink(&url, &text));
+                    i = end;
                     continue;
                 }
             }
@@ -2209,6 +5402,16 @@ impl StreamingParser {
                 }
             }
 
+            // Check for bare URLs and email addresses (GFM extended autolinks)
+            if !self.in_link_text.get() {
+                if let Some((url, end)) = parse_extended_autolink(&chars, i) {
+                    let text: String = chars[i..end].iter().collect();
+                    result.push_str(&self.format_autolink(&url, &text));
+                    i = end;
+                    continue;
+                }
+            }
+
             // Check for HTML entities (&amp;, &#123;, &#x7B;)
             if chars[i] == '&' {
                 if let Some((decoded, consumed)) = decode_html_entity(&chars, i) {
@@ -2222,64 +5425,318 @@ impl StreamingParser {
             i += 1;
         }
 
-        result
+        if nodes.is_empty() {
+            return result;
+        }
+        nodes.push(InlineNode::Text(result));
+        process_emphasis(&mut nodes);
+        render_inline_nodes(nodes)
     }
 
-    fn render_image(&self, alt: &str, src: &str) -> String {
-        match self.image_protocol {
-            ImageProtocol::None => format!("![{}]({})", alt, src),
-            ImageProtocol::Kitty => {
-                // Load image data (local file or HTTP)
-                match self.load_image_data(src) {
-                    Ok(data) => {
-                        // Process and render
-                        match self.process_image(&data) {
-                            Ok(kitty_output) => kitty_output,
-                            Err(_) => alt.to_string(), // Fallback to alt text
-                        }
-                    }
-                    Err(_) => alt.to_string(), // Fallback to alt text
-                }
-            }
-        }
+    /// Format the text of a link, where autolinks would otherwise nest links
+    fn format_link_text(&self, text: &str) -> String {
+        let outer = self.in_link_text.replace(true);
+        let formatted = self.format_inline(text);
+        self.in_link_text.set(outer);
+        formatted
     }
 
-    fn load_image_data(&self, src: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // Check cache first (populated by prefetch_images)
-        if let Some(data) = self.image_cache.get(src) {
-            return Ok(data.clone());
+    /// Render formatted link text as an OSC8 hyperlink, blue and underlined.
+    /// A terminal can't follow a bare `#fragment`, so such links point into
+    /// the document file when its path is known, or else name the heading.
+    fn format_hyperlink(&self, url: &str, text: &str) -> String {
+        let mut url = url.to_string();
+        if let Some(fragment) = url.strip_prefix('#') {
+            if let Some(document) = &self.document_url {
+                url = format!("{}#{}", document, fragment);
+            } else if let Some(anchor) = self
+                .heading_anchors
+                .borrow()
+                .iter()
+                .find(|anchor| anchor.slug.eq_ignore_ascii_case(fragment))
+            {
+                return format!(
+                    "\u{001b}[34;4m{}\u{001b}[0m \u{001b}[2m(§ {})\u{001b}[0m",
+                    text,
+                    anchor.numbered_text()
+                );
+            }
         }
+        format!(
+            "\u{001b}]8;;{}\u{001b}\\\u{001b}[34;4m{}\u{001b}[0m\u{001b}]8;;\u{001b}\\",
+            encode_uri(&url),
+            text
+        )
+    }
 
-        // Not in cache, fetch directly (fallback for non-prefetched images)
-        if src.starts_with("http://") || src.starts_with("https://") {
-            // Fetch remote image
-            let response = ureq::get(src).call()?;
-            let mut bytes = Vec::new();
-            std::io::Read::read_to_end(&mut response.into_reader(), &mut bytes)?;
-            Ok(bytes)
-        } else {
-            // Load local file
-            std::fs::read(src).map_err(|e| e.into())
+    /// Render a link title as a dim suffix, if titles are shown
+    fn format_link_title(&self, title: Option<&str>) -> String {
+        match title {
+            Some(title) if self.link_titles && !title.trim().is_empty() => {
+                let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+                format!(" \u{001b}[2m\"{}\"\u{001b}[0m", title)
+            }
+            _ => String::new(),
         }
     }
 
-    fn process_image(&self, data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
-        use image::ImageReader;
-        use std::io::Cursor;
-
-        // Check if data is SVG (XML-based vector graphics)
-        let is_svg = Self::is_svg(data);
+    /// Render an autolink as an OSC8 hyperlink styled like other links. The
+    /// text is shown as written, without inline formatting.
+    fn format_autolink(&self, url: &str, text: &str) -> String {
+        format!(
+            "\u{001b}]8;;{}\u{001b}\\\u{001b}[34;4m{}\u{001b}[0m\u{001b}]8;;\u{001b}\\",
+            url, text
+        )
+    }
 
-        let img = if is_svg {
-            self.render_svg(data)?
-        } else {
-            // Decode raster image
-            ImageReader::new(Cursor::new(data))
-                .with_guessed_format()?
-                .decode()?
+    /// Render a code span. Line endings become spaces and, as the spec
+    /// requires, one space is stripped from each side when both are present,
+    /// so a span can start or end with a backtick. The span is then padded
+    /// with one space on each side.
+    fn format_code_span(&self, content: &str) -> String {
+        let content = content.replace('\n', " ");
+        let content = match content.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+            Some(inner) if content.contains(|c: char| c != ' ') => inner,
+            _ => &content,
         };
+        format!("{} {} \u{001b}[0m", CODE_SPAN_STYLE, content)
+    }
 
-        // Convert image pixel width to terminal columns
+    /// Format a `math` code block as display math
+    fn format_math_code_block(&self, lines: &[String], info: &str) -> Option<String> {
+        if !self.math || self.parse_code_info(info).language != "math" {
+            return None;
+        }
+        Some(self.format_display_math(&lines.join("\n")))
+    }
+
+    /// Format `$...$` math on one line
+    fn format_inline_math(&self, source: &str) -> String {
+        let math = math_linear(&parse_math(source));
+        format!("{}{}\u{001b}[0m", MATH_STYLE, math.trim())
+    }
+
+    /// Format display math centered on its own lines, with fractions stacked
+    /// and limits above and below big operators. A row too wide for the
+    /// terminal falls back to the single-line form.
+    fn format_display_math(&self, source: &str) -> String {
+        let mut output = String::new();
+        for row in math_rows(parse_math(source)) {
+            let math_box = self.math_box(&row);
+            let (lines, width) = if math_box.width <= self.width {
+                (math_box.lines, math_box.width)
+            } else {
+                let line = math_linear(&row);
+                let width = self.display_width(&line);
+                (vec![line], width)
+            };
+            let indent = " ".repeat(self.width.saturating_sub(width) / 2);
+            for line in lines {
+                output.push_str(&format!(
+                    "{}{}{}\u{001b}[0m\n",
+                    indent,
+                    MATH_STYLE,
+                    line.trim_end()
+                ));
+            }
+        }
+        output.push('\n');
+        output
+    }
+
+    /// Lay out display math as a box of lines
+    fn math_box(&self, nodes: &[MathNode]) -> MathBox {
+        let boxes = nodes.iter().map(|node| self.math_node_box(node)).collect();
+        MathBox::hconcat(boxes)
+    }
+
+    fn math_node_box(&self, node: &MathNode) -> MathBox {
+        let text_box = |text: String| {
+            let width = self.display_width(&text);
+            MathBox::text(text, width)
+        };
+        match node {
+            MathNode::Group(nodes) => self.math_box(nodes),
+            MathNode::Frac(numerator, denominator) => {
+                let numerator = self.math_box(numerator);
+                let denominator = self.math_box(denominator);
+                let width = numerator.width.max(denominator.width) + 2;
+                let baseline = numerator.height();
+                MathBox::vstack(
+                    vec![
+                        numerator,
+                        MathBox::text("─".repeat(width), width),
+                        denominator,
+                    ],
+                    baseline,
+                )
+            }
+            MathNode::Sqrt(index, radicand) => {
+                let radicand_box = self.math_box(radicand);
+                if radicand_box.height() == 1 {
+                    return text_box(math_linear(std::slice::from_ref(node)));
+                }
+                MathBox::hconcat(vec![
+                    text_box(math_root_sign(index.as_deref())),
+                    radicand_box.parenthesize(),
+                ])
+            }
+            MathNode::Scripts { base, sub, sup } => {
+                // Limits go above and below the operator, except on integrals
+                if let Some(op) = limits_operator(base) {
+                    let mut boxes = Vec::new();
+                    let mut baseline = 0;
+                    if let Some(sup) = sup {
+                        let sup_box = self.math_box(sup);
+                        baseline = sup_box.height();
+                        boxes.push(sup_box);
+                    }
+                    boxes.push(text_box(op.to_string()));
+                    if let Some(sub) = sub {
+                        boxes.push(self.math_box(sub));
+                    }
+                    return MathBox::vstack(boxes, baseline);
+                }
+
+                let base_box = self.math_node_box(base);
+                let converted: Vec<Option<String>> = [(sub, false), (sup, true)]
+                    .into_iter()
+                    .filter_map(|(script, superscript)| {
+                        script
+                            .as_ref()
+                            .map(|script| math_script_text(&math_linear(script), superscript))
+                    })
+                    .collect();
+                if converted.iter().all(Option::is_some) {
+                    let scripts: String = converted.into_iter().flatten().collect();
+                    return MathBox::hconcat(vec![base_box, text_box(scripts)]);
+                }
+
+                // Raise the superscript above the base and lower the
+                // subscript below it
+                let sup_box = sup.as_ref().map(|sup| self.math_box(sup));
+                let sub_box = sub.as_ref().map(|sub| self.math_box(sub));
+                let width = sup_box
+                    .iter()
+                    .chain(sub_box.iter())
+                    .map(|b| b.width)
+                    .max()
+                    .unwrap_or(0);
+                let pad = |b: MathBox| -> Vec<String> {
+                    b.lines
+                        .into_iter()
+                        .map(|line| format!("{}{}", line, " ".repeat(width - b.width)))
+                        .collect()
+                };
+                let sup_height = sup_box.as_ref().map_or(0, MathBox::height);
+                let mut lines = sup_box.map(pad).unwrap_or_default();
+                lines.extend(std::iter::repeat_n(" ".repeat(width), base_box.height()));
+                lines.extend(sub_box.map(pad).unwrap_or_default());
+                let scripts = MathBox {
+                    lines,
+                    width,
+                    baseline: sup_height + base_box.baseline,
+                };
+                MathBox::hconcat(vec![base_box, scripts])
+            }
+            MathNode::Matrix {
+                open,
+                close,
+                rows,
+                centered,
+            } if !rows.is_empty() => {
+                let cells: Vec<Vec<MathBox>> = rows
+                    .iter()
+                    .map(|row| row.iter().map(|cell| self.math_box(cell)).collect())
+                    .collect();
+                let columns = cells.iter().map(Vec::len).max().unwrap_or(0);
+                let widths: Vec<usize> = (0..columns)
+                    .map(|column| {
+                        cells
+                            .iter()
+                            .filter_map(|row| row.get(column))
+                            .map(|cell| cell.width)
+                            .max()
+                            .unwrap_or(0)
+                    })
+                    .collect();
+                let rows: Vec<MathBox> = cells
+                    .into_iter()
+                    .map(|row| {
+                        let mut row = row.into_iter();
+                        let mut boxes = Vec::new();
+                        for (column, &width) in widths.iter().enumerate() {
+                            if column > 0 {
+                                boxes.push(text_box("  ".to_string()));
+                            }
+                            let cell = row.next().unwrap_or(MathBox::text(String::new(), 0));
+                            boxes.push(cell.pad(width, *centered));
+                        }
+                        MathBox::hconcat(boxes)
+                    })
+                    .collect();
+                let height: usize = rows.iter().map(MathBox::height).sum();
+                MathBox::vstack(rows, (height - 1) / 2).delimit(*open, *close)
+            }
+            node => text_box(math_linear(std::slice::from_ref(node))),
+        }
+    }
+
+    fn render_image(&self, alt: &str, src: &str) -> String {
+        match self.image_protocol {
+            ImageProtocol::None => format!("![{}]({})", alt, src),
+            ImageProtocol::Kitty => {
+                // Load image data (local file or HTTP)
+                match self.load_image_data(src) {
+                    Ok(data) => {
+                        // Process and render
+                        match self.process_image(&data) {
+                            Ok(kitty_output) => kitty_output,
+                            Err(_) => alt.to_string(), // Fallback to alt text
+                        }
+                    }
+                    Err(_) => alt.to_string(), // Fallback to alt text
+                }
+            }
+        }
+    }
+
+    fn load_image_data(&self, src: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        // Check cache first (populated by prefetch_images)
+        if let Some(data) = self.image_cache.get(src) {
+            return Ok(data.clone());
+        }
+
+        // Not in cache, fetch directly (fallback for non-prefetched images)
+        if src.starts_with("http://") || src.starts_with("https://") {
+            // Fetch remote image
+            let response = ureq::get(src).call()?;
+            let mut bytes = Vec::new();
+            std::io::Read::read_to_end(&mut response.into_reader(), &mut bytes)?;
+            Ok(bytes)
+        } else {
+            // Load local file
+            std::fs::read(src).map_err(|e| e.into())
+        }
+    }
+
+    fn process_image(&self, data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        use image::ImageReader;
+        use std::io::Cursor;
+
+        // Check if data is SVG (XML-based vector graphics)
+        let is_svg = Self::is_svg(data);
+
+        let img = if is_svg {
+            self.render_svg(data)?
+        } else {
+            // Decode raster image
+            ImageReader::new(Cursor::new(data))
+                .with_guessed_format()?
+                .decode()?
+        };
+
+        // Convert image pixel width to terminal columns
         // Assume ~9 pixels per terminal column (typical for monospace fonts)
         const PIXELS_PER_COLUMN: f64 = 9.0;
         let natural_cols = (img.width() as f64 / PIXELS_PER_COLUMN).ceil() as usize;
@@ -2407,39 +5864,14 @@ impl StreamingParser {
             return None;
         }
 
-        // Find closing )
-        let url_end = self.find_closing(")", chars, text_end + 2)?;
-
-        // Extract text
+        let (url, title, end_pos) = parse_inline_link_tail(chars, text_end + 1)?;
         let text: String = chars[start + 1..text_end].iter().collect();
 
-        // Parse the content between ( and )
-        let link_content: String = chars[text_end + 2..url_end].iter().collect();
-        let link_content = link_content.trim();
-
-        // Split URL and optional title
-        // Title is separated by whitespace and enclosed in quotes or parentheses
-        let url = if let Some(space_pos) = link_content.find(|c: char| c.is_whitespace()) {
-            // There's whitespace, so there might be a title
-            let url_part = link_content[..space_pos].trim();
-            let after_url = link_content[space_pos..].trim();
-
-            // Check if there's a title (starts with ", ', or ()
-            if after_url.is_empty() {
-                url_part.to_string()
-            } else {
-                // Title exists, just use the URL part
-                url_part.to_string()
-            }
-        } else {
-            // No whitespace, entire content is the URL
-            link_content.to_string()
-        };
-
         Some(LinkData {
             text,
             url,
-            end_pos: url_end + 1,
+            title,
+            end_pos,
         })
     }
 
@@ -2503,12 +5935,13 @@ impl StreamingParser {
         let normalized_label = self.normalize_link_label(&ref_link.label);
 
         // Check if we have a definition for this label
-        if let Some((url, _title)) = self.link_definitions.get(&normalized_label) {
+        if let Some((url, title)) = self.link_definitions.get(&normalized_label) {
             // Definition found - render as normal OSC8 hyperlink
-            let formatted_text = self.format_inline(&ref_link.text);
+            let formatted_text = self.format_link_text(&ref_link.text);
             format!(
-                "\u{001b}]8;;{}\u{001b}\\\u{001b}[34;4m{}\u{001b}[0m\u{001b}]8;;\u{001b}\\",
-                url, formatted_text
+                "{}{}",
+                self.format_hyperlink(url, &formatted_text),
+                self.format_link_title(title.as_deref())
             )
         } else {
             // No definition (yet) - use citation style
@@ -2695,200 +6128,2162 @@ impl StreamingParser {
             search_pos += 1;
         }
 
-        let (inner_end, end_pos) = content_end?;
+        let (inner_end, end_pos) = content_end?;
+
+        // Extract inner content
+        let inner: String = chars[tag_end + 1..inner_end].iter().collect();
+
+        // Format based on tag type
+        let formatted = match tag_name.as_str() {
+            "em" | "i" => {
+                let formatted_inner = self.format_inline(&inner);
+                format!("\u{001b}[3m{}\u{001b}[0m", formatted_inner)
+            }
+            "strong" | "b" => {
+                let formatted_inner = self.format_inline(&inner);
+                format!("\u{001b}[1m{}\u{001b}[0m", formatted_inner)
+            }
+            "u" => {
+                let formatted_inner = self.format_inline(&inner);
+                format!("\u{001b}[4m{}\u{001b}[0m", formatted_inner)
+            }
+            "s" | "strike" | "del" => {
+                let formatted_inner = self.format_inline(&inner);
+                format!("\u{001b}[9m{}\u{001b}[0m", formatted_inner)
+            }
+            "code" => {
+                // Inline code - don't recursively format
+                self.format_code_span(&decode_html_entities(&inner))
+            }
+            "pre" => {
+                // Code block style - dark background, no recursive formatting
+                let lines: Vec<&str> = inner.lines().collect();
+                let mut result = String::new();
+                for line in lines {
+                    result.push_str("\u{001b}[38;5;167;48;5;235m ");
+                    result.push_str(line);
+                    result.push_str(" \u{001b}[0m\n");
+                }
+                result
+            }
+            "a" => {
+                // Extract href attribute
+                let href = self.extract_href(tag_content);
+                let formatted_inner = self.format_link_text(&inner);
+                if let Some(url) = href {
+                    self.format_hyperlink(&url, &formatted_inner)
+                } else {
+                    // No href, just format the inner content
+                    formatted_inner
+                }
+            }
+            _ => {
+                // Unknown tag - strip it but keep inner content
+                self.format_inline(&inner)
+            }
+        };
+
+        Some(HtmlTagResult { formatted, end_pos })
+    }
+
+    /// Extract an attribute value from tag content like 'img src="url"'
+    fn extract_attr(&self, tag_content: &str, attr_name: &str) -> Option<String> {
+        let lower = tag_content.to_lowercase();
+        let attr_pos = lower.find(&attr_name.to_lowercase())?;
+        let after_attr = &tag_content[attr_pos + attr_name.len()..];
+        let trimmed = after_attr.trim_start();
+
+        // Expect '='
+        if !trimmed.starts_with('=') {
+            return None;
+        }
+        let after_eq = trimmed[1..].trim_start();
+
+        // Extract quoted value
+        if let Some(rest) = after_eq.strip_prefix('"') {
+            // Double-quoted value
+            let end = rest.find('"')?;
+            Some(rest[..end].to_string())
+        } else if let Some(rest) = after_eq.strip_prefix('\'') {
+            // Single-quoted value
+            let end = rest.find('\'')?;
+            Some(rest[..end].to_string())
+        } else {
+            // Unquoted - take until whitespace or >
+            let end = after_eq
+                .find(|c: char| c.is_whitespace() || c == '>')
+                .unwrap_or(after_eq.len());
+            Some(after_eq[..end].to_string())
+        }
+    }
+
+    /// Extract href attribute value from tag content like 'a href="url"'
+    pub fn extract_href(&self, tag_content: &str) -> Option<String> {
+        self.extract_attr(tag_content, "href")
+    }
+
+    fn parse_image(&self, chars: &[char], start: usize) -> Option<ImageData> {
+        // Looking for ![alt](src) or ![alt](src "title")
+        // start points to '!'
+
+        // Must start with ![
+        if start + 1 >= chars.len() || chars[start] != '!' || chars[start + 1] != '[' {
+            return None;
+        }
+
+        // Find closing ]
+        let text_end = self.find_closing("]", chars, start + 2)?;
+
+        // Check if followed by (
+        if text_end + 1 >= chars.len() || chars[text_end + 1] != '(' {
+            return None;
+        }
+
+        // The title is parsed but not shown for images
+        let (src, _title, end_pos) = parse_inline_link_tail(chars, text_end + 1)?;
+        let alt: String = chars[start + 2..text_end].iter().collect();
+
+        Some(ImageData { alt, src, end_pos })
+    }
+
+    fn find_closing(&self, marker: &str, chars: &[char], start: usize) -> Option<usize> {
+        let marker_chars: Vec<char> = marker.chars().collect();
+        let marker_len = marker_chars.len();
+
+        // Track bracket nesting for balanced matching
+        let mut bracket_depth = 0; // for [ ]
+        let mut paren_depth = 0; // for ( )
+
+        let mut i = start;
+        while i + marker_len <= chars.len() {
+            let c = chars[i];
+
+            // Code spans bind more tightly than link brackets
+            if c == '`' && matches!(marker, "]" | ")") {
+                let run_len = chars[i..].iter().take_while(|&&c| c == '`').count();
+                i = find_code_span_end(chars, i + run_len, run_len)
+                    .map_or(i + run_len, |end| end + run_len);
+                continue;
+            }
+
+            // Check for match BEFORE updating depth
+            // This ensures we match the closing bracket at depth 0
+            let at_balanced_depth = match marker {
+                "]" => bracket_depth == 0 && c == ']',
+                ")" => paren_depth == 0 && c == ')',
+                _ => true, // For other markers, don't require balancing
+            };
+
+            if at_balanced_depth {
+                let mut matches = true;
+                for (j, &mc) in marker_chars.iter().enumerate() {
+                    if chars[i + j] != mc {
+                        matches = false;
+                        break;
+                    }
+                }
+                if matches {
+                    return Some(i);
+                }
+            }
+
+            // Update nesting depth AFTER checking for match
+            match c {
+                '[' => bracket_depth += 1,
+                ']' if bracket_depth > 0 => bracket_depth -= 1,
+                '(' => paren_depth += 1,
+                ')' if paren_depth > 0 => paren_depth -= 1,
+                _ => {}
+            }
+
+            i += 1;
+        }
+        None
+    }
+}
+
+impl Default for StreamingParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Narrows a parser's width, restoring the original width when dropped so
+/// that an early return or panic can't leave the parser at the wrong width
+struct NarrowedWidth<'a> {
+    parser: &'a mut StreamingParser,
+    width: usize,
+}
+
+impl<'a> NarrowedWidth<'a> {
+    fn new(parser: &'a mut StreamingParser, width: usize) -> Self {
+        let original = std::mem::replace(&mut parser.width, width);
+        Self {
+            parser,
+            width: original,
+        }
+    }
+}
+
+impl std::ops::Deref for NarrowedWidth<'_> {
+    type Target = StreamingParser;
+
+    fn deref(&self) -> &StreamingParser {
+        self.parser
+    }
+}
+
+impl std::ops::DerefMut for NarrowedWidth<'_> {
+    fn deref_mut(&mut self) -> &mut StreamingParser {
+        self.parser
+    }
+}
+
+impl Drop for NarrowedWidth<'_> {
+    fn drop(&mut self) {
+        self.parser.width = self.width;
+    }
+}
+
+/// Parse CSV-style data with RFC 4180 quoting: fields may be wrapped in
+/// double quotes, which can contain the delimiter, newlines, and `""` for a
+/// literal quote. Returns `None` for malformed data: an unterminated or stray
+/// quote, rows with differing field counts, or no rows at all.
+fn parse_delimited(text: &str, delimiter: char) -> Option<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = text.chars().peekable();
+    let mut in_quotes = false;
+    // Whether the current field was quoted (nothing may follow the closing quote)
+    let mut quoted = false;
+
+    while let Some(ch) = chars.next() {
+        if in_quotes {
+            match ch {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                _ => field.push(ch),
+            }
+            continue;
+        }
+
+        match ch {
+            '"' if field.trim().is_empty() && !quoted => {
+                field.clear();
+                in_quotes = true;
+                quoted = true;
+            }
+            '"' => return None,
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+                quoted = false;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            _ if ch == delimiter => {
+                record.push(std::mem::take(&mut field));
+                quoted = false;
+            }
+            // Only whitespace may follow a closing quote
+            _ if quoted && ch.is_whitespace() => {}
+            _ if quoted => return None,
+            _ => field.push(ch),
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    if quoted || !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Skip blank lines
+    records.retain(|r| !(r.len() == 1 && r[0].trim().is_empty()));
+    let columns = records.first()?.len();
+    if records.iter().any(|r| r.len() != columns) {
+        return None;
+    }
+    Some(records)
+}
+
+/// Whether a CSV value is a number, allowing a sign, thousands separators,
+/// and a trailing percent sign
+fn is_numeric_value(value: &str) -> bool {
+    let number: String = value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .chars()
+        .filter(|&c| c != ',' && c != '_')
+        .collect();
+    number.chars().any(|c| c.is_ascii_digit())
+        && number
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'))
+        && number.parse::<f64>().is_ok()
+}
+
+/// Check whether a line is a single complete open or closing HTML tag, the
+/// seventh HTML block start condition
+fn is_complete_html_tag_line(line: &str) -> bool {
+    let Some(inner) = line
+        .trim_end()
+        .strip_prefix('<')
+        .and_then(|l| l.strip_suffix('>'))
+    else {
+        return false;
+    };
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, inner),
+    };
+    let name_len = inner
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .unwrap_or(inner.len());
+    let name = inner[..name_len].to_ascii_lowercase();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+        || matches!(name.as_str(), "pre" | "script" | "style" | "textarea")
+    {
+        return false;
+    }
+
+    let mut rest = &inner[name_len..];
+    if closing {
+        return rest.trim().is_empty();
+    }
+    rest = rest.strip_suffix('/').unwrap_or(rest);
+
+    // Attributes: whitespace, a name, then optionally `=` and a value
+    loop {
+        let attr = rest.trim_start();
+        if attr.is_empty() {
+            return true;
+        }
+        if attr.len() == rest.len()
+            || !attr.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        {
+            return false;
+        }
+        let attr_len = attr
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':' | '-')))
+            .unwrap_or(attr.len());
+        rest = &attr[attr_len..];
+
+        if let Some(value) = rest.trim_start().strip_prefix('=') {
+            let value = value.trim_start();
+            let value_len = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                    Some(end) => end + 2,
+                    None => return false,
+                },
+                _ => value
+                    .find(|c: char| c.is_whitespace() || "\"'=<>`".contains(c))
+                    .unwrap_or(value.len()),
+            };
+            if value_len == 0 {
+                return false;
+            }
+            rest = &value[value_len..];
+        }
+    }
+}
+
+/// Find the next tag in lowercased HTML, starting at `from`, whose name is one
+/// of `names` (prefix a name with `/` to find its closing tag)
+fn find_html_tag<'a>(lower: &str, from: usize, names: &[&'a str]) -> Option<(usize, &'a str)> {
+    let mut pos = from;
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &lower[start + 1..];
+        for &name in names {
+            if let Some(after) = rest.strip_prefix(name) {
+                if after.is_empty()
+                    || after.starts_with(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+                {
+                    return Some((start, name));
+                }
+            }
+        }
+        pos = start + 1;
+    }
+    None
+}
+
+/// Backslash-escape characters that inline formatting would otherwise interpret,
+/// and join lines with spaces
+fn escape_inline_markdown(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' | '`' | '*' | '_' | '~' | '[' | ']' | '<' | '>' | '!' | '&' | '$' => {
+                result.push('\\');
+                result.push(ch);
+            }
+            // Table cells are a single line
+            '\n' => result.push(' '),
+            '\r' => {}
+            _ => result.push(ch),
+        }
+    }
+    result
+}
+
+/// Parse a CommonMark autolink at `start`: `<scheme:...>` or `<user@host>`.
+/// Returns the URL, the text to show and the position after the `>`.
+fn parse_angle_autolink(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let len = chars[start + 1..].iter().position(|&c| c == '>')?;
+    let end = start + 1 + len;
+    let inner: String = chars[start + 1..end].iter().collect();
+
+    // An absolute URI: a scheme of 2-32 characters, a colon, then no spaces,
+    // angle brackets or control characters
+    if let Some((scheme, _)) = inner.split_once(':') {
+        let valid_scheme = (2..=32).contains(&scheme.len())
+            && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '.' | '-'));
+        if valid_scheme && !inner.contains(|c: char| c == '<' || c == ' ' || c.is_control()) {
+            return Some((inner.clone(), inner, end + 1));
+        }
+    }
+
+    // An email address
+    let (local, domain) = inner.split_once('@')?;
+    let valid_local = !local.is_empty()
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".!#$%&'*+/=?^_`{|}~-".contains(c));
+    let valid_domain = domain.split('.').all(|label| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if valid_local && valid_domain {
+        return Some((format!("mailto:{}", inner), inner, end + 1));
+    }
+    None
+}
+
+/// Parse a GFM extended autolink at `start`: a `www.`, `http://` or
+/// `https://` URL, or an email address (optionally after `mailto:` or
+/// `xmpp:`). Returns the URL to link to and the position where the link text
+/// ends, after trailing punctuation has been left out.
+fn parse_extended_autolink(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let before = start.checked_sub(1).map(|i| chars[i]);
+    let starts_with = |prefix: &str| {
+        prefix
+            .chars()
+            .enumerate()
+            .all(|(offset, c)| chars.get(start + offset) == Some(&c))
+    };
+    let is_email_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+');
+
+    // URLs must start a line or follow whitespace or `*`, `_`, `~` or `(`
+    let url_boundary =
+        before.is_none_or(|c| c.is_whitespace() || matches!(c, '*' | '_' | '~' | '('));
+    if url_boundary {
+        let url_start = if starts_with("www.") {
+            Some((start, "http://"))
+        } else if starts_with("http://") || starts_with("https://") {
+            Some((
+                start + chars[start..].iter().position(|&c| c == '/')? + 2,
+                "",
+            ))
+        } else {
+            None
+        };
+        if let Some((domain_start, scheme)) = url_start {
+            let mut end = start;
+            while end < chars.len() && !chars[end].is_whitespace() && chars[end] != '<' {
+                end += 1;
+            }
+            let end = trim_autolink_end(chars, start, end);
+            let domain_end = (domain_start..end)
+                .find(|&i| !(chars[i].is_alphanumeric() || matches!(chars[i], '.' | '-' | '_')))
+                .unwrap_or(end);
+            if !is_valid_autolink_domain(&chars[domain_start..domain_end]) {
+                return None;
+            }
+            let text: String = chars[start..end].iter().collect();
+            return Some((format!("{}{}", scheme, text), end));
+        }
+    }
+
+    // Email addresses, optionally with a mailto: or xmpp: protocol
+    let (local_start, protocol) = match ["mailto:", "xmpp:"].into_iter().find(|p| starts_with(p)) {
+        Some(protocol) if url_boundary => (start + protocol.len(), protocol),
+        _ if before.is_none_or(|c| !is_email_char(c)) => (start, "mailto:"),
+        _ => return None,
+    };
+    let at = local_start
+        + chars[local_start..]
+            .iter()
+            .position(|&c| !is_email_char(c))?;
+    if at == local_start || chars[at] != '@' {
+        return None;
+    }
+    let mut end = at + 1;
+    while end < chars.len()
+        && (chars[end].is_ascii_alphanumeric() || matches!(chars[end], '.' | '-' | '_'))
+    {
+        end += 1;
+    }
+    // Only a period may end the address, and it isn't part of it
+    while end > at + 1 && chars[end - 1] == '.' {
+        end -= 1;
+    }
+    let domain = &chars[at + 1..end];
+    if domain.is_empty() || !domain.contains(&'.') || matches!(chars[end - 1], '-' | '_') {
+        return None;
+    }
+    let address: String = chars[local_start..end].iter().collect();
+    let url = if start == local_start {
+        format!("{}{}", protocol, address)
+    } else {
+        chars[start..end].iter().collect()
+    };
+    Some((url, end))
+}
+
+/// Leave trailing punctuation, unbalanced closing parentheses and a trailing
+/// entity reference out of an extended autolink ending at `end`
+fn trim_autolink_end(chars: &[char], start: usize, mut end: usize) -> usize {
+    while end > start {
+        match chars[end - 1] {
+            '?' | '!' | '.' | ',' | ':' | '*' | '_' | '~' => end -= 1,
+            ')' => {
+                let link = &chars[start..end];
+                let opening = link.iter().filter(|&&c| c == '(').count();
+                let closing = link.iter().filter(|&&c| c == ')').count();
+                if closing <= opening {
+                    break;
+                }
+                end -= 1;
+            }
+            ';' => {
+                let name_len = chars[start..end - 1]
+                    .iter()
+                    .rev()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .count();
+                let name_start = end - 1 - name_len;
+                if name_len == 0 || name_start == start || chars[name_start - 1] != '&' {
+                    break;
+                }
+                end = name_start - 1;
+            }
+            _ => break,
+        }
+    }
+    end
+}
+
+/// Check for a valid autolink domain: segments of alphanumerics, `_` and `-`
+/// separated by periods, with at least one period and no underscores in the
+/// last two segments
+fn is_valid_autolink_domain(domain: &[char]) -> bool {
+    let domain: String = domain.iter().collect();
+    let segments: Vec<&str> = domain.trim_end_matches('.').split('.').collect();
+    segments.len() >= 2
+        && segments.iter().all(|s| !s.is_empty())
+        && segments[segments.len() - 2..]
+            .iter()
+            .all(|s| !s.contains('_'))
+}
+
+/// Parse a link destination at `start`: either `<...>` on one line, or a
+/// nonempty run without spaces or control characters whose parentheses
+/// balance. Returns the destination with escapes and entities resolved, and
+/// the position after it.
+fn parse_link_destination(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let escaped =
+        |i: usize| chars[i] == '\\' && chars.get(i + 1).is_some_and(|&c| is_ascii_punctuation(c));
+
+    if chars.get(start) == Some(&'<') {
+        let mut i = start + 1;
+        while i < chars.len() {
+            match chars[i] {
+                '>' => return Some((unescape_link_text(&chars[start + 1..i]), i + 1)),
+                '<' | '\n' => return None,
+                _ if escaped(i) => i += 2,
+                _ => i += 1,
+            }
+        }
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            _ if escaped(i) => {
+                i += 2;
+                continue;
+            }
+            '(' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            c if c == ' ' || c.is_ascii_control() => break,
+            _ => {}
+        }
+        i += 1;
+    }
+    if i == start || depth != 0 {
+        return None;
+    }
+    Some((unescape_link_text(&chars[start..i]), i))
+}
+
+/// Parse a link title at `start`, in double quotes, single quotes or
+/// parentheses. Returns the title with escapes and entities resolved, and
+/// the position after it.
+fn parse_link_title(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let close = match chars.get(start)? {
+        '"' => '"',
+        '\'' => '\'',
+        '(' => ')',
+        _ => return None,
+    };
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if chars.get(i + 1).is_some_and(|&c| is_ascii_punctuation(c)) => i += 2,
+            c if c == close => return Some((unescape_link_text(&chars[start + 1..i]), i + 1)),
+            '(' if close == ')' => return None,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Parse the `(destination "title")` part of an inline link or image, where
+/// `open` is the position of the opening parenthesis. Returns the
+/// destination, the optional title and the position after the closing
+/// parenthesis.
+fn parse_inline_link_tail(chars: &[char], open: usize) -> Option<(String, Option<String>, usize)> {
+    let skip_whitespace = |mut i: usize| {
+        while i < chars.len() && matches!(chars[i], ' ' | '\t' | '\n') {
+            i += 1;
+        }
+        i
+    };
+
+    let mut i = skip_whitespace(open + 1);
+    let mut url = String::new();
+    if chars.get(i) != Some(&')') {
+        let (destination, end) = parse_link_destination(chars, i)?;
+        url = destination;
+        i = end;
+    }
+
+    // A title must be separated from the destination by whitespace
+    let after_destination = i;
+    i = skip_whitespace(i);
+    let mut title = None;
+    if i > after_destination {
+        if let Some((text, end)) = parse_link_title(chars, i) {
+            title = Some(text);
+            i = skip_whitespace(end);
+        }
+    }
+
+    (chars.get(i) == Some(&')')).then_some((url, title, i + 1))
+}
+
+/// Parse the destination and optional title of a link reference definition
+/// from the rest of a line. Anything but whitespace after them means the
+/// line isn't a definition.
+fn parse_link_definition_tail(text: &str) -> Option<(String, Option<String>)> {
+    let chars: Vec<char> = text.chars().collect();
+    let skip_whitespace = |mut i: usize| {
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        i
+    };
+
+    let (url, end) = parse_link_destination(&chars, skip_whitespace(0))?;
+    let i = skip_whitespace(end);
+    if i == chars.len() {
+        return Some((url, None));
+    }
+    if i == end {
+        return None;
+    }
+    let (title, end) = parse_link_title(&chars, i)?;
+    (skip_whitespace(end) == chars.len()).then_some((url, Some(title)))
+}
+
+/// Parse the destination of a link reference definition followed by the
+/// start of a title that doesn't close on this line. Returns the destination
+/// and the title text so far, from its opening delimiter.
+fn parse_link_definition_open_title(text: &str) -> Option<(String, String)> {
+    let chars: Vec<char> = text.chars().collect();
+    let start = chars.iter().take_while(|c| c.is_whitespace()).count();
+    let (url, end) = parse_link_destination(&chars, start)?;
+    let title_start = end
+        + chars[end..]
+            .iter()
+            .take_while(|c| c.is_whitespace())
+            .count();
+    if title_start == end {
+        return None;
+    }
+    let title: String = chars[title_start..].iter().collect();
+    starts_open_link_title(&title).then_some((url, title))
+}
+
+/// Whether a line starts a link title that doesn't close on the same line
+fn starts_open_link_title(line: &str) -> bool {
+    let chars: Vec<char> = line.trim_start().chars().collect();
+    matches!(chars.first(), Some('"' | '\'' | '(')) && parse_link_title(&chars, 0).is_none()
+}
+
+/// Parse a line holding nothing but a link title, as may follow a link
+/// reference definition
+fn parse_link_title_line(line: &str) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let start = chars.iter().take_while(|c| c.is_whitespace()).count();
+    let (title, end) = parse_link_title(&chars, start)?;
+    chars[end..]
+        .iter()
+        .all(|c| c.is_whitespace())
+        .then_some(title)
+}
+
+/// Resolve backslash escapes and entities in a link destination or title
+fn unescape_link_text(chars: &[char]) -> String {
+    let mut result = String::with_capacity(chars.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' && chars.get(i + 1).is_some_and(|&c| is_ascii_punctuation(c)) {
+            result.push(chars[i + 1]);
+            i += 2;
+            continue;
+        }
+        if chars[i] == '&' {
+            if let Some((decoded, consumed)) = decode_html_entity(chars, i) {
+                result.push_str(&decoded);
+                i += consumed;
+                continue;
+            }
+        }
+        result.push(chars[i]);
+        i += 1;
+    }
+    result
+}
+
+/// Whether a line opens front matter: `Some(true)` for TOML (`+++`),
+/// `Some(false)` for YAML (`---`)
+fn front_matter_fence(line: &str) -> Option<bool> {
+    match line.trim_end() {
+        "+++" => Some(true),
+        "---" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether a line is a front matter field: `key: value` in YAML, or
+/// `key = value` in TOML
+fn is_front_matter_field(line: &str, toml: bool) -> bool {
+    let separator = if toml { '=' } else { ':' };
+    let Some((key, value)) = line.trim_end().split_once(separator) else {
+        return false;
+    };
+    let key = if toml { key.trim_end() } else { key };
+    let bare_key = key.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ' '));
+    let quoted_key = key.len() >= 2
+        && ['"', '\'']
+            .iter()
+            .any(|&q| key.starts_with(q) && key.ends_with(q));
+    (bare_key || quoted_key) && (toml || value.is_empty() || value.starts_with(' '))
+}
+
+/// Whether a line can continue front matter after `lines`: a field, a
+/// comment, a blank line, or a line continuing the previous field (a nested
+/// or list line in YAML, or the rest of an array in TOML)
+fn is_front_matter_line(lines: &[String], line: &str, toml: bool) -> bool {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') || is_front_matter_field(line, toml) {
+        return true;
+    }
+    if toml {
+        let open_brackets: isize = lines
+            .iter()
+            .map(|l| l.matches('[').count() as isize - l.matches(']').count() as isize)
+            .sum();
+        let is_table = trimmed.starts_with('[') && trimmed.ends_with(']');
+        is_table || open_brackets > 0
+    } else {
+        let continues =
+            line.starts_with([' ', '\t']) || trimmed == "-" || trimmed.starts_with("- ");
+        continues && !lines.is_empty()
+    }
+}
+
+/// Read the top-level fields of YAML front matter. Lists and nested values
+/// are joined into one line, and block scalars (`|`, `>`) into one paragraph.
+fn parse_yaml_front_matter(lines: &[String]) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut separator = ", ";
+
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with([' ', '\t', '-']) {
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value.trim();
+                separator = if value.starts_with(['|', '>']) {
+                    " "
+                } else {
+                    ", "
+                };
+                let value = if separator == " " {
+                    String::new()
+                } else {
+                    front_matter_value(value)
+                };
+                fields.push((key.trim().to_string(), value));
+                continue;
+            }
+        }
+        // A list item or nested line continues the last field
+        if let Some((_, value)) = fields.last_mut() {
+            let item = trimmed.strip_prefix('-').unwrap_or(trimmed).trim();
+            if !value.is_empty() {
+                value.push_str(separator);
+            }
+            value.push_str(&front_matter_value(item));
+        }
+    }
+
+    fields
+}
+
+/// Read the fields of TOML front matter, naming those in a `[table]` as `table.key`
+fn parse_toml_front_matter(lines: &[String]) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut table = String::new();
+
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed.starts_with('[') && trimmed.ends_with(']') && !trimmed.contains('=') {
+            table = trimmed.trim_matches(['[', ']']).trim().to_string();
+            continue;
+        }
+        match trimmed.split_once('=') {
+            Some((key, value)) => {
+                let key = key.trim().trim_matches('"');
+                let key = if table.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", table, key)
+                };
+                fields.push((key, front_matter_value(value.trim())));
+            }
+            // The rest of a multi-line array
+            None => {
+                if let Some((_, value)) = fields.last_mut() {
+                    let item = front_matter_value(&format!("[{}]", trimmed.trim_end_matches(',')));
+                    if !item.is_empty() {
+                        if !value.is_empty() {
+                            value.push_str(", ");
+                        }
+                        value.push_str(&item);
+                    }
+                }
+            }
+        }
+    }
+
+    fields
+}
+
+/// Show a front matter value plainly: quotes removed, and an inline list as
+/// its comma-separated items
+fn front_matter_value(value: &str) -> String {
+    let unquote = |item: &str| {
+        let item = item.trim();
+        for quote in ['"', '\''] {
+            if item.len() >= 2 && item.starts_with(quote) && item.ends_with(quote) {
+                return item[1..item.len() - 1].to_string();
+            }
+        }
+        item.to_string()
+    };
+    let list = value.trim_start_matches('[').trim_end_matches(']');
+    if list.len() == value.len() {
+        return unquote(value);
+    }
+    list.split(',')
+        .map(unquote)
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Whether a line is a table of contents marker: `[TOC]`, `[[_TOC_]]` or
+/// `<!-- toc -->`
+fn is_toc_marker(line: &str) -> bool {
+    let line = line.trim();
+    if line.eq_ignore_ascii_case("[toc]") || line.eq_ignore_ascii_case("[[_toc_]]") {
+        return true;
+    }
+    line.strip_prefix("<!--")
+        .and_then(|rest| rest.strip_suffix("-->"))
+        .is_some_and(|inner| inner.trim().eq_ignore_ascii_case("toc"))
+}
+
+/// GitHub's anchor for a heading's text: lowercased, with punctuation other
+/// than `-` and `_` removed and each space turned into `-`. Repeated headings
+/// get `-1`, `-2`, ... appended; see [`StreamingParser::heading_anchors`].
+pub fn slugify(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            '-' | '_' => Some(c),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Find `$...$` or `$$...$$` starting at `start`, returning the LaTeX source
+/// and the position after the closing dollars. Like Pandoc, the opening `$`
+/// must not be followed by a space and the closing one must not be preceded
+/// by a space or followed by a digit, so prices stay text. Dollars touching
+/// a letter or digit on the outside don't count either, so shell variables
+/// such as `$HOME/$USER` stay text.
+fn find_inline_math(chars: &[char], start: usize) -> Option<(String, usize)> {
+    if start > 0 && chars[start - 1].is_alphanumeric() {
+        return None;
+    }
+    if chars.get(start + 1) == Some(&'$') {
+        let mut j = start + 2;
+        while j + 1 < chars.len() {
+            if chars[j] == '\\' {
+                j += 2;
+                continue;
+            }
+            if chars[j] == '$' && chars[j + 1] == '$' {
+                if j == start + 2 {
+                    return None;
+                }
+                return Some((chars[start + 2..j].iter().collect(), j + 2));
+            }
+            j += 1;
+        }
+        return None;
+    }
+
+    if chars.get(start + 1).is_none_or(|c| c.is_whitespace()) {
+        return None;
+    }
+    let mut j = start + 1;
+    while j < chars.len() {
+        match chars[j] {
+            '\\' => j += 2,
+            // A `$$` never closes single-dollar math
+            '$' if chars.get(j + 1) == Some(&'$') => j += 2,
+            '$' => {
+                if chars[j - 1].is_whitespace()
+                    || chars.get(j + 1).is_some_and(|c| c.is_alphanumeric())
+                {
+                    return None;
+                }
+                return Some((chars[start + 1..j].iter().collect(), j + 1));
+            }
+            _ => j += 1,
+        }
+    }
+    None
+}
+
+/// Parse LaTeX math into nodes
+fn parse_math(source: &str) -> Vec<MathNode> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut pos = 0;
+    let mut nodes = Vec::new();
+    // A stray closing brace is kept as text rather than ending the formula
+    while pos < chars.len() {
+        nodes.extend(parse_math_list(&chars, &mut pos, None));
+        if pos < chars.len() {
+            nodes.push(MathNode::Text(chars[pos].to_string()));
+            pos += 1;
+        }
+    }
+    nodes
+}
+
+/// Parse math up to the `stop` character (consumed) or the end of input
+fn parse_math_list(chars: &[char], pos: &mut usize, stop: Option<char>) -> Vec<MathNode> {
+    let mut nodes: Vec<MathNode> = Vec::new();
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        if Some(c) == stop {
+            *pos += 1;
+            return nodes;
+        }
+        match c {
+            '}' => return nodes,
+            '{' => {
+                *pos += 1;
+                nodes.push(MathNode::Group(parse_math_list(chars, pos, Some('}'))));
+            }
+            '^' | '_' => {
+                *pos += 1;
+                let script = parse_math_arg(chars, pos);
+                let base = nodes.pop().unwrap_or(MathNode::Text(String::new()));
+                let (base, mut sub, mut sup) = match base {
+                    MathNode::Scripts { base, sub, sup } => (base, sub, sup),
+                    base => (Box::new(base), None, None),
+                };
+                if c == '^' {
+                    sup = Some(script);
+                } else {
+                    sub = Some(script);
+                }
+                nodes.push(MathNode::Scripts { base, sub, sup });
+            }
+            '\\' => nodes.extend(parse_math_command(chars, pos)),
+            c if c.is_whitespace() => {
+                while *pos < chars.len() && chars[*pos].is_whitespace() {
+                    *pos += 1;
+                }
+                nodes.push(MathNode::Text(" ".to_string()));
+                continue;
+            }
+            _ => {
+                *pos += 1;
+                let text = match c {
+                    '-' => "−".to_string(),
+                    '*' => "∗".to_string(),
+                    '\'' => "′".to_string(),
+                    '&' | '~' => " ".to_string(),
+                    c => c.to_string(),
+                };
+                nodes.push(MathNode::Text(text));
+            }
+        }
+    }
+    nodes
+}
+
+/// Parse the argument of a command or script: a braced group, a command or
+/// a single character
+fn parse_math_arg(chars: &[char], pos: &mut usize) -> Vec<MathNode> {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+    match chars.get(*pos) {
+        Some('{') => {
+            *pos += 1;
+            parse_math_list(chars, pos, Some('}'))
+        }
+        Some('\\') => parse_math_command(chars, pos),
+        Some(&c) => {
+            *pos += 1;
+            vec![MathNode::Text(if c == '-' {
+                "−".to_string()
+            } else {
+                c.to_string()
+            })]
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Read a braced argument as raw text, for `\text{...}` and environment names
+fn read_math_raw_group(chars: &[char], pos: &mut usize) -> String {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+    if chars.get(*pos) != Some(&'{') {
+        return String::new();
+    }
+    let start = *pos + 1;
+    let mut depth = 0;
+    while *pos < chars.len() {
+        match chars[*pos] {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    *pos += 1;
+                    return chars[start..*pos - 1].iter().collect();
+                }
+            }
+            _ => {}
+        }
+        *pos += 1;
+    }
+    chars[start..].iter().collect()
+}
+
+/// Parse a command starting at the backslash at `pos`
+fn parse_math_command(chars: &[char], pos: &mut usize) -> Vec<MathNode> {
+    let start = *pos;
+    *pos += 1;
+    let Some(&first) = chars.get(*pos) else {
+        return vec![MathNode::Text("\\".to_string())];
+    };
+    let name: String = if first.is_ascii_alphabetic() {
+        let name_start = *pos;
+        while *pos < chars.len() && chars[*pos].is_ascii_alphabetic() {
+            *pos += 1;
+        }
+        chars[name_start..*pos].iter().collect()
+    } else {
+        *pos += 1;
+        first.to_string()
+    };
+    let text = |s: &str| vec![MathNode::Text(s.to_string())];
+
+    match name.as_str() {
+        "\\" => vec![MathNode::Newline],
+        "," | ":" | ";" | " " => text(" "),
+        "!" => Vec::new(),
+        "quad" => text("  "),
+        "qquad" => text("    "),
+        "{" | "}" | "$" | "%" | "&" | "#" | "_" => text(&name),
+        "|" => text("‖"),
+        "frac" | "dfrac" | "tfrac" | "cfrac" => {
+            let numerator = parse_math_arg(chars, pos);
+            let denominator = parse_math_arg(chars, pos);
+            vec![MathNode::Frac(numerator, denominator)]
+        }
+        "sqrt" => {
+            let index = (chars.get(*pos) == Some(&'[')).then(|| {
+                *pos += 1;
+                math_linear(&parse_math_list(chars, pos, Some(']')))
+            });
+            vec![MathNode::Sqrt(index, parse_math_arg(chars, pos))]
+        }
+        "text" | "textrm" | "textit" | "textbf" | "textsf" | "texttt" | "mbox" | "operatorname" => {
+            vec![MathNode::Text(read_math_raw_group(chars, pos))]
+        }
+        "mathrm" | "mathit" | "mathbf" | "mathsf" | "mathtt" | "mathcal" | "mathscr"
+        | "mathfrak" | "boldsymbol" | "bm" | "displaystyle" | "textstyle" => {
+            vec![MathNode::Group(parse_math_arg(chars, pos))]
+        }
+        "mathbb" => {
+            let letters = math_linear(&parse_math_arg(chars, pos));
+            vec![MathNode::Text(letters.chars().map(double_struck).collect())]
+        }
+        // Delimiter sizing has no meaning in a terminal
+        "left" | "right" | "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr"
+        | "biggl" | "biggr" | "Biggl" | "Biggr" => {
+            while *pos < chars.len() && chars[*pos].is_whitespace() {
+                *pos += 1;
+            }
+            if chars.get(*pos) == Some(&'.') {
+                *pos += 1;
+            }
+            Vec::new()
+        }
+        "begin" => {
+            let env = read_math_raw_group(chars, pos);
+            if is_transparent_math_environment(&env) {
+                return Vec::new();
+            }
+            let body_start = *pos;
+            let body_end = find_math_environment_end(chars, pos, &env);
+            match math_environment_delimiters(&env) {
+                Some((open, close)) => vec![MathNode::Matrix {
+                    open,
+                    close,
+                    rows: parse_math_cells(&chars[body_start..body_end]),
+                    centered: env != "cases",
+                }],
+                // Keep the whole environment as written
+                None => vec![MathNode::Unknown(chars[start..*pos].iter().collect())],
+            }
+        }
+        "end" => {
+            let env = read_math_raw_group(chars, pos);
+            if is_transparent_math_environment(&env) {
+                Vec::new()
+            } else {
+                vec![MathNode::Unknown(chars[start..*pos].iter().collect())]
+            }
+        }
+        "lim" | "limsup" | "liminf" | "max" | "min" | "sup" | "inf" | "argmax" | "argmin" => {
+            vec![MathNode::BigOp(name)]
+        }
+        "sin" | "cos" | "tan" | "cot" | "sec" | "csc" | "sinh" | "cosh" | "tanh" | "arcsin"
+        | "arccos" | "arctan" | "log" | "ln" | "lg" | "exp" | "det" | "dim" | "ker" | "deg"
+        | "arg" | "gcd" | "hom" | "Pr" | "mod" | "bmod" => text(&name),
+        "hat" | "widehat" | "bar" | "overline" | "vec" | "dot" | "ddot" | "tilde" | "widetilde" => {
+            let arg = math_linear(&parse_math_arg(chars, pos));
+            let mark = match name.as_str() {
+                "hat" | "widehat" => '\u{0302}',
+                "bar" | "overline" => '\u{0305}',
+                "vec" => '\u{20D7}',
+                "dot" => '\u{0307}',
+                "ddot" => '\u{0308}',
+                _ => '\u{0303}',
+            };
+            if arg.chars().count() == 1 || mark == '\u{0305}' {
+                vec![MathNode::Text(
+                    arg.chars().flat_map(|c| [c, mark]).collect(),
+                )]
+            } else {
+                vec![MathNode::Unknown(chars[start..*pos].iter().collect())]
+            }
+        }
+        _ => {
+            if let Some(op) = math_big_operator(&name) {
+                vec![MathNode::BigOp(op.to_string())]
+            } else if let Some(symbol) = math_symbol(&name) {
+                text(symbol)
+            } else {
+                // Keep the command's arguments with it, as written
+                let mut source = format!("\\{}", name);
+                while chars.get(*pos) == Some(&'{') {
+                    source.push_str(&format!("{{{}}}", read_math_raw_group(chars, pos)));
+                }
+                vec![MathNode::Unknown(source)]
+            }
+        }
+    }
+}
+
+/// Environments that only align their rows, which display math does anyway
+fn is_transparent_math_environment(env: &str) -> bool {
+    matches!(
+        env,
+        "aligned"
+            | "align"
+            | "align*"
+            | "gathered"
+            | "gather"
+            | "gather*"
+            | "split"
+            | "equation"
+            | "equation*"
+    )
+}
+
+/// The delimiters around a matrix environment, or `None` for an environment
+/// that is not a matrix
+fn math_environment_delimiters(env: &str) -> Option<(Option<char>, Option<char>)> {
+    Some(match env {
+        "matrix" | "smallmatrix" => (None, None),
+        "pmatrix" => (Some('('), Some(')')),
+        "bmatrix" => (Some('['), Some(']')),
+        "Bmatrix" => (Some('{'), Some('}')),
+        "vmatrix" => (Some('|'), Some('|')),
+        "Vmatrix" => (Some('‖'), Some('‖')),
+        "cases" => (Some('{'), None),
+        _ => return None,
+    })
+}
+
+/// Find the `\end` closing the environment `env` whose body starts at `pos`,
+/// skipping nested environments of the same name. Returns where the body
+/// ends and leaves `pos` after the `\end{env}`, or at the end of the input if
+/// the environment is never closed.
+fn find_math_environment_end(chars: &[char], pos: &mut usize, env: &str) -> usize {
+    let mut depth = 0;
+    while *pos < chars.len() {
+        let start = *pos;
+        if chars[start] != '\\' {
+            *pos += 1;
+            continue;
+        }
+        *pos += 1;
+        let name_start = *pos;
+        while *pos < chars.len() && chars[*pos].is_ascii_alphabetic() {
+            *pos += 1;
+        }
+        if *pos == name_start {
+            // An escaped character or `\\`
+            *pos += 1;
+            continue;
+        }
+        let name: String = chars[name_start..*pos].iter().collect();
+        if name != "begin" && name != "end" {
+            continue;
+        }
+        let mut after = *pos;
+        if read_math_raw_group(chars, &mut after) != env {
+            continue;
+        }
+        *pos = after;
+        if name == "begin" {
+            depth += 1;
+        } else if depth == 0 {
+            return start;
+        } else {
+            depth -= 1;
+        }
+    }
+    chars.len()
+}
+
+/// Split the body of a matrix environment into rows at `\\` and cells at
+/// `&`, leaving groups and nested environments whole, and parse each cell
+fn parse_math_cells(chars: &[char]) -> Vec<Vec<Vec<MathNode>>> {
+    let mut rows = vec![vec![Vec::new()]];
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let row = rows.last_mut().expect("rows is never empty");
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '&' if depth == 0 => {
+                row.push(Vec::new());
+                i += 1;
+                continue;
+            }
+            '\\' if chars.get(i + 1) == Some(&'\\') && depth == 0 => {
+                rows.push(vec![Vec::new()]);
+                i += 2;
+                continue;
+            }
+            '\\' => {
+                let name: String = chars[i + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphabetic())
+                    .collect();
+                match name.as_str() {
+                    "begin" => depth += 1,
+                    "end" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                // Keep an escaped character with its backslash
+                let len = name.chars().count().max(1).min(chars.len() - i - 1);
+                row.last_mut()
+                    .expect("a row always has a cell")
+                    .extend_from_slice(&chars[i..=i + len]);
+                i += len + 1;
+                continue;
+            }
+            _ => {}
+        }
+        row.last_mut().expect("a row always has a cell").push(c);
+        i += 1;
+    }
+
+    let rows: Vec<Vec<Vec<MathNode>>> = rows
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|cell| {
+                    let mut nodes = parse_math_list(&cell, &mut 0, None);
+                    trim_math_spaces(&mut nodes);
+                    nodes
+                })
+                .collect()
+        })
+        .collect();
+    // A trailing `\\` leaves an empty last row
+    rows.into_iter()
+        .filter(|row| row.iter().any(|cell| !cell.is_empty()))
+        .collect()
+}
+
+/// Operators whose scripts become limits above and below in display math
+fn math_big_operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "sum" => "∑",
+        "prod" => "∏",
+        "coprod" => "∐",
+        "int" => "∫",
+        "iint" => "∬",
+        "iiint" => "∭",
+        "oint" => "∮",
+        "bigcup" => "⋃",
+        "bigcap" => "⋂",
+        "bigvee" => "⋁",
+        "bigwedge" => "⋀",
+        "bigoplus" => "⨁",
+        "bigotimes" => "⨂",
+        _ => return None,
+    })
+}
+
+/// The operator of a node that takes limits above and below in display
+/// math: any big operator but an integral
+fn limits_operator(node: &MathNode) -> Option<&str> {
+    match node {
+        MathNode::BigOp(op) if !matches!(op.as_str(), "∫" | "∬" | "∭" | "∮") => Some(op),
+        _ => None,
+    }
+}
+
+/// Unicode for Greek letters, operators, relations, arrows and other symbols
+fn math_symbol(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "omicron" => "ο",
+        "pi" => "π",
+        "varpi" => "ϖ",
+        "rho" => "ρ",
+        "varrho" => "ϱ",
+        "sigma" => "σ",
+        "varsigma" => "ς",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        "times" => "×",
+        "cdot" => "⋅",
+        "div" => "÷",
+        "pm" => "±",
+        "mp" => "∓",
+        "ast" => "∗",
+        "star" => "⋆",
+        "circ" => "∘",
+        "bullet" => "∙",
+        "oplus" => "⊕",
+        "ominus" => "⊖",
+        "otimes" => "⊗",
+        "odot" => "⊙",
+        "cup" => "∪",
+        "cap" => "∩",
+        "sqcup" => "⊔",
+        "setminus" => "∖",
+        "wedge" | "land" => "∧",
+        "vee" | "lor" => "∨",
+        "neg" | "lnot" => "¬",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "leqslant" => "⩽",
+        "geqslant" => "⩾",
+        "neq" | "ne" => "≠",
+        "ll" => "≪",
+        "gg" => "≫",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "cong" => "≅",
+        "doteq" => "≐",
+        "propto" => "∝",
+        "lesssim" => "≲",
+        "gtrsim" => "≳",
+        "prec" => "≺",
+        "succ" => "≻",
+        "preceq" => "⪯",
+        "succeq" => "⪰",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "supset" => "⊃",
+        "subseteq" => "⊆",
+        "supseteq" => "⊇",
+        "subsetneq" => "⊊",
+        "supsetneq" => "⊋",
+        "perp" => "⊥",
+        "parallel" => "∥",
+        "mid" => "∣",
+        "nmid" => "∤",
+        "vdash" => "⊢",
+        "models" => "⊨",
+        "forall" => "∀",
+        "exists" => "∃",
+        "nexists" => "∄",
+        "emptyset" | "varnothing" => "∅",
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "hbar" => "ℏ",
+        "ell" => "ℓ",
+        "wp" => "℘",
+        "Re" => "ℜ",
+        "Im" => "ℑ",
+        "aleph" => "ℵ",
+        "top" => "⊤",
+        "bot" => "⊥",
+        "angle" => "∠",
+        "triangle" => "△",
+        "square" | "Box" => "□",
+        "diamond" => "⋄",
+        "dagger" => "†",
+        "ddagger" => "‡",
+        "prime" => "′",
+        "degree" => "°",
+        "therefore" => "∴",
+        "because" => "∵",
+        "ldots" | "dots" | "dotsc" | "dotsb" => "…",
+        "cdots" => "⋯",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "to" | "rightarrow" => "→",
+        "gets" | "leftarrow" => "←",
+        "leftrightarrow" => "↔",
+        "Rightarrow" => "⇒",
+        "Leftarrow" => "⇐",
+        "Leftrightarrow" | "iff" => "⇔",
+        "implies" | "Longrightarrow" => "⟹",
+        "impliedby" | "Longleftarrow" => "⟸",
+        "longrightarrow" => "⟶",
+        "longleftarrow" => "⟵",
+        "mapsto" => "↦",
+        "hookrightarrow" => "↪",
+        "uparrow" => "↑",
+        "downarrow" => "↓",
+        "nearrow" => "↗",
+        "searrow" => "↘",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "vert" | "lvert" | "rvert" => "|",
+        "Vert" | "lVert" | "rVert" => "‖",
+        "lbrace" => "{",
+        "rbrace" => "}",
+        "colon" => ":",
+        _ => return None,
+    })
+}
+
+/// Double-struck capitals for `\mathbb`
+fn double_struck(c: char) -> char {
+    match c {
+        'C' => 'ℂ',
+        'H' => 'ℍ',
+        'N' => 'ℕ',
+        'P' => 'ℙ',
+        'Q' => 'ℚ',
+        'R' => 'ℝ',
+        'Z' => 'ℤ',
+        'A'..='Z' => char::from_u32(0x1D538 + (c as u32 - 'A' as u32)).unwrap_or(c),
+        '1' => '𝟙',
+        c => c,
+    }
+}
+
+/// Unicode superscript for a character, if there is one
+fn superscript_char(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '⁰',
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '4' => '⁴',
+        '5' => '⁵',
+        '6' => '⁶',
+        '7' => '⁷',
+        '8' => '⁸',
+        '9' => '⁹',
+        '+' => '⁺',
+        '−' | '-' => '⁻',
+        '=' => '⁼',
+        '(' => '⁽',
+        ')' => '⁾',
+        'a' => 'ᵃ',
+        'b' => 'ᵇ',
+        'c' => 'ᶜ',
+        'd' => 'ᵈ',
+        'e' => 'ᵉ',
+        'f' => 'ᶠ',
+        'g' => 'ᵍ',
+        'h' => 'ʰ',
+        'i' => 'ⁱ',
+        'j' => 'ʲ',
+        'k' => 'ᵏ',
+        'l' => 'ˡ',
+        'm' => 'ᵐ',
+        'n' => 'ⁿ',
+        'o' => 'ᵒ',
+        'p' => 'ᵖ',
+        'r' => 'ʳ',
+        's' => 'ˢ',
+        't' => 'ᵗ',
+        'u' => 'ᵘ',
+        'v' => 'ᵛ',
+        'w' => 'ʷ',
+        'x' => 'ˣ',
+        'y' => 'ʸ',
+        'z' => 'ᶻ',
+        'A' => 'ᴬ',
+        'B' => 'ᴮ',
+        'D' => 'ᴰ',
+        'E' => 'ᴱ',
+        'G' => 'ᴳ',
+        'H' => 'ᴴ',
+        'I' => 'ᴵ',
+        'J' => 'ᴶ',
+        'K' => 'ᴷ',
+        'L' => 'ᴸ',
+        'M' => 'ᴹ',
+        'N' => 'ᴺ',
+        'O' => 'ᴼ',
+        'P' => 'ᴾ',
+        'R' => 'ᴿ',
+        'T' => 'ᵀ',
+        'U' => 'ᵁ',
+        'V' => 'ⱽ',
+        'W' => 'ᵂ',
+        'α' => 'ᵅ',
+        'β' => 'ᵝ',
+        'γ' => 'ᵞ',
+        'δ' => 'ᵟ',
+        'θ' => 'ᶿ',
+        'φ' | 'ϕ' => 'ᵠ',
+        'χ' => 'ᵡ',
+        '′' => '′',
+        '∗' => '*',
+        ' ' => ' ',
+        _ => return None,
+    })
+}
+
+/// Unicode subscript for a character, if there is one
+fn subscript_char(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '₀',
+        '1' => '₁',
+        '2' => '₂',
+        '3' => '₃',
+        '4' => '₄',
+        '5' => '₅',
+        '6' => '₆',
+        '7' => '₇',
+        '8' => '₈',
+        '9' => '₉',
+        '+' => '₊',
+        '−' | '-' => '₋',
+        '=' => '₌',
+        '(' => '₍',
+        ')' => '₎',
+        'a' => 'ₐ',
+        'e' => 'ₑ',
+        'h' => 'ₕ',
+        'i' => 'ᵢ',
+        'j' => 'ⱼ',
+        'k' => 'ₖ',
+        'l' => 'ₗ',
+        'm' => 'ₘ',
+        'n' => 'ₙ',
+        'o' => 'ₒ',
+        'p' => 'ₚ',
+        'r' => 'ᵣ',
+        's' => 'ₛ',
+        't' => 'ₜ',
+        'u' => 'ᵤ',
+        'v' => 'ᵥ',
+        'x' => 'ₓ',
+        'β' => 'ᵦ',
+        'γ' => 'ᵧ',
+        'ρ' => 'ᵨ',
+        'φ' | 'ϕ' => 'ᵩ',
+        'χ' => 'ᵪ',
+        ' ' => ' ',
+        _ => return None,
+    })
+}
+
+/// Convert a script to Unicode super- or subscripts, if every character has one
+fn math_script_text(text: &str, superscript: bool) -> Option<String> {
+    if text.is_empty() || text.contains('\u{001b}') {
+        return None;
+    }
+    let convert = if superscript {
+        superscript_char
+    } else {
+        subscript_char
+    };
+    text.chars().map(convert).collect()
+}
+
+/// Whether linear math reads unambiguously without parentheses around it
+fn is_simple_math(text: &str) -> bool {
+    !text.is_empty()
+        && !text.contains('\u{001b}')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '.' | '′') || is_combining_mark(c))
+}
 
-        // Extract inner content
-        let inner: String = chars[tag_end + 1..inner_end].iter().collect();
+fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}' | '\u{20D0}'..='\u{20FF}')
+}
 
-        // Format based on tag type
-        let formatted = match tag_name.as_str() {
-            "em" | "i" => {
-                let formatted_inner = self.format_inline(&inner);
-                format!("\u{001b}[3m{}\u{001b}[0m", formatted_inner)
-            }
-            "strong" | "b" => {
-                let formatted_inner = self.format_inline(&inner);
-                format!("\u{001b}[1m{}\u{001b}[0m", formatted_inner)
-            }
-            "u" => {
-                let formatted_inner = self.format_inline(&inner);
-                format!("\u{001b}[4m{}\u{001b}[0m", formatted_inner)
-            }
-            "s" | "strike" | "del" => {
-                let formatted_inner = self.format_inline(&inner);
-                format!("\u{001b}[9m{}\u{001b}[0m", formatted_inner)
-            }
-            "code" => {
-                // Inline code - don't recursively format
-                format!("\u{001b}[38;5;167;48;5;235m {} \u{001b}[0m", inner)
-            }
-            "pre" => {
-                // Code block style - dark background, no recursive formatting
-                let lines: Vec<&str> = inner.lines().collect();
-                let mut result = String::new();
-                for line in lines {
-                    result.push_str("\u{001b}[38;5;167;48;5;235m ");
-                    result.push_str(line);
-                    result.push_str(" \u{001b}[0m\n");
+/// Vulgar fraction glyphs for common numeric fractions
+fn vulgar_fraction(numerator: &str, denominator: &str) -> Option<&'static str> {
+    Some(match (numerator, denominator) {
+        ("1", "2") => "½",
+        ("1", "3") => "⅓",
+        ("2", "3") => "⅔",
+        ("1", "4") => "¼",
+        ("3", "4") => "¾",
+        ("1", "5") => "⅕",
+        ("2", "5") => "⅖",
+        ("3", "5") => "⅗",
+        ("4", "5") => "⅘",
+        ("1", "6") => "⅙",
+        ("5", "6") => "⅚",
+        ("1", "8") => "⅛",
+        ("3", "8") => "⅜",
+        ("5", "8") => "⅝",
+        ("7", "8") => "⅞",
+        _ => return None,
+    })
+}
+
+/// Wrap math in parentheses unless it is a single term
+fn math_parenthesize(text: String) -> String {
+    if is_simple_math(&text) {
+        text
+    } else {
+        format!("({})", text)
+    }
+}
+
+/// Render math on one line: scripts as Unicode super- and subscripts where
+/// possible, fractions as `a⁄b` and roots as `√x`
+fn math_linear(nodes: &[MathNode]) -> String {
+    let mut output = String::new();
+    for node in nodes {
+        match node {
+            MathNode::Text(text) | MathNode::BigOp(text) => output.push_str(text),
+            MathNode::Unknown(source) => output.push_str(&format!(
+                "\u{001b}[0m{}{}\u{001b}[0m{}",
+                MATH_UNKNOWN_STYLE, source, MATH_STYLE
+            )),
+            MathNode::Group(nodes) => output.push_str(&math_linear(nodes)),
+            MathNode::Frac(numerator, denominator) => {
+                let numerator = math_linear(numerator);
+                let denominator = math_linear(denominator);
+                match vulgar_fraction(&numerator, &denominator) {
+                    Some(glyph) => output.push_str(glyph),
+                    None => output.push_str(&format!(
+                        "{}⁄{}",
+                        math_parenthesize(numerator),
+                        math_parenthesize(denominator)
+                    )),
                 }
-                result
             }
-            "a" => {
-                // Extract href attribute
-                let href = self.extract_href(tag_content);
-                let formatted_inner = self.format_inline(&inner);
-                if let Some(url) = href {
-                    // OSC8 hyperlink format
-                    format!(
-                        "\u{001b}]8;;{}\u{001b}\\\u{001b}[34;4m{}\u{001b}[0m\u{001b}]8;;\u{001b}\\",
-                        url, formatted_inner
-                    )
-                } else {
-                    // No href, just format the inner content
-                    formatted_inner
+            MathNode::Sqrt(index, radicand) => {
+                output.push_str(&math_root_sign(index.as_deref()));
+                output.push_str(&math_parenthesize(math_linear(radicand)));
+            }
+            MathNode::Scripts { base, sub, sup } => {
+                output.push_str(&math_linear(std::slice::from_ref(base)));
+                for (script, superscript) in [(sub, false), (sup, true)] {
+                    let Some(script) = script else { continue };
+                    let text = math_linear(script);
+                    match math_script_text(&text, superscript) {
+                        Some(converted) => output.push_str(&converted),
+                        None => {
+                            output.push(if superscript { '^' } else { '_' });
+                            if text.chars().count() == 1 {
+                                output.push_str(&text);
+                            } else {
+                                output.push_str(&format!("({})", text));
+                            }
+                        }
+                    }
                 }
             }
-            _ => {
-                // Unknown tag - strip it but keep inner content
-                self.format_inline(&inner)
+            MathNode::Newline => output.push_str("  "),
+            MathNode::Matrix {
+                open, close, rows, ..
+            } => {
+                let rows: Vec<String> = rows
+                    .iter()
+                    .map(|row| {
+                        let cells: Vec<String> = row.iter().map(|cell| math_linear(cell)).collect();
+                        cells.join(" ")
+                    })
+                    .collect();
+                output.extend(open);
+                output.push_str(&rows.join("; "));
+                output.extend(close);
             }
-        };
-
-        Some(HtmlTagResult { formatted, end_pos })
+        }
     }
+    output
+}
 
-    /// Extract an attribute value from tag content like 'img src="url"'
-    fn extract_attr(&self, tag_content: &str, attr_name: &str) -> Option<String> {
-        let lower = tag_content.to_lowercase();
-        let attr_pos = lower.find(&attr_name.to_lowercase())?;
-        let after_attr = &tag_content[attr_pos + attr_name.len()..];
-        let trimmed = after_attr.trim_start();
-
-        // Expect '='
-        if !trimmed.starts_with('=') {
-            return None;
-        }
-        let after_eq = trimmed[1..].trim_start();
+/// The radical sign for a root with an optional index
+fn math_root_sign(index: Option<&str>) -> String {
+    match index {
+        None | Some("") | Some("2") => "√".to_string(),
+        Some("3") => "∛".to_string(),
+        Some("4") => "∜".to_string(),
+        Some(index) => match math_script_text(index, true) {
+            Some(index) => format!("{}√", index),
+            None => format!("({})√", index),
+        },
+    }
+}
 
-        // Extract quoted value
-        if let Some(rest) = after_eq.strip_prefix('"') {
-            // Double-quoted value
-            let end = rest.find('"')?;
-            Some(rest[..end].to_string())
-        } else if let Some(rest) = after_eq.strip_prefix('\'') {
-            // Single-quoted value
-            let end = rest.find('\'')?;
-            Some(rest[..end].to_string())
-        } else {
-            // Unquoted - take until whitespace or >
-            let end = after_eq
-                .find(|c: char| c.is_whitespace() || c == '>')
-                .unwrap_or(after_eq.len());
-            Some(after_eq[..end].to_string())
+/// Split display math into rows at `\\`
+fn math_rows(nodes: Vec<MathNode>) -> Vec<Vec<MathNode>> {
+    let mut rows = vec![Vec::new()];
+    for node in nodes {
+        match node {
+            MathNode::Newline => rows.push(Vec::new()),
+            node => rows.last_mut().expect("rows is never empty").push(node),
         }
     }
-
-    /// Extract href attribute value from tag content like 'a href="url"'
-    pub fn extract_href(&self, tag_content: &str) -> Option<String> {
-        self.extract_attr(tag_content, "href")
+    for row in &mut rows {
+        trim_math_spaces(row);
     }
+    rows.retain(|row| !row.is_empty());
+    rows
+}
 
-    fn parse_image(&self, chars: &[char], start: usize) -> Option<ImageData> {
-        // Looking for ![alt](src) or ![alt](src "title")
-        // start points to '!'
+/// Remove the spaces at either end of a row of math
+fn trim_math_spaces(nodes: &mut Vec<MathNode>) {
+    let is_space = |node: &MathNode| matches!(node, MathNode::Text(text) if text.trim().is_empty());
+    while nodes.last().is_some_and(is_space) {
+        nodes.pop();
+    }
+    let leading = nodes.iter().take_while(|node| is_space(node)).count();
+    nodes.drain(..leading);
+}
 
-        // Must start with ![
-        if start + 1 >= chars.len() || chars[start] != '!' || chars[start + 1] != '[' {
-            return None;
+impl MathBox {
+    /// A single line of already styled text, `width` columns wide
+    fn text(text: String, width: usize) -> Self {
+        MathBox {
+            lines: vec![text],
+            width,
+            baseline: 0,
         }
+    }
 
-        // Find closing ]
-        let text_end = self.find_closing("]", chars, start + 2)?;
+    fn height(&self) -> usize {
+        self.lines.len()
+    }
 
-        // Check if followed by (
-        if text_end + 1 >= chars.len() || chars[text_end + 1] != '(' {
-            return None;
+    /// Place boxes side by side, lined up on their baselines
+    fn hconcat(boxes: Vec<MathBox>) -> Self {
+        let above = boxes.iter().map(|b| b.baseline).max().unwrap_or(0);
+        let below = boxes
+            .iter()
+            .map(|b| b.height() - b.baseline - 1)
+            .max()
+            .unwrap_or(0);
+        let mut lines = vec![String::new(); above + below + 1];
+        for b in &boxes {
+            let top = above - b.baseline;
+            for (row, line) in lines.iter_mut().enumerate() {
+                match row.checked_sub(top).and_then(|i| b.lines.get(i)) {
+                    Some(text) => line.push_str(text),
+                    None => line.push_str(&" ".repeat(b.width)),
+                }
+            }
         }
+        MathBox {
+            lines,
+            width: boxes.iter().map(|b| b.width).sum(),
+            baseline: above,
+        }
+    }
 
-        // Find closing )
-        let url_end = self.find_closing(")", chars, text_end + 2)?;
-
-        // Extract alt text
-        let alt: String = chars[start + 2..text_end].iter().collect();
-
-        // Parse the content between ( and )
-        let src_content: String = chars[text_end + 2..url_end].iter().collect();
-        let src_content = src_content.trim();
+    /// Stack boxes, each centered within the widest
+    fn vstack(boxes: Vec<MathBox>, baseline: usize) -> Self {
+        let width = boxes.iter().map(|b| b.width).max().unwrap_or(0);
+        let mut lines = Vec::new();
+        for b in boxes {
+            let left = (width - b.width) / 2;
+            let right = width - b.width - left;
+            for line in b.lines {
+                lines.push(format!("{}{}{}", " ".repeat(left), line, " ".repeat(right)));
+            }
+        }
+        MathBox {
+            lines,
+            width,
+            baseline,
+        }
+    }
 
-        // Strip optional title from src (e.g., "url "title"")
-        let src = if let Some(space_pos) = src_content.find(|c: char| c.is_whitespace()) {
-            // There's whitespace, so there might be a title
-            let src_part = src_content[..space_pos].trim();
-            src_part.to_string()
+    /// Pad a box to `width` columns, centered or flush left
+    fn pad(self, width: usize, centered: bool) -> Self {
+        let left = if centered {
+            (width - self.width) / 2
         } else {
-            // No whitespace, entire content is the src
-            src_content.to_string()
+            0
         };
+        let right = width - self.width - left;
+        let lines = self
+            .lines
+            .into_iter()
+            .map(|line| format!("{}{}{}", " ".repeat(left), line, " ".repeat(right)))
+            .collect();
+        MathBox {
+            lines,
+            width,
+            baseline: self.baseline,
+        }
+    }
 
-        Some(ImageData {
-            alt,
-            src,
-            end_pos: url_end + 1,
-        })
+    /// Surround a box with parentheses as tall as it is
+    fn parenthesize(self) -> Self {
+        self.delimit(Some('('), Some(')'))
     }
 
-    fn find_closing(&self, marker: &str, chars: &[char], start: usize) -> Option<usize> {
-        let marker_chars: Vec<char> = marker.chars().collect();
-        let marker_len = marker_chars.len();
+    /// Surround a box with delimiters as tall as it is
+    fn delimit(self, open: Option<char>, close: Option<char>) -> Self {
+        let height = self.height();
+        let lines = self
+            .lines
+            .into_iter()
+            .enumerate()
+            .map(|(row, line)| {
+                let piece = |delimiter: Option<char>| {
+                    delimiter.map(|d| math_delimiter_piece(d, row, height))
+                };
+                let mut text: String = piece(open).into_iter().collect();
+                text.push_str(&line);
+                text.extend(piece(close));
+                text
+            })
+            .collect();
+        MathBox {
+            lines,
+            width: self.width + usize::from(open.is_some()) + usize::from(close.is_some()),
+            baseline: self.baseline,
+        }
+    }
+}
 
-        // Track bracket nesting for balanced matching
-        let mut bracket_depth = 0; // for [ ]
-        let mut paren_depth = 0; // for ( )
+/// The piece of a delimiter `height` lines tall to draw on line `row`
+fn math_delimiter_piece(delimiter: char, row: usize, height: usize) -> char {
+    let last = height - 1;
+    let pick = |top, middle, extension, bottom| match row {
+        0 => top,
+        r if r == last => bottom,
+        r if r == last / 2 => middle,
+        _ => extension,
+    };
+    match (delimiter, height) {
+        (delimiter, 1) => delimiter,
+        ('{', 2) => ['⎰', '⎱'][row],
+        ('}', 2) => ['⎱', '⎰'][row],
+        ('(', _) => pick('⎛', '⎜', '⎜', '⎝'),
+        (')', _) => pick('⎞', '⎟', '⎟', '⎠'),
+        ('[', _) => pick('⎡', '⎢', '⎢', '⎣'),
+        (']', _) => pick('⎤', '⎥', '⎥', '⎦'),
+        ('{', _) => pick('⎧', '⎨', '⎪', '⎩'),
+        ('}', _) => pick('⎫', '⎬', '⎪', '⎭'),
+        ('|', _) => '│',
+        (delimiter, _) => delimiter,
+    }
+}
 
-        let mut i = start;
-        while i + marker_len <= chars.len() {
-            let c = chars[i];
+/// Find the start of the backtick string closing a code span: the next run of
+/// exactly `run_len` backticks at or after `start`
+fn find_code_span_end(chars: &[char], start: usize, run_len: usize) -> Option<usize> {
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i] == '`' {
+            let len = chars[i..].iter().take_while(|&&c| c == '`').count();
+            if len == run_len {
+                return Some(i);
+            }
+            i += len;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
 
-            // Check for match BEFORE updating depth
-            // This ensures we match the closing bracket at depth 0
-            let at_balanced_depth = match marker {
-                "]" => bracket_depth == 0 && c == ']',
-                ")" => paren_depth == 0 && c == ')',
-                _ => true, // For other markers, don't require balancing
-            };
+/// Whether a delimiter run is left-flanking and right-flanking, given the
+/// characters either side of it (`None` at the start or end of the text)
+fn delimiter_flanking(before: Option<char>, after: Option<char>) -> (bool, bool) {
+    let is_space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+    let is_punct = |c: Option<char>| c.is_some_and(is_punctuation_char);
+    let left = !is_space(after) && (!is_punct(after) || is_space(before) || is_punct(before));
+    let right = !is_space(before) && (!is_punct(before) || is_space(after) || is_punct(after));
+    (left, right)
+}
 
-            if at_balanced_depth {
-                let mut matches = true;
-                for (j, &mc) in marker_chars.iter().enumerate() {
-                    if chars[i + j] != mc {
-                        matches = false;
-                        break;
-                    }
-                }
-                if matches {
-                    return Some(i);
-                }
-            }
+/// Check if a character counts as punctuation for delimiter flanking: ASCII
+/// punctuation, or any other character that is neither a letter, a digit
+/// nor whitespace
+fn is_punctuation_char(c: char) -> bool {
+    is_ascii_punctuation(c) || (!c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace())
+}
 
-            // Update nesting depth AFTER checking for match
-            match c {
-                '[' => bracket_depth += 1,
-                ']' if bracket_depth > 0 => bracket_depth -= 1,
-                '(' => paren_depth += 1,
-                ')' if paren_depth > 0 => paren_depth -= 1,
-                _ => {}
+/// Match delimiter runs into emphasis, following the CommonMark "process
+/// emphasis" procedure
+fn process_emphasis(nodes: &mut [InlineNode]) {
+    // The delimiter stack, as node indices, with a flag for removed entries
+    let stack: Vec<usize> = nodes
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| matches!(node, InlineNode::Delimiter(_)))
+        .map(|(index, _)| index)
+        .collect();
+    let mut active = vec![true; stack.len()];
+    // Lowest stack position worth searching for an opener, per closer kind
+    let mut openers_bottom: HashMap<(char, bool, usize), usize> = HashMap::new();
+
+    let run = |nodes: &[InlineNode], index: usize| -> (char, usize, usize, bool, bool) {
+        match &nodes[index] {
+            InlineNode::Delimiter(d) => (d.ch, d.count, d.original_count, d.can_open, d.can_close),
+            InlineNode::Text(_) => unreachable!("the delimiter stack only holds delimiters"),
+        }
+    };
+
+    let mut current = 0;
+    while current < stack.len() {
+        let (ch, count, original_count, closer_can_open, can_close) = run(nodes, stack[current]);
+        if !active[current] || !can_close {
+            current += 1;
+            continue;
+        }
+
+        let key = (ch, closer_can_open, original_count % 3);
+        let bottom = openers_bottom.get(&key).copied().unwrap_or(0);
+        let opener = (bottom..current).rev().find(|&pos| {
+            let (o_ch, o_count, o_original, can_open, opener_can_close) = run(nodes, stack[pos]);
+            if !active[pos] || o_ch != ch || !can_open {
+                return false;
             }
+            if ch == '~' {
+                return o_count == count;
+            }
+            // The "rule of three" for runs that can both open and close
+            !((opener_can_close || closer_can_open)
+                && (o_original + original_count) % 3 == 0
+                && !(o_original % 3 == 0 && original_count % 3 == 0))
+        });
+
+        let Some(opener) = opener else {
+            openers_bottom.insert(key, current);
+            if !closer_can_open {
+                active[current] = false;
+            }
+            current += 1;
+            continue;
+        };
 
-            i += 1;
+        let (_, opener_count, _, _, _) = run(nodes, stack[opener]);
+        let (used, emphasis) = match ch {
+            '~' => (count, Emphasis::Strikethrough),
+            _ if opener_count >= 2 && count >= 2 => (2, Emphasis::Bold),
+            _ => (1, Emphasis::Italic),
+        };
+        if let InlineNode::Delimiter(d) = &mut nodes[stack[opener]] {
+            d.count -= used;
+            d.opens.insert(0, emphasis);
+            if d.count == 0 {
+                active[opener] = false;
+            }
+        }
+        // Delimiters between the opener and closer can no longer match
+        for flag in &mut active[opener + 1..current] {
+            *flag = false;
+        }
+        if let InlineNode::Delimiter(d) = &mut nodes[stack[current]] {
+            d.count -= used;
+            d.closes.push(emphasis);
+            if d.count == 0 {
+                active[current] = false;
+                current += 1;
+            }
         }
-        None
     }
 }
 
-impl Default for StreamingParser {
-    fn default() -> Self {
-        Self::new()
+/// Render inline nodes once emphasis has been matched. Closing a style resets
+/// all attributes, so styles still open are applied again before the next
+/// output; adjacent closes share one reset.
+fn render_inline_nodes(nodes: Vec<InlineNode>) -> String {
+    let mut output = String::new();
+    let mut open: Vec<Emphasis> = Vec::new();
+    let mut needs_reset = false;
+
+    let restyle = |output: &mut String, open: &[Emphasis], needs_reset: &mut bool| {
+        if std::mem::take(needs_reset) {
+            output.push_str("\u{001b}[0m");
+            for emphasis in open {
+                output.push_str(emphasis.ansi_code());
+            }
+        }
+    };
+
+    for node in nodes {
+        match node {
+            InlineNode::Text(text) if text.is_empty() => {}
+            InlineNode::Text(text) => {
+                restyle(&mut output, &open, &mut needs_reset);
+                // Links and code spans end with a reset too
+                if open.is_empty() {
+                    output.push_str(&text);
+                } else {
+                    let codes: String = open.iter().map(|e| e.ansi_code()).collect();
+                    output.push_str(&text.replace("\u{001b}[0m", &format!("\u{001b}[0m{}", codes)));
+                }
+            }
+            InlineNode::Delimiter(run) => {
+                if !run.closes.is_empty() {
+                    open.truncate(open.len().saturating_sub(run.closes.len()));
+                    needs_reset = true;
+                }
+                if run.count > 0 || !run.opens.is_empty() {
+                    restyle(&mut output, &open, &mut needs_reset);
+                }
+                output.extend(std::iter::repeat_n(run.ch, run.count));
+                for emphasis in run.opens {
+                    output.push_str(emphasis.ansi_code());
+                    open.push(emphasis);
+                }
+            }
+        }
     }
+    restyle(&mut output, &open, &mut needs_reset);
+    output
 }
 
 /// Check if a character is ASCII punctuation (for backslash escape handling).
@@ -2931,6 +8326,139 @@ fn is_ascii_punctuation(c: char) -> bool {
     )
 }
 
+/// Percent-encode text for use in a URI, leaving only unreserved characters as-is
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Percent-encode the characters of a link destination that aren't allowed
+/// in a URI, as the spec's HTML output does: `my url` becomes `my%20url`.
+/// Reserved characters and existing `%XX` escapes are kept.
+fn encode_uri(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let mut encoded = String::with_capacity(url.len());
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escape = byte == b'%'
+            && bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit)
+            && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit);
+        if escape || byte.is_ascii_alphanumeric() || b";/?:@&=+$,-_.!~*'()#".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Half-open byte ranges within a line
+type ByteRanges = Vec<(usize, usize)>;
+
+/// Split a line into tokens for word-level diffing: runs of alphanumeric
+/// characters, runs of whitespace, or single punctuation characters.
+/// Returns (byte_start, byte_end) pairs.
+fn diff_tokens(text: &str) -> ByteRanges {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, ch)) = chars.next() {
+        let mut end = start + ch.len_utf8();
+        let same_class = |c: char| {
+            (ch.is_alphanumeric() || ch == '_') && (c.is_alphanumeric() || c == '_')
+                || ch.is_whitespace() && c.is_whitespace()
+        };
+        while let Some(&(pos, next)) = chars.peek() {
+            if !same_class(next) {
+                break;
+            }
+            end = pos + next.len_utf8();
+            chars.next();
+        }
+        tokens.push((start, end));
+    }
+
+    tokens
+}
+
+/// Find the byte ranges that differ between two lines, token by token,
+/// using the longest common subsequence of their tokens.
+fn word_diff_ranges(old: &str, new: &str) -> (ByteRanges, ByteRanges) {
+    let old_tokens = diff_tokens(old);
+    let new_tokens = diff_tokens(new);
+
+    // Skip pathological lines rather than spending quadratic time on them
+    if old_tokens.len() * new_tokens.len() > 40_000 {
+        return (Vec::new(), Vec::new());
+    }
+
+    let old_text = |i: usize| &old[old_tokens[i].0..old_tokens[i].1];
+    let new_text = |j: usize| &new[new_tokens[j].0..new_tokens[j].1];
+
+    // lcs[i][j] = LCS length of old_tokens[i..] and new_tokens[j..]
+    let (n, m) = (old_tokens.len(), new_tokens.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old_text(i) == new_text(j) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut old_changed = Vec::new();
+    let mut new_changed = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old_text(i) == new_text(j) {
+            i += 1;
+            j += 1;
+        } else if j >= m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            old_changed.push(old_tokens[i]);
+            i += 1;
+        } else {
+            new_changed.push(new_tokens[j]);
+            j += 1;
+        }
+    }
+
+    // Don't emphasize lines that changed completely
+    if old_changed.len() == n && new_changed.len() == m {
+        return (Vec::new(), Vec::new());
+    }
+
+    (old_changed, new_changed)
+}
+
+/// Decode every HTML entity in a string
+fn decode_html_entities(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] == '&' {
+            if let Some((decoded, consumed)) = decode_html_entity(&chars, i) {
+                result.push_str(&decoded);
+                i += consumed;
+                continue;
+            }
+        }
+        result.push(chars[i]);
+        i += 1;
+    }
+
+    result
+}
+
 /// Try to decode an HTML entity starting at the given position.
 /// Returns Some((decoded_string, chars_consumed)) if an entity is found, None otherwise.
 /// Supports all HTML5 named entities (&amp;, &alpha;, etc.), decimal numeric (&#123;),
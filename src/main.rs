@@ -2,6 +2,7 @@ use mdriver::StreamingParser;
 use std::env;
 use std::fs::File;
 use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::path::Path;
 
 fn print_version() {
     println!("mdriver {}", env!("CARGO_PKG_VERSION"));
@@ -22,6 +23,44 @@ fn print_help() {
     println!("    --images <PROTOCOL> Enable image rendering (protocols: kitty)");
     println!("    --width <N>         Set output width for line wrapping (default: min(terminal width, 80))");
     println!("    --color <WHEN>      When to use colors: auto, always, never (default: auto)");
+    println!("    --line-numbers      Show line numbers on all code blocks");
+    println!(
+        "    --code-style <STYLE> Code block style: plain, background, frame (default: plain)"
+    );
+    println!("    --lang-alias <A=L>  Highlight code fences tagged A as language L (repeatable)");
+    println!(
+        "    --copy-commands <MODE> Make console block commands copyable (modes: osc52, link)"
+    );
+    println!("    --table-layout <LAYOUT> Table layout: auto, grid, records");
+    println!("                        (default: auto)");
+    println!("    --table-min-width <N> Narrowest table column before auto layout uses");
+    println!("                        records (default: 6)");
+    println!("    --table-border <STYLE> Table borders: light, heavy, rounded, double, ascii,");
+    println!("                        markdown, borderless (default: light)");
+    println!("    --table-padding <N> Spaces each side of table cells (default depends on border)");
+    println!("    --zebra             Shade alternate table rows");
+    println!("    --csv-tables        Render csv and tsv code blocks as tables");
+    println!("    --stream-tables[=N] Print table rows as they arrive, sizing columns from the");
+    println!("                        header and the first N rows (default: 0)");
+    println!("    --collapse-details  Show only the summary line of <details> sections");
+    println!("    --link-titles       Show link titles, dimmed, after the link text");
+    println!("    --toc[=end]         Print a table of contents before the document (or in");
+    println!("                        place of a [TOC] marker), or after it with =end");
+    println!("    --front-matter <STYLE> Show YAML/TOML front matter as a box, hide it, or");
+    println!("                        turn off detection with off (default: box)");
+    println!("    --front-matter-title Show the front matter title as a top-level heading");
+    println!(
+        "    --no-math           Show $...$ and $$...$$ math as written instead of as Unicode"
+    );
+    println!("    --heading-style <STYLE> How headings are drawn: uniform, levels (H1 and H2");
+    println!("                        underlined, a color per level), banner (levels with a");
+    println!("                        full-width H1) (default: uniform)");
+    println!("    --no-heading-markers Hide the # markers before headings");
+    println!("    --number-headings   Number headings hierarchically: 1, 1.1, 1.1.2");
+    println!("    --section <PATH>    Show only the section under a matching heading; PATH is");
+    println!("                        text from the heading and its parents: Usage/Examples");
+    println!("                        (repeatable)");
+    println!("    --section-regex <RE> Show only the section whose heading path matches RE");
     println!();
     println!("ARGS:");
     println!("    <FILE>              Markdown file to render (reads from stdin if not provided)");
@@ -29,6 +68,10 @@ fn print_help() {
     println!("ENVIRONMENT:");
     println!("    MDRIVER_THEME       Default syntax highlighting theme (overridden by --theme)");
     println!("    MDRIVER_WIDTH       Default output width (overridden by --width)");
+    println!("    MDRIVER_TABLE_BORDER Default table border style (overridden by --table-border)");
+    println!(
+        "    MDRIVER_LANG_ALIASES Comma-separated code fence aliases, e.g. \"tpl=html,conf=ini\""
+    );
     println!();
     println!("EXAMPLES:");
     println!("    mdriver README.md");
@@ -40,6 +83,30 @@ fn print_help() {
     println!("    MDRIVER_THEME=\"InspiredGitHub\" mdriver file.md");
 }
 
+/// Parse an `ALIAS=LANGUAGE` pair for code fence language aliases
+fn parse_language_alias(arg: &str) -> Option<(String, String)> {
+    let (alias, language) = arg.split_once('=')?;
+    let (alias, language) = (alias.trim(), language.trim());
+    if alias.is_empty() || language.is_empty() {
+        return None;
+    }
+    Some((alias.to_string(), language.to_string()))
+}
+
+/// Parse a table border style name
+fn parse_table_border(name: &str) -> Option<mdriver::TableBorder> {
+    match name {
+        "light" => Some(mdriver::TableBorder::Light),
+        "heavy" => Some(mdriver::TableBorder::Heavy),
+        "rounded" => Some(mdriver::TableBorder::Rounded),
+        "double" => Some(mdriver::TableBorder::Double),
+        "ascii" => Some(mdriver::TableBorder::Ascii),
+        "markdown" => Some(mdriver::TableBorder::Markdown),
+        "borderless" => Some(mdriver::TableBorder::Borderless),
+        _ => None,
+    }
+}
+
 /// Color output mode
 #[derive(Clone, Copy, PartialEq)]
 enum ColorMode {
@@ -57,6 +124,28 @@ fn run() -> io::Result<()> {
     let mut width: Option<usize> = None;
     let mut image_protocol = mdriver::ImageProtocol::None;
     let mut color_mode = ColorMode::Auto;
+    let mut line_numbers = false;
+    let mut language_aliases: Vec<(String, String)> = Vec::new();
+    let mut command_copy = mdriver::CommandCopy::None;
+    let mut code_block_style = mdriver::CodeBlockStyle::Plain;
+    let mut table_layout = mdriver::TableLayout::Auto;
+    let mut table_min_width: Option<usize> = None;
+    let mut table_lookahead: Option<usize> = None;
+    let mut csv_tables = false;
+    let mut table_border: Option<mdriver::TableBorder> = None;
+    let mut table_padding: Option<usize> = None;
+    let mut zebra = false;
+    let mut collapse_details = false;
+    let mut link_titles = false;
+    let mut toc: Option<mdriver::TocPosition> = None;
+    let mut sections: Vec<String> = Vec::new();
+    let mut heading_style = mdriver::HeadingStyle::Uniform;
+    let mut front_matter_style = mdriver::FrontMatterStyle::Box;
+    let mut front_matter_title = false;
+    let mut math = true;
+    let mut heading_markers = true;
+    let mut number_headings = false;
+    let mut section_regexes: Vec<String> = Vec::new();
     let mut file_path: Option<String> = None;
     let mut i = 1;
 
@@ -81,6 +170,35 @@ fn run() -> io::Result<()> {
             continue;
         }
 
+        // Handle --stream-tables=N syntax
+        if let Some(value) = args[i].strip_prefix("--stream-tables=") {
+            match value.parse::<usize>() {
+                Ok(n) => table_lookahead = Some(n),
+                Err(_) => {
+                    eprintln!("Error: --stream-tables requires a number of rows");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            i += 1;
+            continue;
+        }
+
+        // Handle --toc=start|end syntax
+        if let Some(value) = args[i].strip_prefix("--toc=") {
+            toc = match value {
+                "start" => Some(mdriver::TocPosition::Start),
+                "end" => Some(mdriver::TocPosition::End),
+                _ => {
+                    eprintln!("Error: --toc must be 'start' or 'end'");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            };
+            i += 1;
+            continue;
+        }
+
         match args[i].as_str() {
             "--version" | "-V" => {
                 print_version();
@@ -166,6 +284,217 @@ fn run() -> io::Result<()> {
                     std::process::exit(1);
                 }
             }
+            "--line-numbers" => {
+                line_numbers = true;
+                i += 1;
+            }
+            "--lang-alias" => match args.get(i + 1).and_then(|arg| parse_language_alias(arg)) {
+                Some(alias) => {
+                    language_aliases.push(alias);
+                    i += 2;
+                }
+                None => {
+                    eprintln!("Error: --lang-alias requires an ALIAS=LANGUAGE pair");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            },
+            "--code-style" => {
+                if i + 1 < args.len() {
+                    match args[i + 1].as_str() {
+                        "plain" => code_block_style = mdriver::CodeBlockStyle::Plain,
+                        "background" => code_block_style = mdriver::CodeBlockStyle::Background,
+                        "frame" => code_block_style = mdriver::CodeBlockStyle::Frame,
+                        style => {
+                            eprintln!("Error: Unknown code style '{}'", style);
+                            eprintln!("Supported styles: plain, background, frame");
+                            eprintln!("Run 'mdriver --help' for usage information");
+                            std::process::exit(1);
+                        }
+                    }
+                    i += 2;
+                } else {
+                    eprintln!("Error: --code-style requires a style (plain, background, frame)");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            "--copy-commands" => {
+                if i + 1 < args.len() {
+                    match args[i + 1].as_str() {
+                        "osc52" => command_copy = mdriver::CommandCopy::Osc52,
+                        "link" => command_copy = mdriver::CommandCopy::Link,
+                        mode => {
+                            eprintln!("Error: Unknown copy mode '{}'", mode);
+                            eprintln!("Supported modes: osc52, link");
+                            eprintln!("Run 'mdriver --help' for usage information");
+                            std::process::exit(1);
+                        }
+                    }
+                    i += 2;
+                } else {
+                    eprintln!("Error: --copy-commands requires a mode (osc52, link)");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            "--front-matter" => {
+                if i + 1 < args.len() {
+                    match args[i + 1].as_str() {
+                        "box" => front_matter_style = mdriver::FrontMatterStyle::Box,
+                        "hide" => front_matter_style = mdriver::FrontMatterStyle::Hide,
+                        "off" => front_matter_style = mdriver::FrontMatterStyle::Off,
+                        style => {
+                            eprintln!("Error: Unknown front matter style '{}'", style);
+                            eprintln!("Supported styles: box, hide, off");
+                            eprintln!("Run 'mdriver --help' for usage information");
+                            std::process::exit(1);
+                        }
+                    }
+                    i += 2;
+                } else {
+                    eprintln!("Error: --front-matter requires a style (box, hide, off)");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            "--front-matter-title" => {
+                front_matter_title = true;
+                i += 1;
+            }
+            "--no-math" => {
+                math = false;
+                i += 1;
+            }
+            "--heading-style" => {
+                if i + 1 < args.len() {
+                    match args[i + 1].as_str() {
+                        "uniform" => heading_style = mdriver::HeadingStyle::Uniform,
+                        "levels" => heading_style = mdriver::HeadingStyle::Levels,
+                        "banner" => heading_style = mdriver::HeadingStyle::Banner,
+                        style => {
+                            eprintln!("Error: Unknown heading style '{}'", style);
+                            eprintln!("Supported styles: uniform, levels, banner");
+                            eprintln!("Run 'mdriver --help' for usage information");
+                            std::process::exit(1);
+                        }
+                    }
+                    i += 2;
+                } else {
+                    eprintln!("Error: --heading-style requires a style (uniform, levels, banner)");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            "--no-heading-markers" => {
+                heading_markers = false;
+                i += 1;
+            }
+            "--number-headings" => {
+                number_headings = true;
+                i += 1;
+            }
+            "--table-layout" => {
+                if i + 1 < args.len() {
+                    match args[i + 1].as_str() {
+                        "auto" => table_layout = mdriver::TableLayout::Auto,
+                        "grid" => table_layout = mdriver::TableLayout::Grid,
+                        "records" => table_layout = mdriver::TableLayout::Records,
+                        layout => {
+                            eprintln!("Error: Unknown table layout '{}'", layout);
+                            eprintln!("Supported layouts: auto, grid, records");
+                            eprintln!("Run 'mdriver --help' for usage information");
+                            std::process::exit(1);
+                        }
+                    }
+                    i += 2;
+                } else {
+                    eprintln!("Error: --table-layout requires a layout (auto, grid, records)");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            "--table-min-width" => match args.get(i + 1).map(|arg| arg.parse::<usize>()) {
+                Some(Ok(w)) if w > 0 => {
+                    table_min_width = Some(w);
+                    i += 2;
+                }
+                _ => {
+                    eprintln!("Error: --table-min-width requires a positive integer");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            },
+            "--table-border" => {
+                if i + 1 < args.len() {
+                    match parse_table_border(&args[i + 1]) {
+                        Some(border) => table_border = Some(border),
+                        None => {
+                            eprintln!("Error: Unknown table border '{}'", args[i + 1]);
+                            eprintln!(
+                                "Supported borders: light, heavy, rounded, double, ascii, markdown, borderless"
+                            );
+                            eprintln!("Run 'mdriver --help' for usage information");
+                            std::process::exit(1);
+                        }
+                    }
+                    i += 2;
+                } else {
+                    eprintln!("Error: --table-border requires a border style");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            }
+            "--table-padding" => match args.get(i + 1).map(|arg| arg.parse::<usize>()) {
+                Some(Ok(n)) => {
+                    table_padding = Some(n);
+                    i += 2;
+                }
+                _ => {
+                    eprintln!("Error: --table-padding requires a number");
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            },
+            "--zebra" => {
+                zebra = true;
+                i += 1;
+            }
+            "--csv-tables" => {
+                csv_tables = true;
+                i += 1;
+            }
+            "--stream-tables" => {
+                table_lookahead = Some(0);
+                i += 1;
+            }
+            "--collapse-details" => {
+                collapse_details = true;
+                i += 1;
+            }
+            "--link-titles" => {
+                link_titles = true;
+                i += 1;
+            }
+            "--toc" => {
+                toc = Some(mdriver::TocPosition::Start);
+                i += 1;
+            }
+            "--section" | "--section-regex" => match args.get(i + 1) {
+                Some(value) => {
+                    if args[i] == "--section" {
+                        sections.push(value.clone());
+                    } else {
+                        section_regexes.push(value.clone());
+                    }
+                    i += 2;
+                }
+                None => {
+                    eprintln!("Error: {} requires a value", args[i]);
+                    eprintln!("Run 'mdriver --help' for usage information");
+                    std::process::exit(1);
+                }
+            },
             arg if !arg.starts_with('-') => {
                 file_path = Some(arg.to_string());
                 i += 1;
@@ -185,10 +514,24 @@ fn run() -> io::Result<()> {
         ColorMode::Auto => io::stdout().is_terminal(),
     };
 
+    // Without colors the input is copied as-is, so there is nothing to list
+    if !use_color && toc.is_some() {
+        eprintln!("Error: --toc needs formatted output");
+        eprintln!("Use --color=always when output is not a terminal");
+        std::process::exit(1);
+    }
+
+    // Without colors the input is copied as-is, so nothing can be extracted
+    if !use_color && (!sections.is_empty() || !section_regexes.is_empty()) {
+        eprintln!("Error: --section and --section-regex need formatted output");
+        eprintln!("Use --color=always when output is not a terminal");
+        std::process::exit(1);
+    }
+
     let mut buffer = [0u8; 4096];
 
     // Read from file or stdin
-    let mut reader: Box<dyn Read> = if let Some(path) = file_path {
+    let mut reader: Box<dyn Read> = if let Some(path) = &file_path {
         Box::new(File::open(path)?)
     } else {
         Box::new(io::stdin())
@@ -210,6 +553,60 @@ fn run() -> io::Result<()> {
         } else {
             StreamingParser::with_theme(&theme, image_protocol)
         };
+        parser.set_line_numbers(line_numbers);
+        parser.set_command_copy(command_copy);
+        parser.set_code_block_style(code_block_style);
+        parser.set_table_layout(table_layout);
+        parser.set_table_streaming(table_lookahead);
+        parser.set_csv_tables(csv_tables);
+        parser.set_table_zebra(zebra);
+        parser.set_collapse_details(collapse_details);
+        parser.set_link_titles(link_titles);
+        parser.set_toc(toc);
+        parser.set_heading_style(heading_style);
+        parser.set_front_matter_style(front_matter_style);
+        parser.set_front_matter_title(front_matter_title);
+        parser.set_math(math);
+        parser.set_heading_markers(heading_markers);
+        parser.set_number_headings(number_headings);
+        for section in &sections {
+            parser.add_section(section);
+        }
+        for pattern in &section_regexes {
+            if let Err(e) = parser.add_section_regex(pattern) {
+                eprintln!("Error: Invalid --section-regex '{}': {}", pattern, e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(path) = &file_path {
+            parser.set_document_path(Path::new(path));
+        }
+        if let Some(padding) = table_padding {
+            parser.set_table_padding(padding);
+        }
+
+        // Get table border from parameter or environment variable
+        let table_border = table_border.or_else(|| {
+            env::var("MDRIVER_TABLE_BORDER")
+                .ok()
+                .and_then(|name| parse_table_border(&name))
+        });
+        if let Some(border) = table_border {
+            parser.set_table_border(border);
+        }
+        if let Some(w) = table_min_width {
+            parser.set_table_min_column_width(w);
+        }
+
+        // Aliases from the environment first, so --lang-alias can override them
+        let env_aliases = env::var("MDRIVER_LANG_ALIASES").unwrap_or_default();
+        for (alias, language) in env_aliases
+            .split(',')
+            .filter_map(parse_language_alias)
+            .chain(language_aliases)
+        {
+            parser.add_language_alias(&alias, &language);
+        }
 
         // Read and process in chunks with markdown formatting
         loop {
@@ -221,6 +618,11 @@ fn run() -> io::Result<()> {
             let chunk = String::from_utf8_lossy(&buffer[..bytes_read]);
             let output = parser.feed(&chunk);
             write!(stdout, "{}", output)?;
+
+            // Stop reading once the requested sections have been shown
+            if parser.sections_complete() {
+                break;
+            }
         }
 
         // Flush any remaining buffered content
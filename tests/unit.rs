@@ -6,6 +6,20 @@ fn parser() -> StreamingParser {
     StreamingParser::new()
 }
 
+fn parser_with_width(width: usize) -> StreamingParser {
+    StreamingParser::with_width("base16-ocean.dark", ImageProtocol::None, width)
+}
+
+/// Feed a whole document to the parser and flush it
+fn render(p: &mut StreamingParser, input: &str) -> String {
+    p.feed(input) + &p.flush()
+}
+
+/// Render a whole document and strip the ANSI codes from the result
+fn render_plain(p: &mut StreamingParser, input: &str) -> String {
+    strip_ansi(&render(p, input))
+}
+
 /// Strip ANSI codes for easier assertion in tests.
 /// Handles both CSI sequences (\x1b[...m) and OSC sequences (\x1b]...\\)
 fn strip_ansi(text: &str) -> String {
@@ -342,10 +356,6 @@ mod strip_ansi_tests {
 mod wrap_text_tests {
     use super::*;
 
-    fn parser_with_width(width: usize) -> StreamingParser {
-        StreamingParser::with_width("base16-ocean.dark", ImageProtocol::None, width)
-    }
-
     #[test]
     fn test_wrap_plain_text() {
         let p = parser_with_width(40);
@@ -1047,7 +1057,7 @@ mod reference_links {
         let _ = p.feed("[example]: <https://example.com/path with spaces>\n\n");
         let result = p.feed("Visit [example].\n\n");
         // Should be a hyperlink with the URL
-        assert!(result.contains("https://example.com/path with spaces"));
+        assert!(result.contains("https://example.com/path%20with%20spaces"));
     }
 
     // Test that link definitions don't emit content
@@ -1102,10 +1112,6 @@ mod markdown_image_tests {
 mod wrap_image_tests {
     use super::*;
 
-    fn parser_with_width(width: usize) -> StreamingParser {
-        StreamingParser::with_width("base16-ocean.dark", ImageProtocol::None, width)
-    }
-
     #[test]
     fn test_image_markdown_not_broken_by_wrapping() {
         // Image markdown should be kept as a single unit and not broken across lines
@@ -1289,7 +1295,7 @@ mod unicode_width {
         let mut positions = Vec::new();
         let mut visual_col = 0;
 
-        for c in line.chars() {
+        for c in super::strip_ansi(line).chars() {
             if c == '│' {
                 positions.push(visual_col);
             }
@@ -1430,3 +1436,2878 @@ mod backslash_escapes {
         assert_eq!(strip_ansi(&output), "text\\");
     }
 }
+
+mod code_block_attributes {
+    use super::*;
+
+    #[test]
+    fn test_title_rendered_as_header() {
+        let mut p = parser_with_width(80);
+        let output = render(&mut p, "```rust title=\"main.rs\"\nfn main() {}\n```\n");
+        let stripped = strip_ansi(&output);
+        let lines: Vec<&str> = stripped.lines().collect();
+        assert_eq!(lines[0], "    main.rs");
+        assert_eq!(lines[1], "    ───────");
+        assert_eq!(lines[2], "    fn main() {}");
+    }
+
+    #[test]
+    fn test_linenos_attribute() {
+        let mut p = parser_with_width(80);
+        let input = "```python linenos\na = 1\nb = 2\n```\n";
+        let stripped = strip_ansi(&render(&mut p, input));
+        assert!(stripped.contains("1 │ a = 1\n"));
+        assert!(stripped.contains("2 │ b = 2\n"));
+    }
+
+    #[test]
+    fn test_line_numbers_setting_applies_to_all_blocks() {
+        let mut p = parser_with_width(80);
+        p.set_line_numbers(true);
+        let input = "```\n".to_string() + &"x\n".repeat(10) + "```\n";
+        let stripped = strip_ansi(&render(&mut p, &input));
+        // Numbers are right-aligned to the widest line number
+        assert!(stripped.contains(" 1 │ x\n"));
+        assert!(stripped.contains("10 │ x\n"));
+    }
+
+    #[test]
+    fn test_highlighted_lines_get_background() {
+        let mut p = parser_with_width(80);
+        let output = render(&mut p, "```text {2}\none\ntwo\nthree\n```\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(!lines[0].contains("\x1b[48;"));
+        assert!(lines[1].contains("\x1b[48;"));
+        assert!(!lines[2].contains("\x1b[48;"));
+        // Highlighted rows are padded to the full width
+        assert_eq!(strip_ansi(lines[1]).chars().count(), 80);
+    }
+
+    #[test]
+    fn test_highlight_ranges_attached_to_language() {
+        let mut p = parser_with_width(80);
+        let output = render(&mut p, "```js{1,3-4}\na\nb\nc\nd\n```\n");
+        let highlighted: Vec<bool> = output
+            .lines()
+            .take(4)
+            .map(|l| l.contains("\x1b[48;"))
+            .collect();
+        assert_eq!(highlighted, vec![true, false, true, true]);
+    }
+
+    #[test]
+    fn test_long_lines_wrap_with_continuation_marker() {
+        let mut p = parser_with_width(30);
+        let long_line = "x".repeat(60);
+        let output = render(&mut p, &format!("```\n{}\n```\n", long_line));
+        let stripped = strip_ansi(&output);
+        let lines: Vec<&str> = stripped.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("    x"));
+        assert!(lines[1].starts_with("  ↪ x"));
+        for line in &lines {
+            assert!(line.chars().count() <= 30);
+        }
+    }
+}
+
+mod diff_blocks {
+    use super::*;
+
+    const ADDED_BG: &str = "\x1b[48;2;24;60;32m";
+    const REMOVED_BG: &str = "\x1b[48;2;72;24;28m";
+
+    #[test]
+    fn test_added_and_removed_lines_get_backgrounds() {
+        let output = render(
+            &mut parser_with_width(80),
+            "```diff\n context\n-old line\n+new line\n```\n",
+        );
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(!lines[0].contains("\x1b[48;"));
+        assert!(lines[1].contains(REMOVED_BG));
+        assert!(lines[2].contains(ADDED_BG));
+        // Rows are padded to the full width
+        assert_eq!(strip_ansi(lines[1]).chars().count(), 80);
+        assert_eq!(strip_ansi(lines[1]).trim_end(), "    -old line");
+    }
+
+    #[test]
+    fn test_hunk_header_styled() {
+        let output = render(
+            &mut parser_with_width(80),
+            "```patch\n@@ -1,2 +1,2 @@\n-a\n+b\n```\n",
+        );
+        assert!(output.contains("\x1b[36m@@ -1,2 +1,2 @@"));
+    }
+
+    #[test]
+    fn test_dashes_inside_hunk_are_content() {
+        // "--- x" inside a hunk is a removed line, not a file header
+        let output = render(
+            &mut parser_with_width(80),
+            "```diff\n@@ -1,2 +1,1 @@\n--- x\n-y\n+z\n```\n",
+        );
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[1].contains(REMOVED_BG));
+        assert!(!lines[1].contains("\x1b[1m"));
+    }
+
+    #[test]
+    fn test_language_from_file_header() {
+        let with_header = render(&mut parser_with_width(80), "```diff\n--- a/main.rs\n+++ b/main.rs\n@@ -1 +1 @@\n-fn main() {}\n+fn main() { }\n```\n");
+        let without_header = render(
+            &mut parser_with_width(80),
+            "```diff\n@@ -1 +1 @@\n-fn main() {}\n+fn main() { }\n```\n",
+        );
+        let removed_with = with_header.lines().nth(3).unwrap();
+        let removed_without = without_header.lines().nth(1).unwrap();
+        assert_eq!(strip_ansi(removed_with), strip_ansi(removed_without));
+        // Rust highlighting colors `fn` differently from plain text
+        assert_ne!(removed_with, removed_without);
+    }
+
+    #[test]
+    fn test_word_level_emphasis() {
+        let output = render(
+            &mut parser_with_width(80),
+            "```diff\n-let x = 1;\n+let x = 2;\n```\n",
+        );
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].contains("\x1b[48;2;130;40;46m1"));
+        assert!(lines[1].contains("\x1b[48;2;40;110;55m2"));
+        assert!(!lines[1].contains("\x1b[48;2;40;110;55mlet"));
+    }
+
+    #[test]
+    fn test_unpaired_changes_have_no_word_emphasis() {
+        let output = render(&mut parser_with_width(80), "```diff\n-a b\n+a c\n+d\n```\n");
+        assert!(!output.contains("\x1b[48;2;130;40;46m"));
+        assert!(!output.contains("\x1b[48;2;40;110;55m"));
+    }
+}
+
+mod code_language_detection {
+    use super::*;
+
+    fn fenced(info: &str, code: &str) -> String {
+        render(&mut parser(), &format!("```{}\n{}\n```\n", info, code))
+    }
+
+    #[test]
+    fn test_builtin_aliases() {
+        assert_eq!(
+            fenced("py3", "def f(): pass"),
+            fenced("python", "def f(): pass")
+        );
+        assert_eq!(fenced("shell", "ls -la"), fenced("bash", "ls -la"));
+        assert_eq!(fenced("k8s-yaml", "a: 1"), fenced("yaml", "a: 1"));
+        assert_eq!(
+            fenced("JSX", "let a = 1;"),
+            fenced("javascript", "let a = 1;")
+        );
+    }
+
+    #[test]
+    fn test_custom_alias_overrides() {
+        let mut p = parser();
+        p.add_language_alias("tpl", "html");
+        let aliased = render(&mut p, "```tpl\n<b>x</b>\n```\n");
+        assert_eq!(aliased, fenced("html", "<b>x</b>"));
+
+        // User aliases replace built-in ones
+        let mut p = parser();
+        p.add_language_alias("console", "python");
+        let aliased = render(&mut p, "```console\ndef f(): pass\n```\n");
+        assert_eq!(aliased, fenced("python", "def f(): pass"));
+    }
+
+    #[test]
+    fn test_file_path_and_name_as_language() {
+        assert_eq!(
+            fenced("path/to/file.rs", "fn x() {}"),
+            fenced("rust", "fn x() {}")
+        );
+        assert_eq!(
+            fenced("Dockerfile", "FROM alpine"),
+            fenced("dockerfile", "FROM alpine")
+        );
+        assert_ne!(
+            fenced("Dockerfile", "FROM alpine"),
+            fenced("text", "FROM alpine")
+        );
+    }
+
+    #[test]
+    fn test_title_file_name_used_when_no_language() {
+        assert_eq!(
+            strip_ansi(&fenced("title=\"lib.rs\"", "fn x() {}")),
+            strip_ansi(&fenced("rust title=\"lib.rs\"", "fn x() {}"))
+        );
+        assert_eq!(
+            fenced("title=\"lib.rs\"", "fn x() {}"),
+            fenced("rust title=\"lib.rs\"", "fn x() {}")
+        );
+    }
+
+    #[test]
+    fn test_shebang_detection() {
+        let code = "#!/usr/bin/env python\ndef f(): pass";
+        assert_eq!(fenced("", code), fenced("python", code));
+        assert_ne!(fenced("", code), fenced("text", code));
+    }
+
+    #[test]
+    fn test_shebang_detection_in_indented_code() {
+        let indented = render(&mut parser(), "    #!/bin/bash\n    echo hi\n\n");
+        assert_eq!(indented, fenced("bash", "#!/bin/bash\necho hi"));
+    }
+}
+
+mod shell_sessions {
+    use super::*;
+    use mdriver::CommandCopy;
+
+    #[test]
+    fn test_prompt_dimmed_and_output_plain() {
+        let output = render(&mut parser(), "```console\n$ ls -la\ntotal 0\n```\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("    \x1b[2m$ \x1b[22m"));
+        assert_eq!(lines[1], "    \x1b[39mtotal 0");
+        assert_eq!(strip_ansi(&output), "    $ ls -la\n    total 0\n\n");
+    }
+
+    #[test]
+    fn test_command_highlighted_as_bash() {
+        let session = render(&mut parser(), "```console\n$ echo hi\n```\n");
+        let bash = render(&mut parser(), "```bash\necho hi\n```\n");
+        let command = session.lines().next().unwrap();
+        let highlighted = bash.lines().next().unwrap().trim_start_matches("    ");
+        assert!(command.ends_with(highlighted));
+    }
+
+    #[test]
+    fn test_session_aliases_and_user_prompts() {
+        let output = render(
+            &mut parser(),
+            "```shell-session\nme@box:~/src$ make\nok\n```\n",
+        );
+        assert!(output.contains("\x1b[2mme@box:~/src$ \x1b[22m"));
+        assert!(output.contains("\x1b[39mok"));
+    }
+
+    #[test]
+    fn test_bracketed_prompt_prefix() {
+        let output = render(&mut parser(), "```console\n[me@box src]$ make\n```\n");
+        assert!(output.contains("\x1b[2m[me@box src]$ \x1b[22m"));
+    }
+
+    #[test]
+    fn test_output_with_marker_characters_is_not_a_prompt() {
+        let output = render(
+            &mut parser(),
+            "```console\n$ make\n100% built\nerror> bad\n```\n",
+        );
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[1], "    \x1b[39m100% built");
+        assert_eq!(lines[2], "    \x1b[39merror> bad");
+    }
+
+    #[test]
+    fn test_output_lines_are_not_copied() {
+        let mut p = parser();
+        p.set_command_copy(CommandCopy::Osc52);
+        let output = render(&mut p, "```console\n$ ls\n100% built\nerror> bad\n```\n");
+        // base64("ls")
+        assert!(output.contains("\x1b]52;c;bHM=\x07"));
+    }
+
+    #[test]
+    fn test_line_continuation_is_part_of_command() {
+        let output = render(&mut parser(), "```console\n$ echo a \\\n  b\na b\n```\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(!lines[1].contains("\x1b[39m"));
+        assert!(lines[2].contains("\x1b[39ma b"));
+    }
+
+    #[test]
+    fn test_osc52_copies_commands() {
+        let mut p = parser();
+        p.set_command_copy(CommandCopy::Osc52);
+        let output = render(&mut p, "```console\n$ ls\nfile\n# make install\n```\n");
+        // base64("ls\nmake install")
+        assert!(output.contains("\x1b]52;c;bHMKbWFrZSBpbnN0YWxs\x07"));
+    }
+
+    #[test]
+    fn test_link_copy_mode() {
+        let mut p = parser();
+        p.set_command_copy(CommandCopy::Link);
+        let output = render(&mut p, "```console\n$ git status\n```\n");
+        assert!(output.contains("\x1b]8;;data:text/plain;charset=utf-8,git%20status\x1b\\"));
+        assert_eq!(strip_ansi(&output), "    $ git status\n\n");
+    }
+
+    #[test]
+    fn test_no_copy_sequences_by_default() {
+        let output = render(&mut parser(), "```console\n$ ls\n```\n");
+        assert!(!output.contains("\x1b]52;"));
+        assert!(!output.contains("\x1b]8;;"));
+    }
+}
+
+mod code_block_styles {
+    use super::*;
+    use mdriver::CodeBlockStyle;
+
+    fn render_styled(style: CodeBlockStyle, input: &str) -> String {
+        let mut p = parser_with_width(40);
+        p.set_code_block_style(style);
+        render(&mut p, input)
+    }
+
+    fn visible_widths(output: &str) -> Vec<usize> {
+        strip_ansi(output)
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.chars().count())
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_with_language_label() {
+        let output = render_styled(CodeBlockStyle::Frame, "```rust\nfn main() {}\n```\n");
+        let stripped = strip_ansi(&output);
+        let lines: Vec<&str> = stripped.lines().collect();
+        assert!(lines[0].starts_with("╭─ rust ─"));
+        assert!(lines[0].ends_with('╮'));
+        assert!(lines[1].starts_with("│     fn main() {}"));
+        assert!(lines[1].ends_with(" │"));
+        assert!(lines[2].starts_with('╰'));
+        assert_eq!(visible_widths(&output), vec![40, 40, 40]);
+    }
+
+    #[test]
+    fn test_frame_uses_title_as_label() {
+        let output = render_styled(
+            CodeBlockStyle::Frame,
+            "```rust title=\"main.rs\"\nfn main() {}\n```\n",
+        );
+        let stripped = strip_ansi(&output);
+        assert!(stripped.starts_with("╭─ main.rs ─"));
+        // The title is not repeated above the frame
+        assert_eq!(stripped.matches("main.rs").count(), 1);
+    }
+
+    #[test]
+    fn test_frame_around_indented_code() {
+        let output = render_styled(CodeBlockStyle::Frame, "    let x = 1;\n\n");
+        let stripped = strip_ansi(&output);
+        assert!(stripped.starts_with("╭──"));
+        assert_eq!(visible_widths(&output), vec![40, 40, 40]);
+    }
+
+    #[test]
+    fn test_background_pads_every_line() {
+        let output = render_styled(CodeBlockStyle::Background, "```\na\nlonger line\n```\n");
+        // Padding row, two code rows, padding row
+        assert_eq!(visible_widths(&output)[..], [40, 40, 40, 40]);
+        for line in output.lines().take(4) {
+            assert!(line.starts_with("\x1b[48;2;"));
+        }
+    }
+
+    #[test]
+    fn test_plain_style_is_default() {
+        let mut p = parser_with_width(40);
+        let default_output = p.feed("```\na\n```\n");
+        assert_eq!(
+            default_output,
+            render_styled(CodeBlockStyle::Plain, "```\na\n```\n")
+        );
+        assert!(!default_output.contains("\x1b[48;"));
+    }
+}
+
+mod pre_blocks {
+    use super::*;
+
+    #[test]
+    fn test_pre_block_rendered_as_code() {
+        let pre = render(&mut parser(), "<pre>\nfn main() {}\n</pre>\n");
+        let fenced = render(&mut parser(), "```\nfn main() {}\n```\n");
+        assert_eq!(pre, fenced);
+    }
+
+    #[test]
+    fn test_pre_code_language_class() {
+        let pre = render(
+            &mut parser(),
+            "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>\n",
+        );
+        let fenced = render(&mut parser(), "```rust\nfn main() {}\n```\n");
+        assert_eq!(pre, fenced);
+    }
+
+    #[test]
+    fn test_pre_block_decodes_entities() {
+        let output = render(&mut parser(), "<pre>\nif a &lt; b &amp;&amp; c\n</pre>\n");
+        assert_eq!(strip_ansi(&output), "    if a < b && c\n\n");
+    }
+
+    #[test]
+    fn test_single_line_pre_block() {
+        let output = render(&mut parser(), "<pre>one line</pre>\n\nafter\n\n");
+        assert_eq!(strip_ansi(&output), "    one line\n\nafter\n\n");
+    }
+
+    #[test]
+    fn test_non_ascii_text_before_closing_tags() {
+        let output = render(&mut parser(), "<pre>\nİ</code>€</pre>\n");
+        assert_eq!(strip_ansi(&output), "    İ</code>€\n\n");
+        let output = render(&mut parser(), "<pre><code>\nİstanbul €\n</code></pre>\n");
+        assert_eq!(strip_ansi(&output), "    İstanbul €\n\n");
+        let output = render(&mut parser(), "<pre><code>İ €</code></pre>\n");
+        assert_eq!(strip_ansi(&output), "    İ €\n\n");
+    }
+}
+
+mod table_layout {
+    use super::*;
+    use mdriver::TableLayout;
+
+    const WIDE_TABLE: &str = "| Name | Description | Qty |\n\
+|:-----|-------------|----:|\n\
+| **widget** | A very long description of the widget that goes on and on, see [the docs](https://example.com/docs/widget) for more | 12 |\n\
+| gadget | short | 3 |\n\n";
+
+    fn table_lines(output: &str) -> Vec<String> {
+        strip_ansi(output)
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_table_fits_width() {
+        let output = render(&mut parser_with_width(50), WIDE_TABLE);
+        for line in table_lines(&output) {
+            assert_eq!(line.chars().count(), 50, "line: {line:?}");
+        }
+    }
+
+    #[test]
+    fn test_long_cell_wraps_within_row() {
+        let lines = table_lines(&render(&mut parser_with_width(50), WIDE_TABLE));
+        // Top border, header, separator, three lines for widget, one for gadget, bottom
+        assert_eq!(lines.len(), 8);
+        assert!(lines[3].starts_with("│ widget │ A very long"));
+        assert!(lines[4].starts_with("│        │ "));
+        assert!(lines[6].starts_with("│ gadget │ short"));
+        let description: Vec<&str> = lines[3..6]
+            .iter()
+            .map(|l| l.split('│').nth(2).unwrap().trim())
+            .collect();
+        assert_eq!(
+            description.join(" "),
+            "A very long description of the widget that goes on and on, see the docs for more"
+        );
+    }
+
+    #[test]
+    fn test_alignment_kept_when_wrapping() {
+        let lines = table_lines(&render(&mut parser_with_width(50), WIDE_TABLE));
+        // Right-aligned quantity column
+        assert!(lines[3].ends_with("  12 │"));
+        assert!(lines[6].ends_with("   3 │"));
+    }
+
+    #[test]
+    fn test_wrapped_link_does_not_leak_into_borders() {
+        let output = render(&mut parser_with_width(30), WIDE_TABLE);
+        for line in output.lines() {
+            let opens = line.matches("\x1b]8;;https://").count();
+            let closes = line.matches("\x1b]8;;\x1b\\").count();
+            assert_eq!(opens, closes, "unbalanced link in {line:?}");
+        }
+        assert!(strip_ansi(&output).contains("the"));
+    }
+
+    #[test]
+    fn test_long_word_is_split_to_column() {
+        let output = render(
+            &mut parser_with_width(24),
+            "| Key | Value |\n|---|---|\n| a | supercalifragilisticexpialidocious |\n\n",
+        );
+        for line in table_lines(&output) {
+            assert_eq!(line.chars().count(), 24, "line: {line:?}");
+        }
+    }
+
+    #[test]
+    fn test_narrow_table_unchanged() {
+        let output = render(
+            &mut parser_with_width(80),
+            "| A | B |\n|---|---|\n| 1 | 2 |\n\n",
+        );
+        assert_eq!(
+            table_lines(&output),
+            vec![
+                "┌─────┬─────┐",
+                "│ A   │ B   │",
+                "├─────┼─────┤",
+                "│ 1   │ 2   │",
+                "└─────┴─────┘",
+            ]
+        );
+    }
+
+    fn render_with_layout(width: usize, layout: TableLayout, input: &str) -> String {
+        let mut p = parser_with_width(width);
+        p.set_table_layout(layout);
+        render(&mut p, input)
+    }
+
+    fn many_columns() -> String {
+        let header: Vec<String> = (1..=12).map(|i| format!("Column {i}")).collect();
+        let row: Vec<String> = (1..=12).map(|i| format!("value {i}")).collect();
+        format!(
+            "| {} |\n|{}\n| {} |\n\n",
+            header.join(" | "),
+            "---|".repeat(12),
+            row.join(" | ")
+        )
+    }
+
+    #[test]
+    fn test_forced_records_layout() {
+        let output = render_with_layout(50, TableLayout::Records, WIDE_TABLE);
+        let lines = table_lines(&output);
+        assert_eq!(lines[0], "Name:        widget");
+        assert!(lines[1].starts_with("Description: A very long"));
+        assert!(lines.iter().any(|l| l == "Qty:         12"));
+        assert!(lines.iter().any(|l| l.chars().all(|c| c == '─')));
+        assert!(lines.iter().any(|l| l == "Name:        gadget"));
+        assert!(!strip_ansi(&output).contains('│'));
+    }
+
+    #[test]
+    fn test_records_wrap_values_under_each_other() {
+        let output = render_with_layout(40, TableLayout::Records, WIDE_TABLE);
+        let lines = table_lines(&output);
+        assert!(lines[2].starts_with("             "));
+        for line in &lines {
+            assert!(line.chars().count() <= 40, "line: {line:?}");
+        }
+    }
+
+    #[test]
+    fn test_auto_switches_to_records_when_columns_cannot_fit() {
+        let output = render(&mut parser_with_width(80), &many_columns());
+        let stripped = strip_ansi(&output);
+        assert!(!stripped.contains('┌'));
+        assert!(stripped.contains("Column 12: value 12"));
+    }
+
+    #[test]
+    fn test_forced_grid_keeps_columns() {
+        let output = render_with_layout(80, TableLayout::Grid, &many_columns());
+        assert!(strip_ansi(&output).starts_with('┌'));
+    }
+
+    #[test]
+    fn test_min_column_width_threshold() {
+        let input =
+            "| A | B | C |\n|---|---|---|\n| one two three | four five six | seven eight |\n\n";
+        // Each column can get 6 characters at width 28
+        assert!(strip_ansi(&render(&mut parser_with_width(28), input)).starts_with('┌'));
+
+        let mut p = parser_with_width(28);
+        p.set_table_min_column_width(8);
+        let output = render(&mut p, input);
+        assert!(strip_ansi(&output).starts_with("A: one two three"));
+    }
+}
+
+mod table_streaming {
+    use super::*;
+    use mdriver::TableLayout;
+
+    fn streaming_parser(lookahead: usize) -> StreamingParser {
+        let mut p = parser_with_width(80);
+        p.set_table_streaming(Some(lookahead));
+        p
+    }
+
+    #[test]
+    fn test_header_emitted_before_rows() {
+        let mut p = streaming_parser(0);
+        assert_eq!(p.feed("| Name | Notes |\n"), "");
+        let header = strip_ansi(&p.feed("|------|-------|\n"));
+        assert_eq!(
+            header,
+            "┌──────┬───────┐\n│ Name │ Notes │\n├──────┼───────┤\n"
+        );
+        assert_eq!(strip_ansi(&p.feed("| a | b |\n")), "│ a    │ b     │\n");
+        assert_eq!(strip_ansi(&p.feed("\n")), "└──────┴───────┘\n\n");
+    }
+
+    #[test]
+    fn test_lookahead_rows_size_columns() {
+        let mut p = streaming_parser(2);
+        assert_eq!(p.feed("| A | B |\n|---|---|\n| first | x |\n"), "");
+        let output = strip_ansi(&p.feed("| second | y |\n"));
+        assert!(output.starts_with("┌────────┬─────┐\n"));
+        assert!(output.contains("│ first  │ x   │\n│ second │ y   │\n"));
+        assert_eq!(strip_ansi(&p.feed("| third | z |\n")), "│ third  │ z   │\n");
+    }
+
+    #[test]
+    fn test_overflowing_cells_are_truncated() {
+        let mut p = streaming_parser(0);
+        p.feed("| Name | Notes |\n|---|---|\n");
+        let row = strip_ansi(&p.feed("| a | a much longer note |\n"));
+        assert_eq!(row, "│ a    │ a mu… │\n");
+    }
+
+    #[test]
+    fn test_truncated_link_is_closed() {
+        let mut p = streaming_parser(0);
+        p.feed("| Name | Notes |\n|---|---|\n");
+        let row = p.feed("| a | [a long link](https://example.com) |\n");
+        assert_eq!(
+            row.matches("\x1b]8;;https://").count(),
+            row.matches("\x1b]8;;\x1b\\").count()
+        );
+    }
+
+    #[test]
+    fn test_short_table_is_buffered_until_lookahead() {
+        let input = "| A | B |\n|---|---|\n| 1 | 2 |\n\n";
+        let mut buffered = parser_with_width(80);
+        let mut p = streaming_parser(5);
+        assert_eq!(p.feed(input), buffered.feed(input));
+    }
+
+    #[test]
+    fn test_streamed_output_matches_buffered_when_cells_fit() {
+        let input = "| Name | Notes |\n|:----|----:|\n| a | b |\n| c | d |\n\nafter\n";
+        let mut buffered = parser_with_width(80);
+        let mut p = streaming_parser(0);
+        let mut streamed = String::new();
+        for line in input.split_inclusive('\n') {
+            streamed.push_str(&p.feed(line));
+        }
+        streamed.push_str(&p.flush());
+        assert_eq!(streamed, buffered.feed(input) + &buffered.flush());
+    }
+
+    #[test]
+    fn test_streamed_records() {
+        let mut p = streaming_parser(0);
+        p.set_table_layout(TableLayout::Records);
+        assert_eq!(p.feed("| Name | Qty |\n|---|---|\n"), "");
+        let first = strip_ansi(&p.feed("| a | 1 |\n"));
+        assert_eq!(first, "Name: a\nQty:  1\n");
+        let second = strip_ansi(&p.feed("| b | 2 |\n"));
+        assert!(second.starts_with('─'));
+        assert!(second.ends_with("Name: b\nQty:  2\n"));
+        assert_eq!(p.feed("\n"), "\n");
+    }
+}
+
+mod gfm_tables {
+    use super::*;
+
+    #[test]
+    fn test_pipes_optional_at_row_edges() {
+        // Spec example 199
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| abc | defghi |\n:-: | -----------:\nbar | baz\n",
+        );
+        assert!(output.contains("│ abc │ defghi │"));
+        assert!(output.contains("│ bar │    baz │"));
+    }
+
+    #[test]
+    fn test_escaped_pipes() {
+        // Spec example 200
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| f\\|oo  |\n| ------ |\n| b `\\|` az |\n| b **\\|** im |\n",
+        );
+        assert!(output.contains("│ f|oo "));
+        assert!(output.contains("│ b  |  az"));
+        assert!(output.contains("│ b | im"));
+    }
+
+    #[test]
+    fn test_other_backslashes_kept_for_inlines() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| a | b |\n|---|---|\n| `x\\y` | c \\* d |\n",
+        );
+        assert!(output.contains("x\\y"));
+        assert!(output.contains("c * d"));
+    }
+
+    #[test]
+    fn test_block_start_ends_table() {
+        // Spec example 201
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| abc | def |\n| --- | --- |\n| bar | baz |\n> bar\n",
+        );
+        assert!(output.contains("└─────┴─────┘"));
+        assert!(!output.contains("│ >"));
+        assert!(output.contains("bar\n"));
+    }
+
+    #[test]
+    fn test_row_without_pipes_continues_table() {
+        // Spec example 202
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| abc | def |\n| --- | --- |\n| bar | baz |\nbar\n\nbar\n",
+        );
+        assert!(output.contains("│ bar │ baz │\n│ bar │     │\n└"));
+        assert!(output.ends_with("\nbar\n\n"));
+    }
+
+    #[test]
+    fn test_header_and_delimiter_cell_counts_must_match() {
+        // Spec example 203
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| abc | def |\n| --- |\n| bar |\n",
+        );
+        assert!(!output.contains('┌'));
+        assert!(output.contains("| abc | def | | --- | | bar |"));
+    }
+
+    #[test]
+    fn test_rows_padded_and_truncated_to_header() {
+        // Spec example 204
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| abc | def |\n| --- | --- |\n| bar |\n| bar | baz | boo |\n",
+        );
+        assert!(output.contains("│ bar │     │\n│ bar │ baz │\n"));
+        assert!(!output.contains("boo"));
+    }
+
+    #[test]
+    fn test_table_without_body() {
+        // Spec example 205
+        let output = render_plain(&mut parser_with_width(80), "| abc | def |\n| --- | --- |\n");
+        assert_eq!(
+            output,
+            "┌─────┬─────┐\n│ abc │ def │\n├─────┼─────┤\n└─────┴─────┘\n\n"
+        );
+    }
+
+    #[test]
+    fn test_single_dash_delimiter() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| a | b |\n|-|:-:|\n| 1 | 2 |\n",
+        );
+        assert!(output.starts_with("┌─────┬─────┐"));
+        assert!(output.contains("│ 1   │  2  │"));
+    }
+
+    #[test]
+    fn test_empty_cells_kept() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "| a | b | c |\n|---|---|---|\n| | x | |\n",
+        );
+        assert!(output.contains("│     │ x   │     │"));
+    }
+}
+
+mod csv_tables {
+    use super::*;
+
+    #[test]
+    fn test_csv_block_as_table() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(&mut p, "```csv\nname,qty\nwidget,3\n```\n");
+        assert_eq!(
+            output,
+            "┌────────┬─────┐\n│ name   │ qty │\n├────────┼─────┤\n│ widget │   3 │\n└────────┴─────┘\n\n"
+        );
+    }
+
+    #[test]
+    fn test_quoted_fields() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(&mut p, "```csv\na,b\n\"x, y\",\"say \"\"hi\"\"\"\n```\n");
+        assert!(output.contains("│ x, y │ say \"hi\" │"));
+    }
+
+    #[test]
+    fn test_dollars_are_not_math() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(&mut p, "```csv\nexpr,path\n$x$,$HOME/$USER\n```\n");
+        assert!(output.contains("│ $x$  │ $HOME/$USER │"));
+    }
+
+    #[test]
+    fn test_quoted_field_with_newline() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(&mut p, "```csv\na,b\n\"two\nlines\",1\n```\n");
+        assert!(output.contains("two lines"));
+    }
+
+    #[test]
+    fn test_numeric_columns_right_aligned() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(
+            &mut p,
+            "```csv\nitem,price,share\nwidget,\"1,234.50\",12%\ngadget,7,-3%\n```\n",
+        );
+        assert!(output.contains("│ widget │ 1,234.50 │   12% │"));
+        assert!(output.contains("│ gadget │        7 │   -3% │"));
+    }
+
+    #[test]
+    fn test_tsv_without_header() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(&mut p, "```tsv header=false\na\t1\nb\t22\n```\n");
+        assert_eq!(
+            output,
+            "┌─────┬─────┐\n│ a   │   1 │\n│ b   │  22 │\n└─────┴─────┘\n\n"
+        );
+    }
+
+    #[test]
+    fn test_values_are_not_markdown() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let output = render_plain(&mut p, "```csv\nnote\n*not italic*\n```\n");
+        assert!(output.contains("│ *not italic* │"));
+    }
+
+    #[test]
+    fn test_malformed_csv_falls_back_to_code() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let unterminated = render_plain(&mut p, "```csv\na,\"b\nc,d\n```\n");
+        assert!(!unterminated.contains('┌'));
+        assert!(unterminated.contains("a,\"b"));
+
+        let ragged = render_plain(&mut p, "```csv\na,b\n1,2,3\n```\n");
+        assert!(!ragged.contains('┌'));
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut p = parser_with_width(80);
+        p.set_csv_tables(true);
+        let mut p = parser_with_width(80);
+        let output = strip_ansi(&p.feed("```csv\na,b\n1,2\n```\n"));
+        assert_eq!(output, "    a,b\n    1,2\n\n");
+    }
+}
+
+mod table_styles {
+    use super::*;
+    use mdriver::TableBorder;
+
+    const TABLE: &str = "| Name | Qty |\n|:--|--:|\n| a | 1 |\n| bb | 22 |\n\n";
+    const LEFT_ALIGNED: &str = "| Qty | Name |\n|---|---|\n| 1 | a |\n\n";
+
+    fn render_with(configure: impl Fn(&mut StreamingParser)) -> String {
+        let mut p = parser_with_width(80);
+        configure(&mut p);
+        render(&mut p, TABLE)
+    }
+
+    fn render_border(border: TableBorder) -> String {
+        strip_ansi(&render_with(|p| p.set_table_border(border)))
+    }
+
+    #[test]
+    fn test_heavy_border() {
+        assert_eq!(
+            render_border(TableBorder::Heavy),
+            "┏━━━━━━┳━━━━━┓\n┃ Name ┃ Qty ┃\n┣━━━━━━╋━━━━━┫\n┃ a    ┃   1 ┃\n┃ bb   ┃  22 ┃\n┗━━━━━━┻━━━━━┛\n\n"
+        );
+    }
+
+    #[test]
+    fn test_rounded_and_double_corners() {
+        let rounded = render_border(TableBorder::Rounded);
+        assert!(rounded.starts_with("╭──────┬─────╮\n"));
+        assert!(rounded.contains("╰──────┴─────╯\n"));
+        let double = render_border(TableBorder::Double);
+        assert!(double.starts_with("╔══════╦═════╗\n"));
+        assert!(double.contains("║ bb   ║  22 ║\n"));
+    }
+
+    #[test]
+    fn test_ascii_border_is_ascii_only() {
+        let output = render_with(|p| p.set_table_border(TableBorder::Ascii));
+        assert_eq!(
+            strip_ansi(&output),
+            "+------+-----+\n| Name | Qty |\n+------+-----+\n| a    |   1 |\n| bb   |  22 |\n+------+-----+\n\n"
+        );
+        assert!(output.is_ascii());
+    }
+
+    #[test]
+    fn test_markdown_border_marks_alignment() {
+        assert_eq!(
+            render_border(TableBorder::Markdown),
+            "| Name | Qty |\n|------|----:|\n| a    |   1 |\n| bb   |  22 |\n\n"
+        );
+    }
+
+    #[test]
+    fn test_borderless() {
+        assert_eq!(
+            render_border(TableBorder::Borderless),
+            "Name  Qty\n────  ───\na       1\nbb     22\n\n"
+        );
+    }
+
+    #[test]
+    fn test_borderless_has_no_trailing_spaces() {
+        let mut p = parser_with_width(80);
+        p.set_table_border(TableBorder::Borderless);
+        let output = strip_ansi(&p.feed(LEFT_ALIGNED));
+        assert_eq!(output, "Qty  Name\n───  ────\n1    a\n\n");
+    }
+
+    #[test]
+    fn test_padding_override() {
+        let output = strip_ansi(&render_with(|p| p.set_table_padding(2)));
+        assert!(output.starts_with("┌────────┬───────┐\n│  Name  │  Qty  │\n"));
+    }
+
+    #[test]
+    fn test_header_is_bold() {
+        let output = render_with(|_| {});
+        assert!(output.contains("│ \x1b[1mName\x1b[0m │ \x1b[1mQty\x1b[0m │"));
+        assert!(!output.contains("\x1b[1ma"));
+    }
+
+    #[test]
+    fn test_zebra_shades_alternate_rows() {
+        let output = render_with(|p| p.set_table_zebra(true));
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(!lines[3].contains("\x1b[48;2;"));
+        assert!(lines[4].contains("\x1b[48;2;"));
+        assert_eq!(strip_ansi(lines[4]), "│ bb   │  22 │");
+    }
+}
+
+mod code_fences {
+    use super::*;
+
+    #[test]
+    fn test_fewer_than_three_backticks() {
+        // Spec example 91
+        let output = render_plain(&mut parser_with_width(80), "``\nfoo\n``\n");
+        assert!(!output.contains("    foo"));
+        assert!(output.contains("foo"));
+    }
+
+    #[test]
+    fn test_closing_fence_must_match_character() {
+        // Spec examples 92 and 93
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "```\naaa\n~~~\n```\n"),
+            "    aaa\n    ~~~\n\n"
+        );
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "~~~\naaa\n```\n~~~\n"),
+            "    aaa\n    ```\n\n"
+        );
+    }
+
+    #[test]
+    fn test_closing_fence_must_be_long_enough() {
+        // Spec examples 94 and 95
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "````\naaa\n```\n``````\n"),
+            "    aaa\n    ```\n\n"
+        );
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "~~~~\naaa\n~~~\n~~~~\n"),
+            "    aaa\n    ~~~\n\n"
+        );
+    }
+
+    #[test]
+    fn test_nested_markdown_fence() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "````markdown\n```rust\nfn main() {}\n```\n````\nafter\n",
+        );
+        assert!(output.starts_with("    ```rust\n    fn main() {}\n    ```\n\n"));
+        assert!(output.ends_with("after\n\n"));
+    }
+
+    #[test]
+    fn test_unclosed_fence_runs_to_end() {
+        // Spec example 97
+        let output = render_plain(&mut parser_with_width(80), "`````\n\n```\naaa\n");
+        assert_eq!(output, "    \n    ```\n    aaa\n\n");
+    }
+
+    #[test]
+    fn test_fence_indentation_removed_from_content() {
+        // Spec examples 101 and 102
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), " ```\n aaa\naaa\n```\n"),
+            "    aaa\n    aaa\n\n"
+        );
+        assert_eq!(
+            render_plain(
+                &mut parser_with_width(80),
+                "  ```\naaa\n  aaa\naaa\n  ```\n"
+            ),
+            "    aaa\n    aaa\n    aaa\n\n"
+        );
+    }
+
+    #[test]
+    fn test_indented_four_spaces_is_not_closing() {
+        // Spec example 107
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "```\naaa\n    ```\n"),
+            "    aaa\n        ```\n\n"
+        );
+    }
+
+    #[test]
+    fn test_closing_fence_cannot_have_internal_spaces() {
+        // Spec example 109
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "~~~~~~\naaa\n~~~ ~~\n"),
+            "    aaa\n    ~~~ ~~\n\n"
+        );
+    }
+
+    #[test]
+    fn test_backtick_in_info_string() {
+        // Spec example 108: not a fence, so inline code instead
+        let output = render(&mut parser(), "``` ```\naaa\n");
+        assert!(output.starts_with("\u{001b}[38;5;167;48;5;235m   \u{001b}[0m aaa"));
+        // Tilde fences may have backticks in the info string (example 116)
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "~~~ aa ``` ~~~\nfoo\n~~~\n"),
+            "    foo\n\n"
+        );
+    }
+
+    #[test]
+    fn test_closing_fence_cannot_have_info() {
+        // Spec example 117
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "```\n``` aaa\n```\n"),
+            "    ``` aaa\n\n"
+        );
+    }
+
+    #[test]
+    fn test_fence_interrupts_paragraph() {
+        // Spec example 110
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "foo\n```\nbar\n```\nbaz\n"),
+            "foo\n\n    bar\n\nbaz\n\n"
+        );
+    }
+
+    #[test]
+    fn test_info_string_language_is_first_word() {
+        // Spec example 113
+        let mut p = parser_with_width(80);
+        let output = p.feed("~~~~    ruby startline=3 $%@#$\ndef foo(x)\n~~~~~~~\n");
+        let mut plain = parser_with_width(80);
+        assert_eq!(output, plain.feed("```ruby\ndef foo(x)\n```\n"));
+    }
+}
+
+mod html_blocks {
+    use super::*;
+
+    #[test]
+    fn test_div_wrapper_is_stripped() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<div align=\"center\">\n  <p>A <b>fast</b> tool.</p>\n</div>\n",
+        );
+        assert_eq!(output, "A fast tool.\n\n");
+    }
+
+    #[test]
+    fn test_heading_inside_html_block() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<div>\n<h2>Title</h2>\ntext\n</div>\n",
+        );
+        assert_eq!(output, "## Title\n\ntext\n\n");
+    }
+
+    #[test]
+    fn test_multi_line_comment_is_dropped() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<!--\nhidden\n\nstill hidden\n-->\nvisible\n",
+        );
+        assert_eq!(output, "visible\n\n");
+    }
+
+    #[test]
+    fn test_shortest_comments_are_dropped() {
+        // `<!-->` and `<!--->` are complete comments, not a panic
+        let output = render_plain(&mut parser_with_width(80), "<!-->\n\n<!--->\nvisible\n");
+        assert_eq!(output, "visible\n\n");
+    }
+
+    #[test]
+    fn test_script_and_style_are_dropped() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<script>\nalert(1)\n</script>\n<style>\np { color: red }\n</style>\nafter\n",
+        );
+        assert_eq!(output, "after\n\n");
+    }
+
+    #[test]
+    fn test_block_ends_at_blank_line() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<div>\n*raw*\n\n*emphasis*\n</div>\n",
+        );
+        assert_eq!(output, "raw\n\nemphasis\n\n");
+    }
+
+    #[test]
+    fn test_html_table_rendered_as_table() {
+        let output = render_plain(&mut parser_with_width(80),
+            "<table>\n<tr><th>Name</th><th>Role</th></tr>\n<tr><td>Ann</td><td>Dev</td></tr>\n</table>\n",
+        );
+        assert!(output.contains("│ Name │ Role │"));
+        assert!(output.contains("│ Ann  │ Dev  │"));
+    }
+
+    #[test]
+    fn test_picture_shows_fallback_image() {
+        let output = render_plain(&mut parser_with_width(80),
+            "<picture>\n  <source srcset=\"dark.png\">\n  <img alt=\"Shot\" src=\"light.png\">\n</picture>\n",
+        );
+        assert_eq!(output, "![Shot](light.png)\n\n");
+    }
+
+    #[test]
+    fn test_picture_without_img_uses_source() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<picture>\n<source srcset=\"dark.png 2x\">\n</picture>\n",
+        );
+        assert!(output.contains("dark.png"));
+        assert!(!output.contains("2x"));
+    }
+
+    #[test]
+    fn test_block_tag_interrupts_paragraph() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "Para text\n<div>interrupts</div>\n",
+        );
+        assert_eq!(output, "Para text\n\ninterrupts\n\n");
+    }
+
+    #[test]
+    fn test_lone_tag_does_not_interrupt_paragraph() {
+        // Spec example 163: condition 7 cannot interrupt a paragraph
+        let output = render_plain(&mut parser_with_width(80), "Foo\n<a href=\"bar\">\nbaz\n");
+        assert!(output.starts_with("Foo "));
+    }
+
+    #[test]
+    fn test_lone_custom_tag_starts_block() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<custom-el>\ncontent\n</custom-el>\n\nafter\n",
+        );
+        assert_eq!(output, "content\n\nafter\n\n");
+    }
+
+    #[test]
+    fn test_inline_tag_with_text_is_paragraph() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<span>lone</span> stays a paragraph\n",
+        );
+        assert_eq!(output, "lone stays a paragraph\n\n");
+    }
+
+    #[test]
+    fn test_declaration_and_cdata() {
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "<!DOCTYPE html>\nafter\n"),
+            "after\n\n"
+        );
+        assert_eq!(
+            render_plain(&mut parser_with_width(80), "<![CDATA[\na < *b*\n]]>\n"),
+            "a < *b*\n\n"
+        );
+    }
+
+    #[test]
+    fn test_pre_still_renders_as_code() {
+        let output = render_plain(&mut parser_with_width(80), "<pre>\nfn main() {}\n</pre>\n");
+        assert_eq!(output, "    fn main() {}\n\n");
+    }
+}
+
+mod details {
+    use super::*;
+
+    const DETAILS: &str = "<details>\n<summary>Click to expand</summary>\n\n## Inner\n\nSome **markdown**.\n\n</details>\n\nAfter\n";
+
+    #[test]
+    fn test_summary_and_indented_body() {
+        let output = render_plain(&mut parser_with_width(80), DETAILS);
+        assert_eq!(
+            output,
+            "▼ Click to expand\n\n  ## Inner\n\n  Some markdown.\n\nAfter\n\n"
+        );
+    }
+
+    #[test]
+    fn test_summary_is_bold() {
+        let mut p = parser_with_width(80);
+        let output = p.feed(DETAILS);
+        assert!(output.contains("▼ \u{001b}[1mClick to expand\u{001b}[0m"));
+    }
+
+    #[test]
+    fn test_collapsed_details_hide_body() {
+        let mut p = parser_with_width(80);
+        p.set_collapse_details(true);
+        let output = render_plain(&mut p, DETAILS);
+        assert_eq!(output, "▶ Click to expand\n\nAfter\n\n");
+    }
+
+    #[test]
+    fn test_body_blocks_are_parsed() {
+        let input =
+            "<details><summary>Code</summary>\n\n```\nlet x = 1;\n```\n\n- item\n\n</details>\n";
+        let output = render_plain(&mut parser_with_width(80), input);
+        assert!(output.contains("\n      let x = 1;\n"));
+        assert!(output.contains("  • item"));
+    }
+
+    #[test]
+    fn test_nested_details() {
+        let input = "<details><summary>Outer</summary>\n\n<details><summary>Inner</summary>\n\ndeep\n\n</details>\n</details>\n";
+        let output = render_plain(&mut parser_with_width(80), input);
+        assert_eq!(output, "▼ Outer\n\n  ▼ Inner\n\n    deep\n\n");
+    }
+
+    #[test]
+    fn test_single_block_details() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<details><summary>Title</summary>Body text</details>\n",
+        );
+        assert_eq!(output, "▼ Title\n\n  Body text\n\n");
+    }
+
+    #[test]
+    fn test_missing_summary() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<details>\n\nbody\n\n</details>\n",
+        );
+        assert_eq!(output, "▼ Details\n\n  body\n\n");
+    }
+
+    #[test]
+    fn test_body_wraps_within_indent() {
+        let mut p = parser_with_width(30);
+        let input = "<details><summary>S</summary>\n\none two three four five six seven eight nine\n\n</details>\n";
+        let output = render_plain(&mut p, input);
+        for line in output.lines() {
+            assert!(line.chars().count() <= 30, "line too long: {:?}", line);
+        }
+        assert!(output.contains("\n  one two"));
+    }
+}
+
+mod html_tables {
+    use super::*;
+
+    #[test]
+    fn test_th_row_becomes_header() {
+        let output = render_plain(&mut parser_with_width(80),
+            "<table>\n<thead><tr><th>Name</th><th>Role</th></tr></thead>\n<tbody><tr><td>Ann</td><td>Dev</td></tr></tbody>\n</table>\n",
+        );
+        assert_eq!(
+            output,
+            "┌──────┬──────┐\n│ Name │ Role │\n├──────┼──────┤\n│ Ann  │ Dev  │\n└──────┴──────┘\n\n"
+        );
+    }
+
+    #[test]
+    fn test_header_is_bold() {
+        let mut p = parser_with_width(80);
+        let output = p.feed("<table><tr><th>Name</th></tr><tr><td>Ann</td></tr></table>\n\n");
+        assert!(output.contains("\u{001b}[1mName\u{001b}[0m"));
+    }
+
+    #[test]
+    fn test_no_th_means_no_header() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<table>\n<tr><td>a</td><td>b</td></tr>\n<tr><td>c</td><td>d</td></tr>\n</table>\n",
+        );
+        assert_eq!(
+            output,
+            "┌─────┬─────┐\n│ a   │ b   │\n│ c   │ d   │\n└─────┴─────┘\n\n"
+        );
+    }
+
+    #[test]
+    fn test_align_attributes() {
+        let output = render_plain(&mut parser_with_width(80),
+            "<table>\n<tr><th align=\"center\">Status</th><th style=\"text-align: right\">Count</th></tr>\n<tr><td>ok</td><td>7</td></tr>\n</table>\n",
+        );
+        assert!(output.contains("│   ok   │     7 │"));
+    }
+
+    #[test]
+    fn test_colspan_adds_empty_cells() {
+        let output = render_plain(&mut parser_with_width(80),
+            "<table>\n<tr><th>A</th><th>B</th><th>C</th></tr>\n<tr><td colspan=\"2\">wide</td><td>x</td></tr>\n</table>\n",
+        );
+        assert!(output.contains("│ wide │     │ x   │"));
+    }
+
+    #[test]
+    fn test_images_show_alt_text() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<table><tr><td><img src=\"a.png\" alt=\"Ann\"><br>Ann</td></tr></table>\n",
+        );
+        assert!(output.contains("│ Ann Ann │"));
+        assert!(!output.contains("a.png"));
+    }
+
+    #[test]
+    fn test_inline_markup_in_cells() {
+        let mut p = parser_with_width(80);
+        let output =
+            p.feed("<table><tr><td><b>bold</b> and <code>a*b</code></td></tr></table>\n\n");
+        assert!(output.contains("\u{001b}[1mbold\u{001b}[0m"));
+        assert!(strip_ansi(&output).contains("a*b"));
+    }
+
+    #[test]
+    fn test_cell_text_is_not_markdown() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<table><tr><td>2 * 3 * 4 &amp; _x_</td></tr></table>\n",
+        );
+        assert!(output.contains("2 * 3 * 4 & _x_"));
+    }
+
+    #[test]
+    fn test_unclosed_cells() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<table>\n<tr><td>one<td>two\n<tr><td>three<td>four\n</table>\n",
+        );
+        assert!(output.contains("│ one   │ two  │"));
+        assert!(output.contains("│ three │ four │"));
+    }
+
+    #[test]
+    fn test_table_inside_wrapper() {
+        let output = render_plain(
+            &mut parser_with_width(80),
+            "<div align=\"center\">\n<table><tr><td>cell</td></tr></table>\n</div>\n",
+        );
+        assert_eq!(output, "┌──────┐\n│ cell │\n└──────┘\n\n");
+    }
+}
+
+mod emphasis {
+    use super::*;
+
+    const ITALIC: u8 = 1;
+    const BOLD: u8 = 2;
+    const STRIKE: u8 = 4;
+
+    /// Examples from gfmspec.md as (number, markdown, html)
+    fn spec_examples(first: usize, last: usize) -> Vec<(usize, String, String)> {
+        let spec = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/gfmspec.md"))
+            .expect("gfmspec.md should be readable");
+        let mut lines = spec.lines();
+        let mut examples = Vec::new();
+        // Each example is two indented blocks, each closed by a whitespace-only line
+        let read_block = |lines: &mut std::str::Lines| -> String {
+            let mut block = Vec::new();
+            for line in lines.by_ref() {
+                if line.len() >= 8 && line.trim().is_empty() {
+                    break;
+                }
+                if !line.is_empty() {
+                    block.push(line.strip_prefix("    ").unwrap_or(line));
+                }
+            }
+            block.join("\n")
+        };
+        while let Some(line) = lines.next() {
+            let Some(number) = line
+                .strip_prefix("[Example ")
+                .and_then(|rest| rest.split(']').next())
+                .and_then(|n| n.parse::<usize>().ok())
+            else {
+                continue;
+            };
+            let markdown = read_block(&mut lines);
+            let html = read_block(&mut lines);
+            if (first..=last).contains(&number) {
+                examples.push((number, markdown, html));
+            }
+        }
+        examples
+    }
+
+    /// Collapse whitespace so soft line breaks compare equal to spaces
+    fn normalize(styled: Vec<(char, u8)>) -> Vec<(char, u8)> {
+        let mut result: Vec<(char, u8)> = Vec::new();
+        for (ch, style) in styled {
+            if ch.is_whitespace() {
+                if result.last().is_some_and(|(c, _)| *c != ' ') {
+                    result.push((' ', 0));
+                }
+            } else {
+                result.push((ch, style));
+            }
+        }
+        while result.last().is_some_and(|(c, _)| *c == ' ') {
+            result.pop();
+        }
+        result
+    }
+
+    /// Characters of terminal output with the emphasis applied to each
+    fn styled_from_ansi(output: &str) -> Vec<(char, u8)> {
+        let mut styled = Vec::new();
+        let mut style = 0;
+        let mut chars = output.chars();
+        while let Some(ch) = chars.next() {
+            if ch != '\x1b' {
+                styled.push((ch, style));
+                continue;
+            }
+            chars.next();
+            let params: String = chars.by_ref().take_while(|&c| c != 'm').collect();
+            for param in params.split(';') {
+                match param {
+                    "0" => style = 0,
+                    "1" => style |= BOLD,
+                    "3" => style |= ITALIC,
+                    "9" => style |= STRIKE,
+                    _ => {}
+                }
+            }
+        }
+        normalize(styled)
+    }
+
+    /// Characters of a spec paragraph with the emphasis applied to each, or
+    /// `None` if it uses markup other than emphasis
+    fn styled_from_html(html: &str) -> Option<Vec<(char, u8)>> {
+        let html = html.strip_prefix("<p>")?.strip_suffix("</p>")?;
+        let mut styled = Vec::new();
+        let mut depth = [0u8; 3];
+        let mut rest = html;
+        while let Some(ch) = rest.chars().next() {
+            if ch == '<' {
+                let end = rest.find('>')?;
+                let tag = &rest[1..end];
+                let (name, delta) = match tag.strip_prefix('/') {
+                    Some(name) => (name, -1i8),
+                    None => (tag, 1),
+                };
+                let slot = match name {
+                    "em" => 0,
+                    "strong" => 1,
+                    "del" => 2,
+                    _ => return None,
+                };
+                depth[slot] = depth[slot].checked_add_signed(delta)?;
+                rest = &rest[end + 1..];
+                continue;
+            }
+            let (decoded, len) = match ch {
+                '&' => {
+                    let end = rest.find(';')?;
+                    let decoded = match &rest[..=end] {
+                        "&quot;" => '"',
+                        "&amp;" => '&',
+                        "&lt;" => '<',
+                        "&gt;" => '>',
+                        _ => return None,
+                    };
+                    (decoded, end + 1)
+                }
+                _ => (ch, ch.len_utf8()),
+            };
+            let style = (if depth[0] > 0 { ITALIC } else { 0 })
+                | (if depth[1] > 0 { BOLD } else { 0 })
+                | (if depth[2] > 0 { STRIKE } else { 0 });
+            styled.push((decoded, style));
+            rest = &rest[len..];
+        }
+        Some(normalize(styled))
+    }
+
+    fn check_spec_examples(first: usize, last: usize) -> usize {
+        let mut checked = 0;
+        let mut failures = Vec::new();
+        let mut p = parser_with_width(1000);
+        for (number, markdown, html) in spec_examples(first, last) {
+            // Links, code spans, raw HTML and tabs are covered elsewhere
+            if markdown.contains(['`', '<', '[', '→']) || markdown.contains("\\") {
+                continue;
+            }
+            // The non-breaking spaces of example 363 are plain spaces in our
+            // copy of the spec, which turns it into a list item
+            if number == 363 {
+                continue;
+            }
+            let Some(expected) = styled_from_html(&html) else {
+                continue;
+            };
+            let output = p.feed(&format!("{}\n", markdown)) + &p.flush();
+            let actual = styled_from_ansi(&output);
+            if actual != expected {
+                failures.push(number);
+            }
+            checked += 1;
+        }
+        assert!(failures.is_empty(), "spec examples failed: {:?}", failures);
+        checked
+    }
+
+    #[test]
+    fn test_spec_emphasis_examples() {
+        assert!(check_spec_examples(360, 490) > 100);
+    }
+
+    #[test]
+    fn test_spec_strikethrough_examples() {
+        assert!(check_spec_examples(491, 493) >= 2);
+    }
+
+    #[test]
+    fn test_intraword_underscores_are_literal() {
+        let output = render(
+            &mut parser_with_width(1000),
+            "Call snake_case_name or __init__ here.\n",
+        );
+        assert_eq!(
+            strip_ansi(&output),
+            "Call snake_case_name or init here.\n\n"
+        );
+        assert!(output.starts_with("Call snake_case_name"));
+    }
+
+    #[test]
+    fn test_intraword_asterisks_emphasize() {
+        let output = parser().format_inline("foo*bar*baz");
+        assert_eq!(output, "foo\u{001b}[3mbar\u{001b}[0mbaz");
+    }
+
+    #[test]
+    fn test_bold_italic() {
+        let output = parser().format_inline("***both***");
+        assert_eq!(output, "\u{001b}[3m\u{001b}[1mboth\u{001b}[0m");
+    }
+
+    #[test]
+    fn test_outer_style_restored_after_inner() {
+        let output = parser().format_inline("**bold *italic* bold**");
+        assert_eq!(
+            output,
+            "\u{001b}[1mbold \u{001b}[3mitalic\u{001b}[0m\u{001b}[1m bold\u{001b}[0m"
+        );
+    }
+
+    #[test]
+    fn test_unmatched_delimiters_are_literal() {
+        assert_eq!(parser().format_inline("a * b and **c"), "a * b and **c");
+        assert_eq!(
+            parser().format_inline("**foo*"),
+            "*\u{001b}[3mfoo\u{001b}[0m"
+        );
+    }
+
+    #[test]
+    fn test_bold_continues_after_link() {
+        let output = parser().format_inline("**see [docs](https://x.io) now**");
+        assert!(output.ends_with("\u{001b}[0m\u{001b}[1m\u{001b}]8;;\u{001b}\\ now\u{001b}[0m"));
+        assert_eq!(strip_ansi(&output), "see docs now");
+    }
+}
+
+mod code_spans {
+    use super::*;
+
+    const CODE: &str = "\u{001b}[38;5;167;48;5;235m";
+    const RESET: &str = "\u{001b}[0m";
+
+    fn code(content: &str) -> String {
+        format!("{}{}{}", CODE, content, RESET)
+    }
+
+    #[test]
+    fn test_simple_code_span() {
+        assert_eq!(parser().format_inline("`foo`"), code(" foo "));
+    }
+
+    #[test]
+    fn test_double_backticks_contain_single() {
+        // Spec examples 329 and 330
+        assert_eq!(
+            parser().format_inline("`` foo ` bar ``"),
+            code(" foo ` bar ")
+        );
+        assert_eq!(parser().format_inline("` `` `"), code(" `` "));
+    }
+
+    #[test]
+    fn test_only_one_space_is_stripped() {
+        // Spec example 331: the remaining space is kept inside the padding
+        assert_eq!(parser().format_inline("`  ``  `"), code("  ``  "));
+    }
+
+    #[test]
+    fn test_space_stripped_only_when_on_both_sides() {
+        // Spec example 332
+        assert_eq!(parser().format_inline("` a`"), code("  a "));
+    }
+
+    #[test]
+    fn test_all_space_content_is_kept() {
+        // Spec example 334
+        assert_eq!(parser().format_inline("` `"), code("   "));
+    }
+
+    #[test]
+    fn test_backslash_escapes_do_not_apply() {
+        // Spec example 338
+        assert_eq!(
+            parser().format_inline("`foo\\`bar`"),
+            format!("{}bar`", code(" foo\\ "))
+        );
+        assert_eq!(parser().format_inline("`\\*`"), code(" \\* "));
+    }
+
+    #[test]
+    fn test_escaped_backtick_does_not_open() {
+        assert_eq!(parser().format_inline("\\`not code`"), "`not code`");
+    }
+
+    #[test]
+    fn test_code_span_beats_emphasis() {
+        // Spec example 341
+        assert_eq!(
+            parser().format_inline("*foo`*`"),
+            format!("*foo{}", code(" * "))
+        );
+    }
+
+    #[test]
+    fn test_code_span_beats_link() {
+        // Spec example 342
+        let output = parser().format_inline("[not a `link](/foo`)");
+        assert_eq!(output, format!("[not a {})", code(" link](/foo ")));
+    }
+
+    #[test]
+    fn test_unmatched_backtick_strings_are_literal() {
+        // Spec examples 346-348
+        assert_eq!(parser().format_inline("```foo``"), "```foo``");
+        assert_eq!(parser().format_inline("`foo"), "`foo");
+        assert_eq!(
+            parser().format_inline("`foo``bar``"),
+            format!("`foo{}", code(" bar "))
+        );
+    }
+
+    #[test]
+    fn test_line_endings_become_spaces() {
+        let mut p = parser_with_width(80);
+        let output = strip_ansi(&(p.feed("`foo\nbar`\n\n")));
+        assert!(output.contains("foo bar"));
+    }
+
+    #[test]
+    fn test_html_code_tag() {
+        assert_eq!(
+            parser().format_inline("<code>a &lt; b</code>"),
+            code(" a < b ")
+        );
+        assert_eq!(parser().format_inline("<code> `x` </code>"), code(" `x` "));
+    }
+
+    #[test]
+    fn test_padding_wraps_with_the_code() {
+        let mut p = parser_with_width(16);
+        let output = render(&mut p, "one two three `background`\n\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[1], code(" background "));
+    }
+
+    #[test]
+    fn test_padding_counts_toward_table_cell_width() {
+        let mut p = parser_with_width(80);
+        let output = render(
+            &mut p,
+            "| Style |\n|---|\n| `plain` |\n| `background` |\n\n",
+        );
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[3], format!("│ {}      │", code(" plain ")));
+        assert_eq!(lines[4], format!("│ {} │", code(" background ")));
+    }
+}
+
+mod autolinks {
+    use super::*;
+
+    fn link(url: &str, text: &str) -> String {
+        format!(
+            "\u{001b}]8;;{}\u{001b}\\\u{001b}[34;4m{}\u{001b}[0m\u{001b}]8;;\u{001b}\\",
+            url, text
+        )
+    }
+
+    #[test]
+    fn test_angle_bracket_uri() {
+        let output = parser().format_inline("<https://foo.bar/baz?q=1>");
+        assert_eq!(
+            output,
+            link("https://foo.bar/baz?q=1", "https://foo.bar/baz?q=1")
+        );
+    }
+
+    #[test]
+    fn test_angle_bracket_email() {
+        let output = parser().format_inline("<foo@bar.example.com>");
+        assert_eq!(
+            output,
+            link("mailto:foo@bar.example.com", "foo@bar.example.com")
+        );
+    }
+
+    #[test]
+    fn test_angle_bracket_with_space_is_not_autolink() {
+        let output = parser().format_inline("<https://foo.bar/baz bim>");
+        assert!(!output.contains("\u{001b}]8;;"));
+    }
+
+    #[test]
+    fn test_www_gets_http_scheme() {
+        let output = parser().format_inline("Visit www.commonmark.org/help for more.");
+        assert_eq!(
+            output,
+            format!(
+                "Visit {} for more.",
+                link("http://www.commonmark.org/help", "www.commonmark.org/help")
+            )
+        );
+    }
+
+    #[test]
+    fn test_trailing_punctuation_excluded() {
+        let output = parser().format_inline("Visit www.commonmark.org/a.b.");
+        assert_eq!(
+            output,
+            format!(
+                "Visit {}.",
+                link("http://www.commonmark.org/a.b", "www.commonmark.org/a.b")
+            )
+        );
+    }
+
+    #[test]
+    fn test_balanced_parentheses() {
+        let url = "https://en.wikipedia.org/wiki/Rust_(programming_language)";
+        let output = parser().format_inline(&format!("({})", url));
+        assert_eq!(output, format!("({})", link(url, url)));
+    }
+
+    #[test]
+    fn test_unbalanced_closing_parentheses_excluded() {
+        let output = parser().format_inline("www.google.com/search?q=Markup+(business)))");
+        let url = "www.google.com/search?q=Markup+(business)";
+        assert_eq!(
+            output,
+            format!("{}))", link(&format!("http://{}", url), url))
+        );
+    }
+
+    #[test]
+    fn test_entity_like_suffix_excluded() {
+        let output = parser().format_inline("www.google.com/search?q=commonmark&hl;");
+        assert!(output.starts_with(&link(
+            "http://www.google.com/search?q=commonmark",
+            "www.google.com/search?q=commonmark"
+        )));
+        assert!(output.ends_with("&hl;"));
+    }
+
+    #[test]
+    fn test_less_than_ends_link() {
+        let output = parser().format_inline("www.commonmark.org/he<lp");
+        assert!(output.starts_with(&link(
+            "http://www.commonmark.org/he",
+            "www.commonmark.org/he"
+        )));
+    }
+
+    #[test]
+    fn test_domain_rules() {
+        for text in [
+            "http://localhost",
+            "example.com",
+            "www.a_b.c_d",
+            "version 1.2.3",
+        ] {
+            let output = parser().format_inline(text);
+            assert!(!output.contains("\u{001b}]8;;"), "{} was linked", text);
+        }
+    }
+
+    #[test]
+    fn test_bare_email() {
+        let output =
+            parser().format_inline("hello@mail+xyz.example isn't, but hello+xyz@mail.example is.");
+        assert_eq!(
+            output,
+            format!(
+                "hello@mail+xyz.example isn't, but {} is.",
+                link("mailto:hello+xyz@mail.example", "hello+xyz@mail.example")
+            )
+        );
+    }
+
+    #[test]
+    fn test_email_end_rules() {
+        assert_eq!(
+            parser().format_inline("a.b-c_d@a.b."),
+            format!("{}.", link("mailto:a.b-c_d@a.b", "a.b-c_d@a.b"))
+        );
+        assert_eq!(parser().format_inline("a.b-c_d@a.b-"), "a.b-c_d@a.b-");
+        assert_eq!(parser().format_inline("a.b-c_d@a.b_"), "a.b-c_d@a.b_");
+    }
+
+    #[test]
+    fn test_mailto_protocol() {
+        let output = parser().format_inline("mailto:foo@bar.baz");
+        assert_eq!(output, link("mailto:foo@bar.baz", "mailto:foo@bar.baz"));
+    }
+
+    #[test]
+    fn test_autolink_inside_emphasis() {
+        let output = parser().format_inline("*https://z.io*");
+        assert!(output.starts_with("\u{001b}[3m\u{001b}]8;;https://z.io\u{001b}\\"));
+        assert_eq!(strip_ansi(&output), "https://z.io");
+    }
+
+    #[test]
+    fn test_no_autolink_inside_link_text() {
+        let output = parser().format_inline("[www.x.com](https://y.com)");
+        assert_eq!(output, link("https://y.com", "www.x.com"));
+    }
+
+    #[test]
+    fn test_long_url_is_not_broken() {
+        let mut p = parser_with_width(20);
+        let output = strip_ansi(&p.feed("See https://example.com/a/very/long/path ok\n\n"));
+        assert!(output.contains("\nhttps://example.com/a/very/long/path\n"));
+    }
+}
+
+mod link_destinations {
+    use super::*;
+
+    fn link(url: &str, text: &str) -> String {
+        format!(
+            "\u{001b}]8;;{}\u{001b}\\\u{001b}[34;4m{}\u{001b}[0m\u{001b}]8;;\u{001b}\\",
+            url, text
+        )
+    }
+
+    #[test]
+    fn test_balanced_parentheses() {
+        let output = parser().format_inline(
+            "[Rust](https://en.wikipedia.org/wiki/Rust_(programming_language)) rocks",
+        );
+        assert_eq!(
+            output,
+            format!(
+                "{} rocks",
+                link(
+                    "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+                    "Rust"
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_parentheses_are_not_a_link() {
+        let output = parser().format_inline("[link](foo(and(bar))");
+        assert!(!output.contains("\u{001b}]8;;"));
+    }
+
+    #[test]
+    fn test_escaped_parenthesis() {
+        let output = parser().format_inline("[link](foo\\)\\:)");
+        assert_eq!(output, link("foo):", "link"));
+    }
+
+    #[test]
+    fn test_angle_bracket_destination() {
+        let output = parser().format_inline("[link](<foo(bar>)");
+        assert_eq!(output, link("foo(bar", "link"));
+        let output = parser().format_inline("[link](<>)");
+        assert_eq!(output, link("", "link"));
+    }
+
+    #[test]
+    fn test_space_in_bare_destination_is_not_a_link() {
+        let output = parser().format_inline("[link](/my uri)");
+        assert!(!output.contains("\u{001b}]8;;"));
+    }
+
+    #[test]
+    fn test_disallowed_characters_are_percent_encoded() {
+        let output = parser().format_inline("[a](<my url>)");
+        assert_eq!(output, link("my%20url", "a"));
+        let output = parser().format_inline("[a](https://x.io/a?b=c&d=%7E#e)");
+        assert_eq!(output, link("https://x.io/a?b=c&d=%7E#e", "a"));
+    }
+
+    #[test]
+    fn test_entity_in_destination() {
+        let output = parser().format_inline("[link](foo%20b&auml;)");
+        // Spec example 513: the decoded entity is percent-encoded
+        assert_eq!(output, link("foo%20b%C3%A4", "link"));
+    }
+
+    #[test]
+    fn test_title_is_hidden_by_default() {
+        for source in [
+            "[link](/url \"title\")",
+            "[link](/url 'title')",
+            "[link](/url (title))",
+        ] {
+            assert_eq!(parser().format_inline(source), link("/url", "link"));
+        }
+    }
+
+    #[test]
+    fn test_title_shown_when_enabled() {
+        let mut p = parser();
+        p.set_link_titles(true);
+        for source in [
+            "[link](/url \"title\")",
+            "[link](/url 'title')",
+            "[link](/url (title))",
+        ] {
+            assert_eq!(
+                p.format_inline(source),
+                format!("{} \u{001b}[2m\"title\"\u{001b}[0m", link("/url", "link"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_escaped_quote_in_title() {
+        let mut p = parser();
+        p.set_link_titles(true);
+        let output = strip_ansi(&p.format_inline("[link](/url \"title \\\"and\\\" title\")"));
+        assert_eq!(output, "link \"title \"and\" title\"");
+    }
+
+    #[test]
+    fn test_title_needs_whitespace() {
+        let output = parser().format_inline("[link](/url\"title\")");
+        assert_eq!(output, link("/url%22title%22", "link"));
+    }
+
+    #[test]
+    fn test_image_with_parentheses_and_title() {
+        let output = strip_ansi(&parser().format_inline("![alt](a_(b).png \"A title\")"));
+        assert!(!output.contains("A title"));
+        assert!(!output.contains(".png \""));
+    }
+
+    #[test]
+    fn test_definition_with_parentheses() {
+        let mut p = parser();
+        let output = p.feed("[foo]: /url(x)\n\n[foo]\n\n");
+        assert!(output.contains("\u{001b}]8;;/url(x)\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_definition_title_on_next_line() {
+        let mut p = parser();
+        p.set_link_titles(true);
+        let output = p.feed("[foo]: /url\n\"the title\"\n\n[foo]\n\n");
+        assert_eq!(strip_ansi(&output).trim(), "foo \"the title\"");
+        assert!(output.contains("\u{001b}]8;;/url\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_definition_destination_on_next_line() {
+        let mut p = parser();
+        p.set_link_titles(true);
+        let output = p.feed("[foo]:\n   /url\n      'the title'\n\n[foo]\n\n");
+        assert_eq!(strip_ansi(&output).trim(), "foo \"the title\"");
+        assert!(output.contains("\u{001b}]8;;/url\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_title_continues_across_lines() {
+        let mut p = parser();
+        p.set_link_titles(true);
+        let output = p.feed("[multi]: /m \"line one\nline two\"\n\n[multi]\n\n");
+        assert_eq!(strip_ansi(&output).trim(), "multi \"line one line two\"");
+        assert!(output.contains("\u{001b}]8;;/m\u{001b}\\"));
+
+        let mut p = parser();
+        p.set_link_titles(true);
+        let output = p.feed("[multi]: /m\n'one\ntwo\nthree'\n\n[multi]\n\n");
+        assert_eq!(strip_ansi(&output).trim(), "multi \"one two three\"");
+    }
+
+    #[test]
+    fn test_title_with_blank_line_is_not_a_title() {
+        // Spec example 196: not a definition at all
+        let mut p = parser();
+        let output = p.feed("[foo]: /url 'title\n\nwith blank line'\n\n[foo]\n\n");
+        let stripped = strip_ansi(&output);
+        assert!(stripped.starts_with("foo[1]: /url 'title\n\nwith blank line'\n\n"));
+        assert!(!output.contains("\u{001b}]8;;"));
+
+        // A title on its own line leaves the definition without a title
+        let mut p = parser();
+        let output = p.feed("[foo]: /url\n'title\n\nmore'\n\n[foo]\n\n");
+        let stripped = strip_ansi(&output);
+        assert!(stripped.starts_with("'title\n\nmore'\n\nfoo"));
+        assert!(output.contains("\u{001b}]8;;/url\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_unclosed_title_at_end_of_input() {
+        let mut p = parser();
+        let mut output = p.feed("[foo]: /url\n'title\n");
+        output.push_str(&p.flush());
+        assert_eq!(strip_ansi(&output).trim(), "'title");
+    }
+
+    #[test]
+    fn test_definition_followed_by_paragraph() {
+        let mut p = parser();
+        let output = p.feed("[foo]: /url\n\"title\" ok\n\n[foo]\n\n");
+        let stripped = strip_ansi(&output);
+        assert!(stripped.contains("\"title\" ok"));
+        assert!(output.contains("\u{001b}]8;;/url\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_trailing_text_is_not_a_definition() {
+        let mut p = parser();
+        let output = p.feed("[foo]: /url \"title\" ok\n\n");
+        // Without a definition, [foo] is left as a citation
+        assert_eq!(strip_ansi(&output).trim(), "foo[1]: /url \"title\" ok");
+    }
+
+    #[test]
+    fn test_label_without_destination_is_a_paragraph() {
+        let mut p = parser();
+        let output = p.feed("[foo]:\n\n[foo]\n\n");
+        assert!(strip_ansi(&output).starts_with("foo[1]:\n"));
+        assert!(!output.contains("\u{001b}]8;;"));
+    }
+
+    #[test]
+    fn test_definition_at_end_of_input() {
+        let mut p = parser();
+        p.set_link_titles(true);
+        let mut output = p.feed("See [foo].\n\n[foo]: /url\n");
+        output.push_str(&p.flush());
+        assert!(output.contains("[1] foo: "));
+        assert!(output.contains("/url"));
+    }
+}
+
+mod heading_anchors {
+    use super::*;
+
+    fn slugs(p: &StreamingParser) -> Vec<String> {
+        p.heading_anchors().into_iter().map(|a| a.slug).collect()
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(mdriver::slugify("Installation"), "installation");
+        assert_eq!(mdriver::slugify("What's new in 2.0?"), "whats-new-in-20");
+        assert_eq!(mdriver::slugify("foo_bar - baz"), "foo_bar---baz");
+        assert_eq!(mdriver::slugify("Café Olé"), "café-olé");
+    }
+
+    #[test]
+    fn test_anchors_for_each_heading() {
+        let mut p = parser();
+        p.feed("# Intro\n\nText\n\nSetup\n-----\n\n### Usage Notes\n\n");
+        let anchors = p.heading_anchors();
+        assert_eq!(anchors.len(), 3);
+        assert_eq!(
+            anchors[2],
+            mdriver::HeadingAnchor {
+                level: 3,
+                text: "Usage Notes".to_string(),
+                slug: "usage-notes".to_string(),
+                number: None,
+            }
+        );
+        assert_eq!(slugs(&p), ["intro", "setup", "usage-notes"]);
+    }
+
+    #[test]
+    fn test_repeated_headings_are_numbered() {
+        let mut p = parser();
+        p.feed("# Usage\n\n## Usage\n\n## Usage\n\n");
+        assert_eq!(slugs(&p), ["usage", "usage-1", "usage-2"]);
+    }
+
+    #[test]
+    fn test_markup_is_left_out_of_slug() {
+        let mut p = parser();
+        p.feed("## `foo_bar` *and* [the **link**](https://x.io) &amp; __more__\n\n");
+        let anchors = p.heading_anchors();
+        assert_eq!(anchors[0].text, "foo_bar and the link & more");
+        assert_eq!(anchors[0].slug, "foo_bar-and-the-link--more");
+    }
+
+    #[test]
+    fn test_html_heading_anchor() {
+        let mut p = parser();
+        p.feed("<h2>Getting <em>started</em></h2>\n\n");
+        assert_eq!(slugs(&p), ["getting-started"]);
+    }
+
+    #[test]
+    fn test_fragment_link_names_earlier_heading() {
+        let mut p = parser();
+        let output = p.feed("## Installation\n\nSee [setup](#installation).\n\n");
+        assert!(!output.contains("\u{001b}]8;;#installation"));
+        assert!(output
+            .contains("\u{001b}[34;4msetup\u{001b}[0m \u{001b}[2m(§ Installation)\u{001b}[0m"));
+    }
+
+    #[test]
+    fn test_fragment_link_to_unknown_heading_is_kept() {
+        let mut p = parser();
+        let output = p.feed("See [usage](#usage).\n\n## Usage\n\n");
+        assert!(output.contains("\u{001b}]8;;#usage\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_fragment_link_into_document_file() {
+        let mut p = parser();
+        p.set_document_path(std::path::Path::new("/no/such dir/README.md"));
+        let output = p.feed("See [usage](#usage).\n\n");
+        assert!(output.contains("\u{001b}]8;;file:///no/such%20dir/README.md#usage\u{001b}\\"));
+    }
+
+    #[test]
+    fn test_reference_fragment_link() {
+        let mut p = parser();
+        let output = p.feed("# Intro\n\n[top]: #intro\n\nBack to [top].\n\n");
+        assert!(output.contains("(§ Intro)"));
+    }
+}
+
+mod table_of_contents {
+    use super::*;
+    use mdriver::TocPosition;
+
+    const DOC: &str = "# Guide\n\nIntro\n\n## Install\n\n### From source\n\n## Usage\n";
+
+    fn render(position: Option<TocPosition>, input: &str) -> (String, String) {
+        let mut p = parser();
+        p.set_toc(position);
+        let streamed = p.feed(input);
+        let flushed = p.flush();
+        (strip_ansi(&streamed), strip_ansi(&flushed))
+    }
+
+    #[test]
+    fn test_toc_at_start_buffers_input() {
+        let (streamed, flushed) = render(Some(TocPosition::Start), DOC);
+        assert_eq!(streamed, "");
+        assert!(flushed.starts_with(
+            "─── Contents ───\n\n• Guide\n  • Install\n    • From source\n  • Usage\n\n# Guide\n"
+        ));
+    }
+
+    #[test]
+    fn test_toc_at_end_streams() {
+        let (streamed, flushed) = render(Some(TocPosition::End), DOC);
+        assert!(streamed.contains("## Install"));
+        assert!(flushed.ends_with(
+            "─── Contents ───\n\n• Guide\n  • Install\n    • From source\n  • Usage\n\n"
+        ));
+    }
+
+    #[test]
+    fn test_marker_replaced_in_place() {
+        for marker in ["[TOC]", "[[_TOC_]]", "<!-- toc -->", "<!--TOC-->"] {
+            let input = format!("# Guide\n\n{}\n\n## Install\n", marker);
+            let (_, flushed) = render(Some(TocPosition::Start), &input);
+            assert!(
+                flushed.starts_with("# Guide\n\n─── Contents ───\n\n• Guide\n  • Install\n\n"),
+                "{}",
+                marker
+            );
+            assert_eq!(flushed.matches("Contents").count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_marker_kept_without_toc() {
+        for marker in ["[TOC]", "[[_TOC_]]"] {
+            let input = format!("# Guide\n\n{}\n\nText\n", marker);
+            let (streamed, flushed) = render(None, &input);
+            let output = streamed + &flushed;
+            assert!(output.contains("TOC"), "{} was dropped", marker);
+            assert!(!output.contains("Contents"));
+            assert!(output.contains("Text"));
+        }
+    }
+
+    #[test]
+    fn test_marker_hidden_with_toc_at_end() {
+        let (streamed, flushed) = render(Some(TocPosition::End), "# Guide\n\n[TOC]\n\nText\n");
+        assert!(!streamed.contains("TOC"));
+        assert!(flushed.contains("Contents"));
+    }
+
+    #[test]
+    fn test_no_headings_no_toc() {
+        let (_, flushed) = render(Some(TocPosition::Start), "Just text\n");
+        assert_eq!(flushed, "Just text\n\n");
+    }
+
+    #[test]
+    fn test_toc_links_into_document_file() {
+        let mut p = parser();
+        p.set_toc(Some(TocPosition::End));
+        p.set_document_path(std::path::Path::new("/docs/guide.md"));
+        p.feed("# Guide\n\n## Install\n");
+        let output = p.flush();
+        assert!(output.contains("\u{001b}]8;;file:///docs/guide.md#install\u{001b}\\"));
+    }
+}
+
+mod sections {
+    use super::*;
+
+    const DOC: &str = "# Project\n\nIntro\n\n## Install\n\nRun it\n\n### From source\n\nBuild it\n\n## Usage\n\nUse it\n\n### Examples\n\nLike this\n\n## License\n\nMIT\n";
+
+    #[test]
+    fn test_section_includes_subsections() {
+        let mut p = parser();
+        p.add_section("install");
+        assert_eq!(
+            render_plain(&mut p, DOC),
+            "## Install\n\nRun it\n\n### From source\n\nBuild it\n\n"
+        );
+    }
+
+    #[test]
+    fn test_section_path() {
+        let mut p = parser();
+        p.add_section("Usage/Exam");
+        assert_eq!(render_plain(&mut p, DOC), "### Examples\n\nLike this\n\n");
+    }
+
+    #[test]
+    fn test_section_path_needs_matching_ancestor() {
+        let mut p = parser();
+        p.add_section("Install/Examples");
+        assert_eq!(render_plain(&mut p, DOC), "");
+    }
+
+    #[test]
+    fn test_section_at_end_without_trailing_newline() {
+        let mut p = parser();
+        p.add_section("C");
+        let output = render(&mut p, "# A\n\na\n\n# B\n\nb\n\n# C");
+        assert!(!output.contains("mdriver-section"));
+        assert_eq!(strip_ansi(&output), "# C\n\n");
+    }
+
+    #[test]
+    fn test_multiple_sections() {
+        let mut p = parser();
+        p.add_section("License");
+        p.add_section("From source");
+        assert_eq!(
+            render_plain(&mut p, DOC),
+            "### From source\n\nBuild it\n\n## License\n\nMIT\n\n"
+        );
+    }
+
+    #[test]
+    fn test_adjacent_sections_stay_shown() {
+        let mut p = parser();
+        p.add_section("Install");
+        p.add_section("Usage");
+        let output = render_plain(&mut p, DOC);
+        assert!(output.starts_with("## Install\n"));
+        assert!(output.ends_with("### Examples\n\nLike this\n\n"));
+    }
+
+    #[test]
+    fn test_section_regex() {
+        let mut p = parser();
+        p.add_section_regex("^Project/Us").unwrap();
+        assert!(render_plain(&mut p, DOC).starts_with("## Usage\n\nUse it\n"));
+        assert!(parser().add_section_regex("(unclosed").is_err());
+    }
+
+    #[test]
+    fn test_first_match_only() {
+        let mut p = parser();
+        p.add_section("0.9");
+        let output = render_plain(&mut p, "## 0.9.1\n\nFixes\n\n## 0.9.0\n\nFeatures\n");
+        assert_eq!(output, "## 0.9.1\n\nFixes\n\n");
+    }
+
+    #[test]
+    fn test_setext_heading_section() {
+        let mut p = parser();
+        p.add_section("Usage");
+        let output = render_plain(
+            &mut p,
+            "Install\n-------\n\nRun it\n\nUsage\n-----\n\nUse it\n",
+        );
+        assert_eq!(output, "## Usage\n\nUse it\n\n");
+    }
+
+    #[test]
+    fn test_sections_complete_when_section_ends() {
+        let mut p = parser();
+        p.add_section("Install");
+        assert!(!p.sections_complete());
+        p.feed("# Project\n\n## Install\n\nRun it\n\n");
+        assert!(!p.sections_complete());
+        let output = p.feed("## Usage\n");
+        assert!(p.sections_complete());
+        assert_eq!(strip_ansi(&output), "");
+    }
+
+    #[test]
+    fn test_no_sections_never_complete() {
+        let mut p = parser();
+        p.feed(DOC);
+        assert!(!p.sections_complete());
+    }
+}
+
+mod heading_styles {
+    use super::*;
+    use mdriver::HeadingStyle;
+
+    #[test]
+    fn test_uniform_by_default() {
+        let output = render(&mut parser(), "# One\n\n#### Four\n");
+        assert_eq!(
+            output,
+            "\u{001b}[1;34m# One\u{001b}[0m\n\n\u{001b}[1;34m#### Four\u{001b}[0m\n\n"
+        );
+    }
+
+    #[test]
+    fn test_levels_underline_h1_and_h2() {
+        let mut p = parser();
+        p.set_heading_style(HeadingStyle::Levels);
+        let output = strip_ansi(&render(&mut p, "# One\n\n## Two\n\n### Three\n"));
+        assert_eq!(output, "# One\n═════\n\n## Two\n──────\n\n### Three\n\n");
+    }
+
+    #[test]
+    fn test_levels_have_distinct_colors() {
+        let mut p = parser();
+        p.set_heading_style(HeadingStyle::Levels);
+        let output = render(&mut p, "### a\n\n#### b\n\n##### c\n\n###### d\n");
+        for color in ["1;32m###", "1;33m####", "1;35m#####", "1;90m######"] {
+            assert!(output.contains(color), "{}", color);
+        }
+    }
+
+    #[test]
+    fn test_banner_fills_width() {
+        let mut p = parser_with_width(20);
+        p.set_heading_style(HeadingStyle::Banner);
+        let output = render(&mut p, "# Title\n");
+        assert_eq!(
+            output,
+            "\u{001b}[1;97;44m # Title            \u{001b}[0m\n\n"
+        );
+    }
+
+    #[test]
+    fn test_underline_capped_at_width() {
+        let mut p = parser_with_width(20);
+        p.set_heading_style(HeadingStyle::Levels);
+        let output = strip_ansi(&render(&mut p, "## A heading longer than the width\n"));
+        assert!(output.contains(&format!("\n{}\n", "─".repeat(20))));
+    }
+
+    #[test]
+    fn test_hide_markers() {
+        let mut p = parser();
+        p.set_heading_markers(false);
+        let output = strip_ansi(&render(&mut p, "## Two *em*\n"));
+        assert_eq!(output, "Two em\n\n");
+    }
+
+    #[test]
+    fn test_numbering() {
+        let mut p = parser();
+        p.set_number_headings(true);
+        let output = strip_ansi(&render(
+            &mut p,
+            "# A\n\n## B\n\n### C\n\n### D\n\n## E\n\n# F\n\n## G\n",
+        ));
+        assert_eq!(
+            output,
+            "# 1 A\n\n## 1.1 B\n\n### 1.1.1 C\n\n### 1.1.2 D\n\n## 1.2 E\n\n# 2 F\n\n## 2.1 G\n\n"
+        );
+        let numbers: Vec<_> = p
+            .heading_anchors()
+            .into_iter()
+            .map(|anchor| anchor.number.unwrap())
+            .collect();
+        assert_eq!(numbers, ["1", "1.1", "1.1.1", "1.1.2", "1.2", "2", "2.1"]);
+    }
+
+    #[test]
+    fn test_numbering_starts_at_top_level_used() {
+        let mut p = parser();
+        p.set_number_headings(true);
+        p.set_heading_markers(false);
+        let output = strip_ansi(&render(&mut p, "## A\n\n### B\n\n## C\n"));
+        assert_eq!(output, "1 A\n\n1.1 B\n\n2 C\n\n");
+    }
+
+    #[test]
+    fn test_numbering_leaves_slug_alone() {
+        let mut p = parser();
+        p.set_number_headings(true);
+        let output = render(&mut p, "# Intro\n\nSee [intro](#intro).\n");
+        assert_eq!(p.heading_anchors()[0].slug, "intro");
+        assert!(strip_ansi(&output).contains("intro (§ 1 Intro)"));
+    }
+}
+
+mod front_matter {
+    use super::*;
+    use mdriver::FrontMatterStyle;
+
+    #[test]
+    fn test_yaml_front_matter_box() {
+        let input =
+            "---\ntitle: \"My Post\"\ntags: [rust, cli]\nauthors:\n  - Ann\n  - Bob\n---\n\nText\n";
+        let expected = [
+            "╭────────────────────╮",
+            "│ title:   My Post   │",
+            "│ tags:    rust, cli │",
+            "│ authors: Ann, Bob  │",
+            "╰────────────────────╯",
+            "",
+            "Text",
+            "",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(render_plain(&mut parser(), input), expected);
+    }
+
+    #[test]
+    fn test_box_is_dimmed() {
+        let mut p = parser();
+        let output = p.feed("---\na: b\n---\n");
+        assert!(output.starts_with("\u{001b}[2m╭"));
+        assert!(output.contains("\u{001b}[2m│ a: b │\u{001b}[0m\n"));
+    }
+
+    #[test]
+    fn test_yaml_block_scalar_and_dots_fence() {
+        let input = "---\nsummary: >\n  A long\n  summary\n...\nText\n";
+        let output = render_plain(&mut parser(), input);
+        assert!(output.contains("│ summary: A long summary │"));
+        assert!(output.ends_with("Text\n\n"));
+    }
+
+    #[test]
+    fn test_toml_front_matter() {
+        let input =
+            "+++\ntitle = 'T'\ntags = [\n  \"a\",\n  \"b\",\n]\n[extra]\nfoo = 1\n+++\nHi\n";
+        let output = render_plain(&mut parser(), input);
+        assert!(output.contains("│ title:     T    │"));
+        assert!(output.contains("│ tags:      a, b │"));
+        assert!(output.contains("│ extra.foo: 1    │"));
+    }
+
+    #[test]
+    fn test_hidden() {
+        let mut p = parser();
+        p.set_front_matter_style(FrontMatterStyle::Hide);
+        assert_eq!(
+            render_plain(&mut p, "---\ntitle: T\n---\nText\n"),
+            "Text\n\n"
+        );
+    }
+
+    #[test]
+    fn test_title_promoted_to_heading() {
+        let mut p = parser();
+        p.set_front_matter_title(true);
+        let output = render_plain(
+            &mut p,
+            "---\ntitle: A *plain* title\ndate: 2024\n---\nText\n",
+        );
+        assert!(output.starts_with("# A *plain* title\n\n╭"));
+        assert!(!output.contains("title:"));
+        assert_eq!(p.heading_anchors()[0].slug, "a-plain-title");
+    }
+
+    #[test]
+    fn test_title_dollars_are_not_math() {
+        let mut p = parser();
+        p.set_front_matter_title(true);
+        let output = render_plain(&mut p, "---\ntitle: Costs $x$\n---\n");
+        assert!(output.starts_with("# Costs $x$\n"));
+    }
+
+    #[test]
+    fn test_only_at_start_of_input() {
+        let output = render_plain(&mut parser(), "Intro\n\n---\ntitle: T\n---\n");
+        assert!(!output.contains('╭'));
+        assert!(output.contains("title: T"));
+    }
+
+    #[test]
+    fn test_unclosed_front_matter_is_markdown() {
+        let output = render_plain(&mut parser(), "---\nJust text\n");
+        assert!(output.starts_with("───"));
+        assert!(output.ends_with("Just text\n\n"));
+    }
+
+    #[test]
+    fn test_markdown_after_rule_is_not_front_matter() {
+        let output = render_plain(
+            &mut parser(),
+            "---\n# Title\n\nSome text here.\n\n---\n\nMore\n",
+        );
+        assert!(output.starts_with("───"));
+        assert!(output.contains("# Title\n"));
+        assert!(output.contains("Some text here.\n"));
+        assert!(output.ends_with("More\n\n"));
+    }
+
+    #[test]
+    fn test_non_field_line_replays_as_markdown_right_away() {
+        let mut p = parser();
+        let output = strip_ansi(&p.feed("---\ntitle: T\nJust some prose\n\n"));
+        assert!(output.starts_with("───"));
+        assert!(output.contains("title: T Just some prose\n"));
+    }
+
+    #[test]
+    fn test_fence_waits_for_next_line() {
+        let mut p = parser();
+        assert_eq!(p.feed("---\n"), "");
+        assert!(strip_ansi(&p.feed("\n")).starts_with("───"));
+
+        let mut p = parser();
+        assert_eq!(p.feed("---\n"), "");
+        assert!(strip_ansi(&p.flush()).starts_with("───"));
+    }
+
+    #[test]
+    fn test_line_by_line_matches_single_chunk() {
+        for input in [
+            "---\ntitle: Hi\ntags: a\n---\nText\n",
+            "+++\ntitle = 'Hi'\n+++\nText\n",
+            "---\n# Title\n\nSome text here.\n\n---\n\nMore\n",
+        ] {
+            let whole = render(&mut parser(), input);
+            let mut p = parser();
+            let mut streamed = String::new();
+            for line in input.split_inclusive('\n') {
+                streamed.push_str(&p.feed(line));
+            }
+            streamed.push_str(&p.flush());
+            assert_eq!(streamed, whole, "{:?}", input);
+        }
+        let output = render_plain(&mut parser(), "---\ntitle: Hi\ntags: a\n---\nText\n");
+        assert!(output.starts_with('╭'));
+    }
+
+    #[test]
+    fn test_detection_off() {
+        let mut p = parser();
+        p.set_front_matter_style(FrontMatterStyle::Off);
+        let output = render_plain(&mut p, "---\ntitle: T\n---\nText\n");
+        assert!(output.starts_with("───"));
+        assert!(!output.contains('╭'));
+        assert!(output.contains("title: T"));
+    }
+
+    #[test]
+    fn test_long_values_are_cut() {
+        let mut p = parser_with_width(20);
+        let output = render_plain(&mut p, "---\nk: a value that is much too long\n---\n");
+        assert!(output.contains("│ k: a value that… │"));
+    }
+}
+
+mod math {
+    use super::*;
+
+    fn narrow() -> StreamingParser {
+        parser_with_width(30)
+    }
+
+    #[test]
+    fn test_inline_greek_and_operators() {
+        let output = render_plain(
+            &mut parser(),
+            "If $\\alpha \\leq \\beta \\times \\pi$ then.\n",
+        );
+        assert_eq!(output, "If α ≤ β × π then.\n\n");
+    }
+
+    #[test]
+    fn test_inline_scripts() {
+        let output = render_plain(&mut parser(), "$x^2 + a_i - e^{-n}$\n");
+        assert_eq!(output, "x² + aᵢ − e⁻ⁿ\n\n");
+    }
+
+    #[test]
+    fn test_scripts_without_unicode_use_carets() {
+        let output = render_plain(&mut parser(), "$e^{i\\pi} + x_q$\n");
+        assert_eq!(output, "e^(iπ) + x_q\n\n");
+    }
+
+    #[test]
+    fn test_inline_fractions_and_roots() {
+        let output = render_plain(
+            &mut parser(),
+            "$\\frac{1}{2}$, $\\frac{a}{b}$, $\\frac{a+b}{2}$, $\\sqrt{x}$, $\\sqrt[3]{x+1}$\n",
+        );
+        assert_eq!(output, "½, a⁄b, (a+b)⁄2, √x, ∛(x+1)\n\n");
+    }
+
+    #[test]
+    fn test_inline_sum() {
+        let output = render_plain(&mut parser(), "$\\sum_{i=1}^n i$ and $\\mathbb{R}^3$\n");
+        assert_eq!(output, "∑ᵢ₌₁ⁿ i and ℝ³\n\n");
+    }
+
+    #[test]
+    fn test_inline_math_is_styled() {
+        let output = parser().feed("a $x$ b\n\n");
+        assert!(output.contains("\u{001b}[36mx\u{001b}[0m"));
+    }
+
+    #[test]
+    fn test_unknown_command_kept_in_distinct_style() {
+        let mut p = parser();
+        let output = p.feed("$\\foo{x} + 1$\n\n");
+        assert!(output.contains("\u{001b}[33m\\foo{x}\u{001b}[0m\u{001b}[36m + 1"));
+        assert_eq!(strip_ansi(&output), "\\foo{x} + 1\n\n");
+    }
+
+    #[test]
+    fn test_dollar_amounts_are_not_math() {
+        let output = render_plain(&mut parser(), "It costs $5 and $10, or $ 3 $.\n");
+        assert_eq!(output, "It costs $5 and $10, or $ 3 $.\n\n");
+    }
+
+    #[test]
+    fn test_shell_variables_are_not_math() {
+        let output = render_plain(&mut parser(), "Copy to $HOME/$USER/bin\n");
+        assert_eq!(output, "Copy to $HOME/$USER/bin\n\n");
+        let output = render_plain(&mut parser(), "Run set $PATH:$HOME/bin first\n");
+        assert_eq!(output, "Run set $PATH:$HOME/bin first\n\n");
+    }
+
+    #[test]
+    fn test_dollar_after_word_does_not_open_math() {
+        let output = render_plain(&mut parser(), "Use a$b$ or US$x$\n");
+        assert_eq!(output, "Use a$b$ or US$x$\n\n");
+    }
+
+    #[test]
+    fn test_double_dollar_does_not_close_inline_math() {
+        let output = render_plain(&mut parser(), "From $10 use `$$` lines\n");
+        assert_eq!(output, "From $10 use  $$  lines\n\n");
+    }
+
+    #[test]
+    fn test_escaped_dollar_and_code_span() {
+        let output = render_plain(&mut parser(), "\\$x$ and `$y$`\n");
+        assert_eq!(output, "$x$ and  $y$ \n\n");
+    }
+
+    #[test]
+    fn test_display_fraction_is_stacked() {
+        let output = render_plain(&mut narrow(), "$$\nx = \\frac{a+b}{2}\n$$\n");
+        let expected = [
+            "               a+b",
+            "          x = ─────",
+            "                2",
+            "",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_display_math_emits_at_closing_dollars() {
+        let mut p = narrow();
+        assert_eq!(p.feed("$$\n"), "");
+        assert_eq!(p.feed("\\alpha\n"), "");
+        let output = strip_ansi(&p.feed("$$\n"));
+        assert_eq!(output, "              α\n\n");
+    }
+
+    #[test]
+    fn test_display_sum_limits() {
+        let output = render_plain(&mut narrow(), "$$\\sum_{k=1}^{n} k$$\n");
+        let expected = [
+            "             n",
+            "             ∑  k",
+            "            k=1",
+            "",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_display_integral_scripts_beside_sign() {
+        let output = render_plain(&mut narrow(), "$$\\int_0^\\infty f$$\n");
+        let expected = [
+            "              ∞",
+            "             ∫  f",
+            "              0",
+            "",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_math_code_block() {
+        let output = render_plain(&mut narrow(), "```math\n\\sqrt{x} \\neq \\infty\n```\n");
+        assert_eq!(output, "            √x ≠ ∞\n\n");
+    }
+
+    #[test]
+    fn test_display_rows() {
+        let output = render_plain(&mut narrow(), "$$\na = 1 \\\\\nb = 2\n$$\n");
+        assert_eq!(output, "            a = 1\n            b = 2\n\n");
+    }
+
+    #[test]
+    fn test_display_matrix_has_tall_delimiters() {
+        let output = render_plain(
+            &mut narrow(),
+            "$$\nA = \\begin{pmatrix} a & b \\\\ c & d \\end{pmatrix}\n$$\n",
+        );
+        let expected = ["          A = ⎛a  b⎞", "              ⎝c  d⎠", "", ""].join("\n");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_display_cases_are_left_aligned() {
+        let output = render_plain(
+            &mut narrow(),
+            "$$\n\\begin{cases} 1 & x > 0 \\\\ -1 & \\text{otherwise} \\end{cases}\n$$\n",
+        );
+        let expected = ["        ⎰1   x > 0", "        ⎱−1  otherwise", "", ""].join("\n");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_inline_matrix_rows_separated_by_semicolons() {
+        let output = render_plain(
+            &mut parser(),
+            "$\\begin{bmatrix} 1 & 0 \\\\ 0 & 1 \\end{bmatrix}$\n",
+        );
+        assert_eq!(output, "[1 0; 0 1]\n\n");
+    }
+
+    #[test]
+    fn test_unknown_environment_kept_as_written() {
+        let output = parser().feed("$\\begin{array}{cc} a & b \\end{array}$\n\n");
+        assert!(output.contains("\u{001b}[33m\\begin{array}{cc} a & b \\end{array}\u{001b}[0m"));
+    }
+
+    #[test]
+    fn test_display_math_interrupts_paragraph() {
+        let output = render_plain(&mut narrow(), "Text\n$$\\pi$$\n");
+        assert_eq!(output, "Text\n\n              π\n\n");
+    }
+
+    #[test]
+    fn test_unclosed_display_math_is_text() {
+        let output = render_plain(&mut parser(), "$$ not math\n\nafter\n");
+        assert_eq!(output, "$$ not math\n\nafter\n\n");
+        let output = render_plain(&mut parser(), "$$ still open\n");
+        assert_eq!(output, "$$ still open\n\n");
+    }
+
+    #[test]
+    fn test_math_disabled() {
+        let mut p = parser();
+        p.set_math(false);
+        let output = render_plain(&mut p, "$x^2$\n\n```math\nx^2\n```\n");
+        assert!(output.starts_with("$x^2$\n\n"));
+        assert!(output.contains("x^2"));
+        assert!(!output.contains('²'));
+    }
+}